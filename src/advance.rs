@@ -0,0 +1,254 @@
+use fontcull_read_fonts::{FontRef, TableProvider};
+use kurbo::{Affine, BezPath, Rect, Shape};
+use rustc_hash::FxHashSet;
+
+/// PANOSE proportion value for monospaced Latin text faces.
+const PANOSE_MONOSPACED: u8 = 9;
+
+/// How advances respond to ruby that overhangs the base glyph's advance.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AdvancePolicy {
+    /// Keep advances; ruby may overhang into neighbouring glyphs
+    #[default]
+    Keep,
+    /// Widen advances (shifting the outline) so the ruby fits, up to `max_scale` times the
+    /// original advance. Ruby that still does not fit is compressed horizontally.
+    Expand { max_scale: f64 },
+}
+
+/// How an annotated glyph is adjusted so its ruby fits inside the advance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdvanceFit {
+    /// Horizontal scale applied to the ruby around `anchor`
+    pub scale: f64,
+    /// The ruby's edge next to the glyph for ruby beside it, otherwise the centre of the advance
+    pub anchor: f64,
+    /// Shift applied to the whole outline
+    pub shift: f64,
+    /// Width added to the advance
+    pub extra: f64,
+}
+
+impl AdvanceFit {
+    /// Whether the ruby had to be compressed to respect the cap.
+    pub fn is_compressed(&self) -> bool {
+        self.scale < 1.0
+    }
+
+    /// Apply the fit to `path`, whose ruby starts at element `ruby_start`.
+    pub fn apply(&self, path: &mut BezPath, ruby_start: usize) {
+        let shift = Affine::translate((self.shift, 0.0));
+        let compress = shift
+            * Affine::translate((self.anchor, 0.0))
+            * Affine::scale_non_uniform(self.scale, 1.0)
+            * Affine::translate((-self.anchor, 0.0));
+
+        *path = path
+            .elements()
+            .iter()
+            .enumerate()
+            .map(|(idx, &el)| {
+                if idx < ruby_start {
+                    shift * el
+                } else {
+                    compress * el
+                }
+            })
+            .collect();
+    }
+}
+
+/// Returns the bounds of the ruby in `path`, which starts at element `ruby_start`.
+pub fn ruby_bounds(path: &BezPath, ruby_start: usize) -> Option<Rect> {
+    let ruby = path.elements().get(ruby_start..)?;
+
+    (!ruby.is_empty()).then(|| BezPath::from_vec(ruby.to_vec()).bounding_box())
+}
+
+/// Work out how to fit `ruby` inside `advance`, widening it to at most `max_scale` times
+/// the original. Returns `None` if the ruby already fits.
+pub fn fit(ruby: Rect, advance: f64, max_scale: f64) -> Option<AdvanceFit> {
+    let overhang = |x0: f64, x1: f64| ((-x0).max(0.0).ceil(), (x1 - advance).max(0.0).ceil());
+
+    if overhang(ruby.x0, ruby.x1) == (0.0, 0.0) {
+        return None;
+    }
+
+    let max_advance = advance * max_scale.max(1.0);
+
+    // Ruby beside the glyph is compressed towards the glyph, so it keeps its gutter; ruby
+    // above or below is compressed around the centre of the advance
+    let anchor = match (ruby.x0 + ruby.x1) / 2.0 {
+        mid if mid < 0.0 => ruby.x1,
+        mid if mid > advance => ruby.x0,
+        _ => advance / 2.0,
+    };
+
+    let scaled = |scale: f64| {
+        overhang(
+            anchor + (ruby.x0 - anchor) * scale,
+            anchor + (ruby.x1 - anchor) * scale,
+        )
+    };
+    let fits = |scale: f64| {
+        let (left, right) = scaled(scale);
+        advance + left + right <= max_advance
+    };
+
+    let mut scale = 1.0;
+
+    if !fits(scale) {
+        let (mut low, mut high) = (0.0, 1.0);
+
+        for _ in 0..32 {
+            let mid = (low + high) / 2.0;
+
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        scale = low;
+    }
+
+    let (left, right) = scaled(scale);
+
+    Some(AdvanceFit {
+        scale,
+        anchor,
+        shift: left,
+        extra: left + right,
+    })
+}
+
+/// Returns true if the font is laid out on a fixed grid, where widening any advance
+/// breaks alignment.
+///
+/// Besides `post.isFixedPitch` and PANOSE, fonts whose advances are all one width (or
+/// one width and its double, as in CJK monospace fonts) count as monospaced.
+pub fn is_monospace(font: &FontRef) -> bool {
+    if font.post().is_ok_and(|post| post.is_fixed_pitch() != 0) {
+        return true;
+    }
+
+    if font
+        .os2()
+        .is_ok_and(|os2| os2.panose_10()[0] == 2 && os2.panose_10()[3] == PANOSE_MONOSPACED)
+    {
+        return true;
+    }
+
+    let Ok(hmtx) = font.hmtx() else {
+        return false;
+    };
+
+    let widths = hmtx
+        .h_metrics()
+        .iter()
+        .map(|metric| metric.advance())
+        .filter(|&advance| advance != 0)
+        .collect::<FxHashSet<u16>>();
+
+    let mut widths = widths.into_iter().collect::<Vec<u16>>();
+    widths.sort_unstable();
+
+    match widths.as_slice() {
+        [_] => true,
+        [narrow, wide] => *wide == narrow * 2,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::PathEl;
+
+    use super::*;
+
+    fn ruby(x0: f64, x1: f64) -> Rect {
+        Rect::new(x0, 800.0, x1, 1000.0)
+    }
+
+    #[test]
+    fn fitting_ruby_needs_no_fit() {
+        assert_eq!(fit(ruby(0.0, 1000.0), 1000.0, 1.5), None);
+        assert_eq!(fit(ruby(100.0, 900.0), 1000.0, 1.0), None);
+    }
+
+    #[test]
+    fn overhang_widens_within_the_cap() {
+        let fit = fit(ruby(-100.0, 1150.0), 1000.0, 1.5).unwrap();
+
+        assert_eq!(
+            fit,
+            AdvanceFit {
+                scale: 1.0,
+                anchor: 500.0,
+                shift: 100.0,
+                extra: 250.0,
+            }
+        );
+        assert!(!fit.is_compressed());
+    }
+
+    #[test]
+    fn ruby_past_the_cap_is_compressed() {
+        let fit = fit(ruby(-500.0, 1500.0), 1000.0, 1.5).unwrap();
+
+        assert!(fit.is_compressed());
+        assert_eq!(fit.anchor, 500.0);
+        assert!(fit.extra <= 500.0);
+        // The compressed ruby fills the widened advance
+        assert!((fit.scale * 2000.0 - (1000.0 + fit.extra)).abs() < 2.0);
+        assert_eq!(fit.shift, fit.extra / 2.0);
+    }
+
+    #[test]
+    fn side_ruby_is_anchored_next_to_the_glyph() {
+        // Left of the glyph, 100 units wide with a 20 unit gutter
+        let left = fit(ruby(-120.0, -20.0), 1000.0, 1.05).unwrap();
+
+        assert!(left.is_compressed());
+        assert_eq!(left.anchor, -20.0);
+        assert_eq!(left.extra, 50.0);
+        assert_eq!(left.shift, 50.0);
+
+        // Right of the glyph
+        let right = fit(ruby(1020.0, 1120.0), 1000.0, 1.05).unwrap();
+
+        assert!(right.is_compressed());
+        assert_eq!(right.anchor, 1020.0);
+        assert_eq!(right.extra, 50.0);
+        assert_eq!(right.shift, 0.0);
+    }
+
+    #[test]
+    fn apply_shifts_glyph_and_compresses_ruby() {
+        let fit = AdvanceFit {
+            scale: 0.5,
+            anchor: -20.0,
+            shift: 50.0,
+            extra: 50.0,
+        };
+
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((1000.0, 0.0));
+        path.move_to((-120.0, 800.0));
+        path.line_to((-20.0, 800.0));
+
+        fit.apply(&mut path, 2);
+
+        assert_eq!(
+            path.elements(),
+            [
+                PathEl::MoveTo((50.0, 0.0).into()),
+                PathEl::LineTo((1050.0, 0.0).into()),
+                PathEl::MoveTo((-20.0, 800.0).into()),
+                PathEl::LineTo((30.0, 800.0).into()),
+            ]
+        );
+    }
+}
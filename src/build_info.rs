@@ -0,0 +1,184 @@
+use std::ops::RangeInclusive;
+
+use anyhow::{Context, Result};
+use fontcull_read_fonts::{FontRef, types::Tag};
+use fontcull_skrifa::MetadataProvider;
+
+/// Private table the build settings of a rubified font are stored in.
+pub const TAG: Tag = Tag::new(b"RBFY");
+
+/// How a font was built: the rubify version, the settings it was run with, and which
+/// characters were annotated.
+///
+/// Stored as UTF-8 `key=value` lines, so it can also be read without rubify.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Renderer and layout settings, in the order given
+    pub settings: Vec<(String, String)>,
+    /// Character ranges the renderer covers
+    pub ranges: Vec<RangeInclusive<u32>>,
+    /// Characters that received an annotation
+    pub annotated: Vec<RangeInclusive<u32>>,
+}
+
+/// Collapse sorted `codepoints` into ranges.
+pub fn to_ranges(codepoints: impl IntoIterator<Item = u32>) -> Vec<RangeInclusive<u32>> {
+    let mut ranges: Vec<RangeInclusive<u32>> = Vec::new();
+
+    for codepoint in codepoints {
+        match ranges.last_mut() {
+            Some(range) if *range.end() + 1 == codepoint => {
+                *range = *range.start()..=codepoint;
+            }
+            _ => ranges.push(codepoint..=codepoint),
+        }
+    }
+
+    ranges
+}
+
+fn format_ranges(ranges: &[RangeInclusive<u32>]) -> String {
+    ranges
+        .iter()
+        .map(|range| {
+            if range.start() == range.end() {
+                format!("{:04X}", range.start())
+            } else {
+                format!("{:04X}-{:04X}", range.start(), range.end())
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn parse_ranges(value: &str) -> Result<Vec<RangeInclusive<u32>>> {
+    value
+        .split(',')
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse = |hex| {
+                u32::from_str_radix(hex, 16).with_context(|| format!("Invalid range {range:?}"))
+            };
+
+            Ok(parse(start)?..=parse(end)?)
+        })
+        .collect()
+}
+
+impl BuildInfo {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("rubify={}\n", self.version);
+
+        for (key, value) in &self.settings {
+            // Keep every setting on its own line
+            out.push_str(&format!("{key}={}\n", value.replace('\n', " ")));
+        }
+
+        out.push_str(&format!("ranges={}\n", format_ranges(&self.ranges)));
+        out.push_str(&format!("annotated={}\n", format_ranges(&self.annotated)));
+
+        out.into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data).context("Build info is not UTF-8")?;
+        let mut info = BuildInfo::default();
+
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Invalid build info line {line:?}"))?;
+
+            match key {
+                "rubify" => info.version = value.to_string(),
+                "ranges" => info.ranges = parse_ranges(value)?,
+                "annotated" => info.annotated = parse_ranges(value)?,
+                _ => info.settings.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Read the build info of `font`, or `None` if it was not built by rubify.
+    pub fn read(font: &FontRef) -> Result<Option<Self>> {
+        font.table_data(TAG)
+            .map(|data| Self::parse(data.as_bytes()))
+            .transpose()
+    }
+
+    /// Characters in `font`'s cmap that fall in the renderer's ranges, and how many of
+    /// them were annotated.
+    pub fn coverage(&self, font: &FontRef) -> (usize, usize) {
+        let contains =
+            |ranges: &[RangeInclusive<u32>], c: u32| ranges.iter().any(|range| range.contains(&c));
+
+        font.charmap()
+            .mappings()
+            .filter(|&(c, _)| contains(&self.ranges, c))
+            .fold((0, 0), |(covered, annotated), (c, _)| {
+                (
+                    covered + 1,
+                    annotated + contains(&self.annotated, c) as usize,
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_ranges_collapses_runs() {
+        assert_eq!(to_ranges([]), Vec::<RangeInclusive<u32>>::new());
+        assert_eq!(
+            to_ranges([0x41, 0x42, 0x43, 0x45, 0x4E00]),
+            [0x41..=0x43, 0x45..=0x45, 0x4E00..=0x4E00]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let info = BuildInfo {
+            version: "0.1.0".to_string(),
+            settings: vec![
+                ("renderer".to_string(), "pinyin".to_string()),
+                ("position".to_string(), "top".to_string()),
+            ],
+            ranges: vec![0x3400..=0x4DBF, 0x4E00..=0x9FFF],
+            annotated: vec![0x4E00..=0x4E00, 0x4E09..=0x4E0B],
+        };
+
+        let bytes = info.to_bytes();
+
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            "rubify=0.1.0\nrenderer=pinyin\nposition=top\nranges=3400-4DBF,4E00-9FFF\nannotated=4E00,4E09-4E0B\n"
+        );
+        assert_eq!(BuildInfo::parse(&bytes).unwrap(), info);
+    }
+
+    #[test]
+    fn settings_stay_on_one_line() {
+        let info = BuildInfo {
+            settings: vec![("font".to_string(), "a\nb".to_string())],
+            ..BuildInfo::default()
+        };
+
+        let parsed = BuildInfo::parse(&info.to_bytes()).unwrap();
+
+        assert_eq!(parsed.settings, [("font".to_string(), "a b".to_string())]);
+        assert!(parsed.ranges.is_empty());
+        assert!(parsed.annotated.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!(BuildInfo::parse(&[0xFF, 0xFE]).is_err());
+        assert!(BuildInfo::parse(b"rubify=0.1.0\nno separator\n").is_err());
+        assert!(BuildInfo::parse(b"ranges=4E00-XYZ\n").is_err());
+    }
+}
@@ -0,0 +1,1394 @@
+//! Rewriting of `CFF ` and `CFF2` tables with replaced charstrings.
+//!
+//! Every structure except the charstrings is carried over byte-for-byte; only the
+//! DICT offsets that point at relocated data are re-encoded. Static instances of `CFF2`
+//! fonts are instead converted to CID-keyed `CFF ` tables.
+
+use std::borrow::Cow;
+
+use anyhow::{Context, Result, bail, ensure};
+use kurbo::{BezPath, PathEl, Point};
+
+const OP_CHARSET: u16 = 15;
+const OP_ENCODING: u16 = 16;
+const OP_CHARSTRINGS: u16 = 17;
+const OP_PRIVATE: u16 = 18;
+const OP_SUBRS: u16 = 19;
+const OP_NOMINAL_WIDTH_X: u16 = 21;
+const OP_VSINDEX: u16 = 22;
+const OP_BLEND: u16 = 23;
+const OP_VSTORE: u16 = 24;
+const OP_FONT_MATRIX: u16 = 0x0c07;
+const OP_ROS: u16 = 0x0c1e;
+const OP_CID_COUNT: u16 = 0x0c22;
+const OP_FD_ARRAY: u16 = 0x0c24;
+const OP_FD_SELECT: u16 = 0x0c25;
+
+/// SID of the first custom string; lower SIDs are the standard strings.
+const FIRST_CUSTOM_SID: usize = 391;
+
+const CS_RLINETO: u8 = 5;
+const CS_RRCURVETO: u8 = 8;
+const CS_ENDCHAR: u8 = 14;
+const CS_RMOVETO: u8 = 21;
+
+#[derive(Clone)]
+struct Operand {
+    raw: Vec<u8>,
+    value: f64,
+}
+
+#[derive(Clone)]
+struct DictEntry {
+    op: u16,
+    operands: Vec<Operand>,
+}
+
+/// A parsed DICT that keeps the original operand encodings.
+#[derive(Clone)]
+struct Dict(Vec<DictEntry>);
+
+impl Dict {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut operands = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            match data[pos] {
+                12 => {
+                    let b1 = *data.get(pos + 1).context("Truncated DICT operator")?;
+
+                    entries.push(DictEntry {
+                        op: 0x0c00 | b1 as u16,
+                        operands: std::mem::take(&mut operands),
+                    });
+                    pos += 2;
+                }
+                b0 @ 0..=27 => {
+                    entries.push(DictEntry {
+                        op: b0 as u16,
+                        operands: std::mem::take(&mut operands),
+                    });
+                    pos += 1;
+                }
+                28..=30 | 32..=254 => {
+                    let (value, len) = parse_operand(data, pos)?;
+
+                    operands.push(Operand {
+                        raw: data[pos..pos + len].to_vec(),
+                        value,
+                    });
+                    pos += len;
+                }
+                b0 => bail!("Invalid DICT byte: {b0}"),
+            }
+        }
+
+        Ok(Self(entries))
+    }
+
+    fn operands(&self, op: u16) -> Option<&[Operand]> {
+        self.0
+            .iter()
+            .find(|entry| entry.op == op)
+            .map(|entry| entry.operands.as_slice())
+    }
+
+    fn number(&self, op: u16) -> Option<f64> {
+        self.operands(op)?.first().map(|operand| operand.value)
+    }
+
+    fn offset(&self, op: u16) -> Option<usize> {
+        self.number(op).map(|value| value as usize)
+    }
+
+    /// Returns the `(size, offset)` pair of the Private operator.
+    fn private_range(&self) -> Option<(usize, usize)> {
+        match self.operands(OP_PRIVATE)? {
+            [size, offset] => Some((size.value as usize, offset.value as usize)),
+            _ => None,
+        }
+    }
+
+    /// Replace the operands of `op` with fixed-width integers, so the encoded size of
+    /// the DICT does not depend on the values.
+    fn set(&mut self, op: u16, values: &[usize]) {
+        let operands = values
+            .iter()
+            .map(|&value| {
+                let mut raw = vec![29];
+                raw.extend_from_slice(&(value as i32).to_be_bytes());
+
+                Operand {
+                    raw,
+                    value: value as f64,
+                }
+            })
+            .collect();
+
+        match self.0.iter_mut().find(|entry| entry.op == op) {
+            Some(entry) => entry.operands = operands,
+            None => self.0.push(DictEntry { op, operands }),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for entry in &self.0 {
+            for operand in &entry.operands {
+                out.extend_from_slice(&operand.raw);
+            }
+
+            if entry.op > 0xff {
+                out.extend_from_slice(&entry.op.to_be_bytes());
+            } else {
+                out.push(entry.op as u8);
+            }
+        }
+
+        out
+    }
+}
+
+struct Private<'a> {
+    dict: Dict,
+    subrs: Option<&'a [u8]>,
+    nominal_width: f64,
+}
+
+impl Private<'_> {
+    /// Encode the Private DICT with its local Subrs placed directly after it.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = self.dict.clone();
+
+        if let Some(subrs) = self.subrs {
+            dict.set(OP_SUBRS, &[0]);
+            let len = dict.to_bytes().len();
+            dict.set(OP_SUBRS, &[len]);
+
+            let mut out = dict.to_bytes();
+            out.extend_from_slice(subrs);
+            out
+        } else {
+            dict.to_bytes()
+        }
+    }
+
+    fn dict_len(&self) -> usize {
+        let mut dict = self.dict.clone();
+
+        if self.subrs.is_some() {
+            dict.set(OP_SUBRS, &[0]);
+        }
+
+        dict.to_bytes().len()
+    }
+}
+
+struct FontDict<'a> {
+    dict: Dict,
+    private: Option<Private<'a>>,
+}
+
+/// A `CFF ` or `CFF2` table whose charstrings can be replaced.
+pub struct CffTable<'a> {
+    cff2: bool,
+    header: &'a [u8],
+    names: Cow<'a, [u8]>,
+    top_dict: Dict,
+    strings: &'a [u8],
+    global_subrs: &'a [u8],
+    charset: Option<&'a [u8]>,
+    encoding: Option<&'a [u8]>,
+    variation_store: Option<&'a [u8]>,
+    fd_select: Option<&'a [u8]>,
+    charstrings: Vec<Cow<'a, [u8]>>,
+    private: Option<Private<'a>>,
+    font_dicts: Vec<FontDict<'a>>,
+}
+
+impl<'a> CffTable<'a> {
+    pub fn parse(data: &'a [u8], cff2: bool) -> Result<Self> {
+        let hdr_size = *data.get(2).context("Truncated CFF header")? as usize;
+        let header = slice(data, 0, hdr_size)?;
+
+        let (names, top_dict, strings, global_subrs) = if cff2 {
+            let top_dict_len = read_u16(data, 3)? as usize;
+            let top_dict = Dict::parse(slice(data, hdr_size, top_dict_len)?)?;
+
+            let global_subrs_start = hdr_size + top_dict_len;
+            let global_subrs_len = index_len(data, global_subrs_start, true)?;
+
+            (
+                Cow::Borrowed(&[][..]),
+                top_dict,
+                &[][..],
+                slice(data, global_subrs_start, global_subrs_len)?,
+            )
+        } else {
+            let names_len = index_len(data, hdr_size, false)?;
+            let top_dicts_start = hdr_size + names_len;
+            let top_dicts = index_items(data, top_dicts_start, false)?;
+
+            ensure!(
+                top_dicts.len() == 1,
+                "CFF table must contain exactly one font"
+            );
+
+            let strings_start = top_dicts_start + index_len(data, top_dicts_start, false)?;
+            let strings_len = index_len(data, strings_start, false)?;
+            let global_subrs_start = strings_start + strings_len;
+            let global_subrs_len = index_len(data, global_subrs_start, false)?;
+
+            (
+                Cow::Borrowed(slice(data, hdr_size, names_len)?),
+                Dict::parse(top_dicts[0])?,
+                slice(data, strings_start, strings_len)?,
+                slice(data, global_subrs_start, global_subrs_len)?,
+            )
+        };
+
+        let charstrings_offset = top_dict
+            .offset(OP_CHARSTRINGS)
+            .context("CFF table has no CharStrings")?;
+        let charstrings = index_items(data, charstrings_offset, cff2)?
+            .into_iter()
+            .map(Cow::Borrowed)
+            .collect::<Vec<_>>();
+        let num_glyphs = charstrings.len();
+
+        // Values 0..=2 are predefined charsets and 0..=1 predefined encodings
+        let charset = match top_dict.offset(OP_CHARSET) {
+            Some(offset) if !cff2 && offset > 2 => {
+                Some(slice(data, offset, charset_len(data, offset, num_glyphs)?)?)
+            }
+            _ => None,
+        };
+
+        let encoding = match top_dict.offset(OP_ENCODING) {
+            Some(offset) if !cff2 && offset > 1 => {
+                Some(slice(data, offset, encoding_len(data, offset)?)?)
+            }
+            _ => None,
+        };
+
+        let variation_store = match top_dict.offset(OP_VSTORE) {
+            Some(offset) if cff2 => {
+                Some(slice(data, offset, 2 + read_u16(data, offset)? as usize)?)
+            }
+            _ => None,
+        };
+
+        let fd_select = match top_dict.offset(OP_FD_SELECT) {
+            Some(offset) => Some(slice(
+                data,
+                offset,
+                fd_select_len(data, offset, num_glyphs)?,
+            )?),
+            None => None,
+        };
+
+        let private = match top_dict.private_range() {
+            Some((size, offset)) => Some(parse_private(data, size, offset, cff2)?),
+            None => None,
+        };
+
+        let font_dicts = match top_dict.offset(OP_FD_ARRAY) {
+            Some(offset) => index_items(data, offset, cff2)?
+                .into_iter()
+                .map(|font_dict| {
+                    let dict = Dict::parse(font_dict)?;
+                    let private = match dict.private_range() {
+                        Some((size, offset)) => Some(parse_private(data, size, offset, cff2)?),
+                        None => None,
+                    };
+
+                    Ok(FontDict { dict, private })
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            cff2,
+            header,
+            names,
+            top_dict,
+            strings,
+            global_subrs,
+            charset,
+            encoding,
+            variation_store,
+            fd_select,
+            charstrings,
+            private,
+            font_dicts,
+        })
+    }
+
+    pub fn num_glyphs(&self) -> usize {
+        self.charstrings.len()
+    }
+
+    /// Encode `path` as a charstring for glyph `gid` with the given advance width.
+    pub fn encode_glyph(&self, gid: u32, path: &BezPath, advance: f64) -> Result<Vec<u8>> {
+        let width = (!self.cff2).then(|| advance - self.nominal_width(gid));
+
+        encode_charstring(path, width, self.cff2)
+    }
+
+    /// Replace the FontName in the Name INDEX. CFF2 tables have no names, so they are
+    /// left unchanged.
+    pub fn set_font_name(&mut self, name: &str) {
+        if !self.cff2 {
+            self.names = Cow::Owned(write_index(&[name.as_bytes()], false));
+        }
+    }
+
+    /// Replace the charstring of glyph `gid`.
+    pub fn set_charstring(&mut self, gid: u32, charstring: Vec<u8>) -> Result<()> {
+        let slot = self
+            .charstrings
+            .get_mut(gid as usize)
+            .with_context(|| format!("Glyph {gid} is out of range for CFF table"))?;
+
+        *slot = Cow::Owned(charstring);
+
+        Ok(())
+    }
+
+    fn nominal_width(&self, gid: u32) -> f64 {
+        let private = if self.font_dicts.is_empty() {
+            self.private.as_ref()
+        } else {
+            self.font_dicts
+                .get(self.fd_index(gid))
+                .and_then(|font_dict| font_dict.private.as_ref())
+        };
+
+        private.map(|private| private.nominal_width).unwrap_or(0.0)
+    }
+
+    fn fd_index(&self, gid: u32) -> usize {
+        let Some(fd_select) = self.fd_select else {
+            return 0;
+        };
+
+        let gid = gid as usize;
+
+        let fd = match fd_select.first() {
+            Some(0) => fd_select.get(1 + gid).map(|&fd| fd as usize),
+            Some(3) => read_u16(fd_select, 1).ok().and_then(|n_ranges| {
+                (0..n_ranges as usize).find_map(|i| {
+                    let first = read_u16(fd_select, 3 + i * 3).ok()? as usize;
+                    let next = read_u16(fd_select, 3 + (i + 1) * 3).ok()? as usize;
+
+                    if (first..next).contains(&gid) {
+                        fd_select.get(5 + i * 3).map(|&fd| fd as usize)
+                    } else {
+                        None
+                    }
+                })
+            }),
+            Some(4) => read_u32(fd_select, 1).ok().and_then(|n_ranges| {
+                (0..n_ranges as usize).find_map(|i| {
+                    let first = read_u32(fd_select, 5 + i * 6).ok()? as usize;
+                    let next = read_u32(fd_select, 5 + (i + 1) * 6).ok()? as usize;
+
+                    if (first..next).contains(&gid) {
+                        read_u16(fd_select, 9 + i * 6).ok().map(|fd| fd as usize)
+                    } else {
+                        None
+                    }
+                })
+            }),
+            _ => None,
+        };
+
+        fd.unwrap_or(0)
+    }
+
+    /// Serialize the table. Data is laid out in the order recommended by the CFF spec,
+    /// with every relocated offset encoded at a fixed width.
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let charstrings = write_index(
+            &self
+                .charstrings
+                .iter()
+                .map(|c| c.as_ref())
+                .collect::<Vec<_>>(),
+            self.cff2,
+        );
+
+        let privates = self
+            .private
+            .iter()
+            .chain(self.font_dicts.iter().filter_map(|fd| fd.private.as_ref()))
+            .map(|private| (private.dict_len(), private.to_bytes()))
+            .collect::<Vec<_>>();
+
+        // Fill every offset with a placeholder first to measure the DICTs
+        let mut top_dict = self.top_dict.clone();
+        let mut font_dicts = self
+            .font_dicts
+            .iter()
+            .map(|fd| fd.dict.clone())
+            .collect::<Vec<_>>();
+
+        let mut private_sizes = privates.iter().map(|(size, _)| *size);
+
+        if self.private.is_some() {
+            top_dict.set(OP_PRIVATE, &[private_sizes.next().unwrap_or(0), 0]);
+        }
+
+        for (font_dict, source) in font_dicts.iter_mut().zip(&self.font_dicts) {
+            if source.private.is_some() {
+                font_dict.set(OP_PRIVATE, &[private_sizes.next().unwrap_or(0), 0]);
+            }
+        }
+
+        let relocated = [
+            (OP_CHARSET, self.charset),
+            (OP_ENCODING, self.encoding),
+            (OP_VSTORE, self.variation_store),
+            (OP_FD_SELECT, self.fd_select),
+        ];
+
+        for (op, block) in relocated {
+            if block.is_some() {
+                top_dict.set(op, &[0]);
+            }
+        }
+
+        top_dict.set(OP_CHARSTRINGS, &[0]);
+
+        if !self.font_dicts.is_empty() {
+            top_dict.set(OP_FD_ARRAY, &[0]);
+        }
+
+        let top_dict_len = top_dict.to_bytes().len();
+        let fd_array_len = write_index(
+            &font_dicts.iter().map(Dict::to_bytes).collect::<Vec<_>>(),
+            self.cff2,
+        )
+        .len();
+
+        let mut offset = if self.cff2 {
+            self.header.len() + top_dict_len + self.global_subrs.len()
+        } else {
+            self.header.len()
+                + self.names.len()
+                + write_index(&[vec![0; top_dict_len]], false).len()
+                + self.strings.len()
+                + self.global_subrs.len()
+        };
+
+        // Now assign the real offsets
+        for (op, block) in relocated {
+            if let Some(block) = block {
+                top_dict.set(op, &[offset]);
+                offset += block.len();
+            }
+        }
+
+        top_dict.set(OP_CHARSTRINGS, &[offset]);
+        offset += charstrings.len();
+
+        if !self.font_dicts.is_empty() {
+            top_dict.set(OP_FD_ARRAY, &[offset]);
+            offset += fd_array_len;
+        }
+
+        let mut private_blocks = privates.iter();
+
+        if self.private.is_some() {
+            let (size, bytes) = private_blocks.next().context("Missing Private DICT")?;
+            top_dict.set(OP_PRIVATE, &[*size, offset]);
+            offset += bytes.len();
+        }
+
+        for (font_dict, source) in font_dicts.iter_mut().zip(&self.font_dicts) {
+            if source.private.is_some() {
+                let (size, bytes) = private_blocks.next().context("Missing Private DICT")?;
+                font_dict.set(OP_PRIVATE, &[*size, offset]);
+                offset += bytes.len();
+            }
+        }
+
+        let top_dict = top_dict.to_bytes();
+        let mut out = Vec::with_capacity(offset);
+
+        if self.cff2 {
+            out.extend_from_slice(&self.header[..3]);
+            out.extend_from_slice(&(top_dict.len() as u16).to_be_bytes());
+            out.extend_from_slice(&self.header[5..]);
+            out.extend_from_slice(&top_dict);
+        } else {
+            out.extend_from_slice(self.header);
+            out.extend_from_slice(&self.names);
+            out.extend(write_index(&[top_dict], false));
+            out.extend_from_slice(self.strings);
+        }
+
+        out.extend_from_slice(self.global_subrs);
+
+        for block in relocated.iter().filter_map(|(_, block)| *block) {
+            out.extend_from_slice(block);
+        }
+
+        out.extend(charstrings);
+
+        if !self.font_dicts.is_empty() {
+            out.extend(write_index(
+                &font_dicts.iter().map(Dict::to_bytes).collect::<Vec<_>>(),
+                self.cff2,
+            ));
+        }
+
+        for (_, bytes) in &privates {
+            out.extend_from_slice(bytes);
+        }
+
+        ensure!(out.len() == offset, "CFF layout size mismatch");
+
+        Ok(out)
+    }
+
+    /// Convert this `CFF2` table into a CID-keyed `CFF ` table named `font_name`, with
+    /// CIDs equal to glyph IDs.
+    ///
+    /// `charstrings` must be CFF charstrings whose widths are relative to a nominalWidthX
+    /// of 0, without subroutine calls. Each Font DICT keeps its Private DICT with `blend`
+    /// operators resolved to their default values; the variation store and Subrs are dropped.
+    pub fn to_cid_keyed(&self, font_name: &str, charstrings: &[Vec<u8>]) -> Result<Vec<u8>> {
+        ensure!(
+            self.cff2,
+            "Only CFF2 tables can be converted to CID-keyed CFF"
+        );
+
+        let num_glyphs = charstrings.len();
+        ensure!(
+            num_glyphs == self.num_glyphs(),
+            "Expected {} charstrings, got {num_glyphs}",
+            self.num_glyphs()
+        );
+        ensure!(
+            (1..=u16::MAX as usize).contains(&num_glyphs),
+            "CFF tables hold 1 to 65535 glyphs, not {num_glyphs}"
+        );
+
+        let sources = if self.font_dicts.is_empty() {
+            vec![self.private.as_ref()]
+        } else {
+            self.font_dicts
+                .iter()
+                .map(|font_dict| font_dict.private.as_ref())
+                .collect()
+        };
+
+        let privates = sources
+            .into_iter()
+            .map(|private| match private {
+                Some(private) => {
+                    resolve_blends(&private.dict, self.variation_store).map(|dict| dict.to_bytes())
+                }
+                None => Ok(Vec::new()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // .notdef is implied, every other glyph is covered by one range of CIDs from 1
+        let charset = if num_glyphs > 1 {
+            let mut charset = vec![2];
+            charset.extend_from_slice(&1u16.to_be_bytes());
+            charset.extend_from_slice(&((num_glyphs - 2) as u16).to_be_bytes());
+            charset
+        } else {
+            vec![0]
+        };
+
+        let mut ranges: Vec<(u16, u8)> = Vec::new();
+
+        for gid in 0..num_glyphs {
+            let fd = self.fd_index(gid as u32);
+            ensure!(
+                fd < privates.len(),
+                "Glyph {gid} selects missing Font DICT {fd}"
+            );
+
+            let fd = u8::try_from(fd).context("Too many Font DICTs for a CFF table")?;
+
+            if ranges.last().is_none_or(|&(_, last)| last != fd) {
+                ranges.push((gid as u16, fd));
+            }
+        }
+
+        let mut fd_select = vec![3];
+        fd_select.extend_from_slice(&(ranges.len() as u16).to_be_bytes());
+
+        for (first, fd) in ranges {
+            fd_select.extend_from_slice(&first.to_be_bytes());
+            fd_select.push(fd);
+        }
+
+        fd_select.extend_from_slice(&(num_glyphs as u16).to_be_bytes());
+
+        let names = write_index(&[font_name.as_bytes()], false);
+        let strings = write_index(&[b"Adobe".as_slice(), b"Identity"], false);
+        let global_subrs = write_index::<&[u8]>(&[], false);
+        let charstrings = write_index(charstrings, false);
+
+        // ROS must come first in the Top DICT of a CID-keyed font
+        let mut top_dict = Dict(Vec::new());
+        top_dict.set(OP_ROS, &[FIRST_CUSTOM_SID, FIRST_CUSTOM_SID + 1, 0]);
+
+        if let Some(entry) = self.top_dict.0.iter().find(|e| e.op == OP_FONT_MATRIX) {
+            top_dict.0.push(entry.clone());
+        }
+
+        top_dict.set(OP_CID_COUNT, &[num_glyphs]);
+
+        for op in [OP_CHARSET, OP_FD_SELECT, OP_CHARSTRINGS, OP_FD_ARRAY] {
+            top_dict.set(op, &[0]);
+        }
+
+        let mut font_dicts = vec![Dict(Vec::new()); privates.len()];
+
+        for font_dict in &mut font_dicts {
+            font_dict.set(OP_PRIVATE, &[0, 0]);
+        }
+
+        let fd_array_len = write_index(
+            &font_dicts.iter().map(Dict::to_bytes).collect::<Vec<_>>(),
+            false,
+        )
+        .len();
+
+        let mut offset = 4
+            + names.len()
+            + write_index(&[top_dict.to_bytes()], false).len()
+            + strings.len()
+            + global_subrs.len();
+
+        top_dict.set(OP_CHARSET, &[offset]);
+        offset += charset.len();
+        top_dict.set(OP_FD_SELECT, &[offset]);
+        offset += fd_select.len();
+        top_dict.set(OP_CHARSTRINGS, &[offset]);
+        offset += charstrings.len();
+        top_dict.set(OP_FD_ARRAY, &[offset]);
+        offset += fd_array_len;
+
+        for (font_dict, private) in font_dicts.iter_mut().zip(&privates) {
+            font_dict.set(OP_PRIVATE, &[private.len(), offset]);
+            offset += private.len();
+        }
+
+        let mut out = Vec::with_capacity(offset);
+
+        out.extend_from_slice(&[1, 0, 4, 4]);
+        out.extend(names);
+        out.extend(write_index(&[top_dict.to_bytes()], false));
+        out.extend(strings);
+        out.extend(global_subrs);
+        out.extend(charset);
+        out.extend(fd_select);
+        out.extend(charstrings);
+        out.extend(write_index(
+            &font_dicts.iter().map(Dict::to_bytes).collect::<Vec<_>>(),
+            false,
+        ));
+
+        for private in privates {
+            out.extend(private);
+        }
+
+        ensure!(out.len() == offset, "CFF layout size mismatch");
+
+        Ok(out)
+    }
+}
+
+/// Encode `path` as a Type 2 charstring.
+///
+/// `width` is the CFF width operand (advance minus nominalWidthX); CFF2 charstrings carry
+/// neither a width nor `endchar`. Quadratic segments are elevated to cubics. Fails if a
+/// coordinate delta does not fit in a charstring operand.
+pub fn encode_charstring(path: &BezPath, width: Option<f64>, cff2: bool) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut width = width.map(|w| w.round() as i32);
+
+    let mut current = (0, 0);
+
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => {
+                if let Some(width) = width.take() {
+                    push_number(&mut out, width)?;
+                }
+
+                push_points(&mut out, &mut current, &[p])?;
+                out.push(CS_RMOVETO);
+            }
+            PathEl::LineTo(p) => {
+                push_points(&mut out, &mut current, &[p])?;
+                out.push(CS_RLINETO);
+            }
+            PathEl::QuadTo(p1, p2) => {
+                let p0 = Point::new(current.0 as f64, current.1 as f64);
+                let c1 = p0 + (p1 - p0) * (2.0 / 3.0);
+                let c2 = p2 + (p1 - p2) * (2.0 / 3.0);
+
+                push_points(&mut out, &mut current, &[c1, c2, p2])?;
+                out.push(CS_RRCURVETO);
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                push_points(&mut out, &mut current, &[p1, p2, p3])?;
+                out.push(CS_RRCURVETO);
+            }
+            // Contours are closed implicitly by the next moveto
+            PathEl::ClosePath => {}
+        }
+    }
+
+    if !cff2 {
+        if let Some(width) = width {
+            push_number(&mut out, width)?;
+        }
+
+        out.push(CS_ENDCHAR);
+    }
+
+    Ok(out)
+}
+
+/// Push each point as a delta from `current`, rounded to whole font units.
+fn push_points(out: &mut Vec<u8>, current: &mut (i32, i32), points: &[Point]) -> Result<()> {
+    for p in points {
+        let (x, y) = (p.x.round() as i32, p.y.round() as i32);
+
+        push_number(out, x - current.0)?;
+        push_number(out, y - current.1)?;
+
+        *current = (x, y);
+    }
+
+    Ok(())
+}
+
+/// Push a charstring integer operand. Operands are at most 16 bits, so larger values
+/// are rejected rather than clamped into a different coordinate.
+fn push_number(out: &mut Vec<u8>, value: i32) -> Result<()> {
+    match value {
+        -107..=107 => out.push((value + 139) as u8),
+        108..=1131 => {
+            let v = value - 108;
+            out.extend_from_slice(&[(v / 256 + 247) as u8, (v % 256) as u8]);
+        }
+        -1131..=-108 => {
+            let v = -value - 108;
+            out.extend_from_slice(&[(v / 256 + 251) as u8, (v % 256) as u8]);
+        }
+        _ => {
+            let v = i16::try_from(value)
+                .with_context(|| format!("{value} does not fit in a charstring operand"))?;
+            out.push(28);
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_operand(data: &[u8], pos: usize) -> Result<(f64, usize)> {
+    let b0 = data[pos];
+    let byte = |i: usize| data.get(pos + i).copied().context("Truncated DICT operand");
+
+    Ok(match b0 {
+        28 => (i16::from_be_bytes([byte(1)?, byte(2)?]) as f64, 3),
+        29 => (
+            i32::from_be_bytes([byte(1)?, byte(2)?, byte(3)?, byte(4)?]) as f64,
+            5,
+        ),
+        30 => {
+            let mut text = String::new();
+            let mut len = 1;
+
+            'bcd: loop {
+                let b = byte(len)?;
+                len += 1;
+
+                for nibble in [b >> 4, b & 0x0f] {
+                    match nibble {
+                        0..=9 => text.push(char::from(b'0' + nibble)),
+                        0xa => text.push('.'),
+                        0xb => text.push('E'),
+                        0xc => text.push_str("E-"),
+                        0xe => text.push('-'),
+                        0xf => break 'bcd,
+                        _ => {}
+                    }
+                }
+            }
+
+            (text.parse().unwrap_or(0.0), len)
+        }
+        32..=246 => (b0 as f64 - 139.0, 1),
+        247..=250 => (((b0 as f64 - 247.0) * 256.0) + byte(1)? as f64 + 108.0, 2),
+        251..=254 => ((-(b0 as f64 - 251.0) * 256.0) - byte(1)? as f64 - 108.0, 2),
+        _ => bail!("Invalid DICT operand: {b0}"),
+    })
+}
+
+fn parse_private(data: &[u8], size: usize, offset: usize, cff2: bool) -> Result<Private<'_>> {
+    let dict = Dict::parse(slice(data, offset, size)?)?;
+
+    // Subrs offsets are relative to the start of the Private DICT
+    let subrs = match dict.offset(OP_SUBRS) {
+        Some(rel) => {
+            let start = offset + rel;
+            Some(slice(data, start, index_len(data, start, cff2)?)?)
+        }
+        None => None,
+    };
+
+    let nominal_width = dict.number(OP_NOMINAL_WIDTH_X).unwrap_or(0.0);
+
+    Ok(Private {
+        dict,
+        subrs,
+        nominal_width,
+    })
+}
+
+/// Resolve the `blend` operators of a CFF2 Private DICT to their default values,
+/// dropping `vsindex` and Subrs.
+fn resolve_blends(dict: &Dict, variation_store: Option<&[u8]>) -> Result<Dict> {
+    let mut entries = Vec::new();
+    let mut stack = Vec::new();
+    let mut vsindex = 0;
+
+    for entry in &dict.0 {
+        stack.extend(entry.operands.iter().cloned());
+
+        match entry.op {
+            OP_VSINDEX => {
+                vsindex = stack.pop().context("vsindex without an operand")?.value as usize;
+                stack.clear();
+            }
+            OP_BLEND => {
+                // n defaults, followed by n deltas per region, then n itself
+                let n = stack.pop().context("blend without operands")?.value as usize;
+                let store = variation_store.context("blend without a VariationStore")?;
+                let deltas = n * region_count(store, vsindex)?;
+
+                ensure!(stack.len() >= n + deltas, "Too few blend operands");
+                stack.truncate(stack.len() - deltas);
+            }
+            OP_SUBRS => stack.clear(),
+            op => entries.push(DictEntry {
+                op,
+                operands: std::mem::take(&mut stack),
+            }),
+        }
+    }
+
+    Ok(Dict(entries))
+}
+
+/// Number of regions of ItemVariationData `index` in a CFF2 VariationStore, which is
+/// prefixed by its u16 length.
+fn region_count(variation_store: &[u8], index: usize) -> Result<usize> {
+    let store = variation_store
+        .get(2..)
+        .context("Truncated VariationStore")?;
+    let data_count = read_u16(store, 6)? as usize;
+
+    ensure!(index < data_count, "vsindex {index} is out of range");
+
+    let data_offset = read_u32(store, 8 + index * 4)? as usize;
+
+    Ok(read_u16(store, data_offset + 4)? as usize)
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    data.get(start..start + len)
+        .context("CFF data is out of bounds")
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16> {
+    Ok(u16::from_be_bytes(slice(data, pos, 2)?.try_into()?))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(slice(data, pos, 4)?.try_into()?))
+}
+
+fn read_offset(data: &[u8], pos: usize, off_size: usize) -> Result<usize> {
+    Ok(slice(data, pos, off_size)?
+        .iter()
+        .fold(0usize, |acc, &b| (acc << 8) | b as usize))
+}
+
+/// Returns the objects of the INDEX at `pos`.
+fn index_items(data: &[u8], pos: usize, cff2: bool) -> Result<Vec<&[u8]>> {
+    let (count, start) = if cff2 {
+        (read_u32(data, pos)? as usize, pos + 4)
+    } else {
+        (read_u16(data, pos)? as usize, pos + 2)
+    };
+
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let off_size = *data.get(start).context("Truncated INDEX")? as usize;
+    ensure!(
+        (1..=4).contains(&off_size),
+        "Invalid INDEX offSize: {off_size}"
+    );
+
+    let offsets_start = start + 1;
+    // Offsets are 1-based from the byte preceding the object data
+    let data_start = offsets_start + (count + 1) * off_size - 1;
+
+    (0..count)
+        .map(|i| {
+            let from = read_offset(data, offsets_start + i * off_size, off_size)?;
+            let to = read_offset(data, offsets_start + (i + 1) * off_size, off_size)?;
+
+            ensure!(from <= to, "Invalid INDEX offsets");
+
+            slice(data, data_start + from, to - from)
+        })
+        .collect()
+}
+
+/// Returns the total size in bytes of the INDEX at `pos`.
+fn index_len(data: &[u8], pos: usize, cff2: bool) -> Result<usize> {
+    let (count, start) = if cff2 {
+        (read_u32(data, pos)? as usize, pos + 4)
+    } else {
+        (read_u16(data, pos)? as usize, pos + 2)
+    };
+
+    if count == 0 {
+        return Ok(start - pos);
+    }
+
+    let off_size = *data.get(start).context("Truncated INDEX")? as usize;
+    ensure!(
+        (1..=4).contains(&off_size),
+        "Invalid INDEX offSize: {off_size}"
+    );
+
+    let offsets_start = start + 1;
+    let last = read_offset(data, offsets_start + count * off_size, off_size)?;
+
+    Ok(offsets_start + (count + 1) * off_size - 1 + last - pos)
+}
+
+fn write_index<T: AsRef<[u8]>>(items: &[T], cff2: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    if cff2 {
+        out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+    } else {
+        out.extend_from_slice(&(items.len() as u16).to_be_bytes());
+    }
+
+    if items.is_empty() {
+        return out;
+    }
+
+    let data_len = items.iter().map(|item| item.as_ref().len()).sum::<usize>();
+    let off_size = match data_len + 1 {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x10000..=0xff_ffff => 3,
+        _ => 4,
+    };
+
+    out.push(off_size as u8);
+
+    let mut offset = 1usize;
+    out.extend_from_slice(&(offset as u32).to_be_bytes()[4 - off_size..]);
+
+    for item in items {
+        offset += item.as_ref().len();
+        out.extend_from_slice(&(offset as u32).to_be_bytes()[4 - off_size..]);
+    }
+
+    for item in items {
+        out.extend_from_slice(item.as_ref());
+    }
+
+    out
+}
+
+fn charset_len(data: &[u8], offset: usize, num_glyphs: usize) -> Result<usize> {
+    // .notdef is not included in the charset
+    let covered_glyphs = num_glyphs.saturating_sub(1);
+
+    match *data.get(offset).context("Truncated charset")? {
+        0 => Ok(1 + 2 * covered_glyphs),
+        format @ (1 | 2) => {
+            let range_len = if format == 1 { 3 } else { 4 };
+            let mut pos = offset + 1;
+            let mut covered = 0;
+
+            while covered < covered_glyphs {
+                let n_left = if format == 1 {
+                    *data.get(pos + 2).context("Truncated charset")? as usize
+                } else {
+                    read_u16(data, pos + 2)? as usize
+                };
+
+                covered += n_left + 1;
+                pos += range_len;
+            }
+
+            Ok(pos - offset)
+        }
+        format => bail!("Unknown charset format: {format}"),
+    }
+}
+
+fn encoding_len(data: &[u8], offset: usize) -> Result<usize> {
+    let format = *data.get(offset).context("Truncated encoding")?;
+    let count = *data.get(offset + 1).context("Truncated encoding")? as usize;
+
+    let mut len = match format & 0x7f {
+        0 => 2 + count,
+        1 => 2 + 2 * count,
+        format => bail!("Unknown encoding format: {format}"),
+    };
+
+    // High bit signals supplemental encodings
+    if format & 0x80 != 0 {
+        let supplements = *data.get(offset + len).context("Truncated encoding")? as usize;
+        len += 1 + 3 * supplements;
+    }
+
+    Ok(len)
+}
+
+fn fd_select_len(data: &[u8], offset: usize, num_glyphs: usize) -> Result<usize> {
+    match *data.get(offset).context("Truncated FDSelect")? {
+        0 => Ok(1 + num_glyphs),
+        3 => Ok(1 + 2 + 3 * read_u16(data, offset + 1)? as usize + 2),
+        4 => Ok(1 + 4 + 6 * read_u32(data, offset + 1)? as usize + 4),
+        format => bail!("Unknown FDSelect format: {format}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_round_trip() {
+        for value in [
+            -32_768, -1131, -1130, -108, -107, 0, 107, 108, 1131, 1132, 32_767,
+        ] {
+            let mut data = Vec::new();
+            push_number(&mut data, value).unwrap();
+
+            let (parsed, len) = parse_operand(&data, 0).unwrap();
+
+            assert_eq!(parsed, value as f64, "{value}");
+            assert_eq!(len, data.len(), "{value}");
+        }
+    }
+
+    #[test]
+    fn out_of_range_operands_are_rejected() {
+        for value in [-40_000, 32_768] {
+            assert!(push_number(&mut Vec::new(), value).is_err(), "{value}");
+        }
+
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((40_000.0, 0.0));
+
+        assert!(encode_charstring(&path, None, true).is_err());
+    }
+
+    #[test]
+    fn real_operand() {
+        // 1.5, then -2.25E-3
+        assert_eq!(parse_operand(&[30, 0x1a, 0x5f], 0).unwrap(), (1.5, 3));
+        assert_eq!(
+            parse_operand(&[30, 0xe2, 0xa2, 0x5c, 0x3f], 0).unwrap(),
+            (-2.25e-3, 5)
+        );
+    }
+
+    #[test]
+    fn dict_round_trip() {
+        // 500 nominalWidthX, 1.5 0 ROS-like two-byte operator, 1000 CharStrings
+        let data = [248, 136, 21, 30, 0x1a, 0x5f, 139, 12, 30, 250, 124, 17];
+        let dict = Dict::parse(&data).unwrap();
+
+        assert_eq!(dict.to_bytes(), data);
+        assert_eq!(dict.number(OP_NOMINAL_WIDTH_X), Some(500.0));
+        assert_eq!(dict.operands(0x0c1e).map(<[Operand]>::len), Some(2));
+        assert_eq!(dict.offset(OP_CHARSTRINGS), Some(1000));
+    }
+
+    #[test]
+    fn dict_set_is_fixed_width() {
+        let mut dict = Dict::parse(&[139, OP_CHARSTRINGS as u8]).unwrap();
+
+        dict.set(OP_CHARSTRINGS, &[0]);
+        let len = dict.to_bytes().len();
+        dict.set(OP_CHARSTRINGS, &[70_000]);
+        dict.set(OP_FD_ARRAY, &[12]);
+
+        let bytes = dict.to_bytes();
+        let parsed = Dict::parse(&bytes).unwrap();
+
+        assert_eq!(bytes.len(), len + 5 + 2);
+        assert_eq!(parsed.offset(OP_CHARSTRINGS), Some(70_000));
+        assert_eq!(parsed.offset(OP_FD_ARRAY), Some(12));
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let large = vec![7; 300];
+        let items: [&[u8]; 3] = [b"a", b"", &large];
+
+        for cff2 in [false, true] {
+            let mut data = vec![0xff; 3];
+            data.extend(write_index(&items, cff2));
+            data.push(0xff);
+
+            assert_eq!(index_items(&data, 3, cff2).unwrap(), items);
+            assert_eq!(index_len(&data, 3, cff2).unwrap(), data.len() - 4);
+        }
+    }
+
+    #[test]
+    fn empty_index() {
+        let empty: [&[u8]; 0] = [];
+
+        assert_eq!(write_index(&empty, false), [0, 0]);
+        assert_eq!(write_index(&empty, true), [0, 0, 0, 0]);
+        assert_eq!(index_len(&[0, 0], 0, false).unwrap(), 2);
+        assert!(index_items(&[0, 0, 0, 0], 0, true).unwrap().is_empty());
+    }
+
+    /// A CFF table laid out the way [`CffTable::build`] writes it.
+    fn test_table(charstrings: &[Vec<u8>]) -> Vec<u8> {
+        let header = [1, 0, 4, 1];
+        let names = write_index(&[b"Test"], false);
+        let strings = write_index::<&[u8]>(&[], false);
+        let global_subrs = write_index::<&[u8]>(&[], false);
+        let charstrings = write_index(charstrings, false);
+        // 500 nominalWidthX
+        let private = [248, 136, OP_NOMINAL_WIDTH_X as u8];
+
+        let mut top_dict = Dict(Vec::new());
+        top_dict.set(OP_CHARSTRINGS, &[0]);
+        top_dict.set(OP_PRIVATE, &[0, 0]);
+
+        let top_dict_len = write_index(&[top_dict.to_bytes()], false).len();
+        let charstrings_offset =
+            header.len() + names.len() + top_dict_len + strings.len() + global_subrs.len();
+
+        top_dict.set(OP_CHARSTRINGS, &[charstrings_offset]);
+        top_dict.set(
+            OP_PRIVATE,
+            &[private.len(), charstrings_offset + charstrings.len()],
+        );
+
+        let parts: [&[u8]; 7] = [
+            &header,
+            &names,
+            &write_index(&[top_dict.to_bytes()], false),
+            &strings,
+            &global_subrs,
+            &charstrings,
+            &private,
+        ];
+
+        parts.concat()
+    }
+
+    #[test]
+    fn table_round_trip() {
+        let glyphs = [
+            encode_charstring(&BezPath::new(), Some(0.0), false).unwrap(),
+            encode_charstring(&BezPath::new(), Some(-500.0), false).unwrap(),
+        ];
+        let data = test_table(&glyphs);
+        let table = CffTable::parse(&data, false).unwrap();
+
+        assert_eq!(table.num_glyphs(), 2);
+        assert_eq!(table.nominal_width(1), 500.0);
+        assert_eq!(table.build().unwrap(), data);
+    }
+
+    #[test]
+    fn replaced_charstrings_are_relocated() {
+        let empty = encode_charstring(&BezPath::new(), Some(0.0), false).unwrap();
+        let data = test_table(&[empty.clone(), empty.clone()]);
+        let mut table = CffTable::parse(&data, false).unwrap();
+
+        let mut path = BezPath::new();
+        path.move_to((10.0, 20.0));
+        path.line_to((300.0, 20.0));
+        path.line_to((300.0, 700.0));
+        path.close_path();
+
+        let glyph = table.encode_glyph(1, &path, 600.0).unwrap();
+        table.set_charstring(1, glyph.clone()).unwrap();
+        table.set_font_name("Renamed-Regular");
+
+        let built = table.build().unwrap();
+        let reparsed = CffTable::parse(&built, false).unwrap();
+
+        assert_eq!(
+            reparsed
+                .charstrings
+                .iter()
+                .map(|c| c.as_ref())
+                .collect::<Vec<&[u8]>>(),
+            [&empty[..], &glyph[..]]
+        );
+        assert_eq!(reparsed.nominal_width(0), 500.0);
+        assert_eq!(
+            index_items(&reparsed.names, 0, false).unwrap(),
+            [b"Renamed-Regular"]
+        );
+    }
+
+    /// An ItemVariationStore with one ItemVariationData over two regions, prefixed by
+    /// its length as in a CFF2 table.
+    fn test_variation_store() -> Vec<u8> {
+        let mut store = vec![0, 22, 0, 1, 0, 0, 0, 0, 0, 1];
+        store.extend_from_slice(&12u32.to_be_bytes());
+        store.extend_from_slice(&[0, 0, 0, 0, 0, 2, 0, 0, 0, 1]);
+        store
+    }
+
+    /// vsindex 0, then BlueValues 100 plus a blended pair, then StdHW 50.
+    const BLENDED_PRIVATE: [u8; 14] = [
+        139, 22, 239, 149, 159, 140, 141, 142, 143, 141, 23, 6, 189, 10,
+    ];
+
+    #[test]
+    fn blends_resolve_to_defaults() {
+        let store = test_variation_store();
+        let dict = Dict::parse(&BLENDED_PRIVATE).unwrap();
+        let resolved = resolve_blends(&dict, Some(&store)).unwrap();
+
+        assert_eq!(resolved.to_bytes(), [239, 149, 159, 6, 189, 10]);
+        assert!(resolve_blends(&dict, None).is_err());
+    }
+
+    /// A CFF2 table with a variation store and a single Font DICT.
+    fn test_cff2_table(charstrings: &[Vec<u8>]) -> Vec<u8> {
+        let store = test_variation_store();
+        let global_subrs = write_index::<&[u8]>(&[], true);
+        let charstrings = write_index(charstrings, true);
+
+        let mut top_dict = Dict(Vec::new());
+
+        for op in [OP_VSTORE, OP_CHARSTRINGS, OP_FD_ARRAY] {
+            top_dict.set(op, &[0]);
+        }
+
+        let mut font_dict = Dict(Vec::new());
+        font_dict.set(OP_PRIVATE, &[0, 0]);
+
+        let vstore_offset = 5 + top_dict.to_bytes().len() + global_subrs.len();
+        let charstrings_offset = vstore_offset + store.len();
+        let fd_array_offset = charstrings_offset + charstrings.len();
+        let private_offset = fd_array_offset + write_index(&[font_dict.to_bytes()], true).len();
+
+        top_dict.set(OP_VSTORE, &[vstore_offset]);
+        top_dict.set(OP_CHARSTRINGS, &[charstrings_offset]);
+        top_dict.set(OP_FD_ARRAY, &[fd_array_offset]);
+        font_dict.set(OP_PRIVATE, &[BLENDED_PRIVATE.len(), private_offset]);
+
+        let top_dict = top_dict.to_bytes();
+        let mut header = vec![2, 0, 5];
+        header.extend_from_slice(&(top_dict.len() as u16).to_be_bytes());
+
+        let parts: [&[u8]; 7] = [
+            &header,
+            &top_dict,
+            &global_subrs,
+            &store,
+            &charstrings,
+            &write_index(&[font_dict.to_bytes()], true),
+            &BLENDED_PRIVATE,
+        ];
+
+        parts.concat()
+    }
+
+    #[test]
+    fn cff2_converts_to_cid_keyed() {
+        let mut path = BezPath::new();
+        path.move_to((10.0, 20.0));
+        path.line_to((300.0, 20.0));
+        path.line_to((300.0, 700.0));
+        path.close_path();
+
+        let source = test_cff2_table(&[
+            encode_charstring(&BezPath::new(), None, true).unwrap(),
+            encode_charstring(&path, None, true).unwrap(),
+        ]);
+        let table = CffTable::parse(&source, true).unwrap();
+
+        let glyphs = [
+            encode_charstring(&BezPath::new(), Some(0.0), false).unwrap(),
+            encode_charstring(&path, Some(600.0), false).unwrap(),
+        ];
+        let data = table.to_cid_keyed("Test-Bold", &glyphs).unwrap();
+        let converted = CffTable::parse(&data, false).unwrap();
+
+        assert_eq!(
+            converted
+                .charstrings
+                .iter()
+                .map(|c| c.as_ref())
+                .collect::<Vec<&[u8]>>(),
+            [&glyphs[0][..], &glyphs[1][..]]
+        );
+        assert_eq!(
+            index_items(&converted.names, 0, false).unwrap(),
+            [b"Test-Bold"]
+        );
+        assert_eq!(
+            converted
+                .top_dict
+                .operands(OP_ROS)
+                .unwrap()
+                .iter()
+                .map(|operand| operand.value)
+                .collect::<Vec<f64>>(),
+            [391.0, 392.0, 0.0]
+        );
+        assert_eq!(converted.top_dict.number(OP_CID_COUNT), Some(2.0));
+        assert!(converted.variation_store.is_none());
+        assert_eq!(converted.font_dicts.len(), 1);
+        assert_eq!(
+            converted.font_dicts[0]
+                .private
+                .as_ref()
+                .unwrap()
+                .dict
+                .to_bytes(),
+            [239, 149, 159, 6, 189, 10]
+        );
+
+        // The layout is the one `build` writes
+        assert_eq!(converted.build().unwrap(), data);
+
+        assert!(table.to_cid_keyed("Test-Bold", &glyphs[..1]).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use fontcull_read_fonts::{TopLevelTable, tables::head::Head, types::Tag};
+use tracing::warn;
+
+/// Offset of `checksumAdjustment` in the `head` table.
+const CHECKSUM_ADJUSTMENT_OFFSET: usize = 8;
+
+/// What the table directory and every table of a font sum to once its
+/// `checksumAdjustment` is set.
+pub const CHECKSUM_MAGIC: u32 = 0xB1B0_AFBA;
+
+const TTC_TAG: &[u8; 4] = b"ttcf";
+
+/// Sum `data` as big-endian `u32` words, padding the last word with zeros.
+pub fn table_checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0u8; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/// Checksum of a table, treating `head.checksumAdjustment` as zero as the spec requires.
+pub fn checksum_for(tag: Tag, data: &[u8]) -> u32 {
+    let checksum = table_checksum(data);
+
+    if tag != Head::TAG {
+        return checksum;
+    }
+
+    match data.get(CHECKSUM_ADJUSTMENT_OFFSET..CHECKSUM_ADJUSTMENT_OFFSET + 4) {
+        Some(&[a, b, c, d]) => checksum.wrapping_sub(u32::from_be_bytes([a, b, c, d])),
+        _ => checksum,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Table directory out of bounds")?;
+
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+/// Offsets of the table directory of every face in a font or collection.
+pub fn directory_offsets(data: &[u8]) -> Result<Vec<usize>> {
+    if !data.starts_with(TTC_TAG) {
+        return Ok(vec![0]);
+    }
+
+    let num_fonts = read_u32(data, 8)? as usize;
+
+    (0..num_fonts)
+        .map(|idx| Ok(read_u32(data, 12 + idx * 4)? as usize))
+        .collect()
+}
+
+/// A table record of a face, as found in its table directory.
+struct Record {
+    /// Position of the record within the file
+    pos: usize,
+    tag: Tag,
+    offset: usize,
+    length: usize,
+}
+
+fn records(data: &[u8], directory_offset: usize) -> Result<Vec<Record>> {
+    let num_tables = (read_u32(data, directory_offset + 4)? >> 16) as usize;
+
+    (0..num_tables)
+        .map(|idx| {
+            let pos = directory_offset + 12 + idx * 16;
+
+            Ok(Record {
+                pos,
+                tag: Tag::from_u32(read_u32(data, pos)?),
+                offset: read_u32(data, pos + 8)? as usize,
+                length: read_u32(data, pos + 12)? as usize,
+            })
+        })
+        .collect()
+}
+
+/// Sum of the table directory at `directory_offset` and every table it points to, as
+/// stored. This equals [`CHECKSUM_MAGIC`] when `checksumAdjustment` is correct.
+pub fn font_checksum(data: &[u8], directory_offset: usize) -> Result<u32> {
+    let records = records(data, directory_offset)?;
+    let directory = data
+        .get(directory_offset..directory_offset + 12 + records.len() * 16)
+        .context("Table directory out of bounds")?;
+
+    records
+        .iter()
+        .try_fold(table_checksum(directory), |sum, record| {
+            let table = data
+                .get(record.offset..record.offset + record.length)
+                .with_context(|| format!("'{}' table out of bounds", record.tag))?;
+
+            Ok(sum.wrapping_add(table_checksum(table)))
+        })
+}
+
+/// Verify the table record checksums of every face in a font or collection, correcting
+/// any that are wrong, then set each face's `head.checksumAdjustment`.
+///
+/// Faces of a collection must not share a `head` table, as each needs its own adjustment.
+pub fn update_checksums(data: &mut [u8]) -> Result<()> {
+    for (face, directory_offset) in directory_offsets(data)?.into_iter().enumerate() {
+        let records = records(data, directory_offset)?;
+        let mut head_offset = None;
+
+        for record in &records {
+            let table = data
+                .get(record.offset..record.offset + record.length)
+                .with_context(|| format!("'{}' table out of bounds", record.tag))?;
+
+            let checksum = checksum_for(record.tag, table);
+
+            if checksum != read_u32(data, record.pos + 4)? {
+                warn!(
+                    "Face {face}: '{}' table checksum was incorrect and has been corrected",
+                    record.tag
+                );
+
+                data[record.pos + 4..record.pos + 8].copy_from_slice(&checksum.to_be_bytes());
+            }
+
+            if record.tag == Head::TAG && record.length >= CHECKSUM_ADJUSTMENT_OFFSET + 4 {
+                head_offset = Some(record.offset + CHECKSUM_ADJUSTMENT_OFFSET);
+            }
+        }
+
+        let Some(adjustment_offset) = head_offset else {
+            continue;
+        };
+
+        // Sum the font with the adjustment zeroed, then make up the difference
+        data[adjustment_offset..adjustment_offset + 4].copy_from_slice(&[0; 4]);
+
+        let adjustment = CHECKSUM_MAGIC.wrapping_sub(font_checksum(data, directory_offset)?);
+        data[adjustment_offset..adjustment_offset + 4].copy_from_slice(&adjustment.to_be_bytes());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fonts::{font, head};
+
+    /// A collection with one face per revision, each with its own `head` table and zeroed
+    /// record checksums.
+    fn collection(revisions: &[u32]) -> Vec<u8> {
+        let directories_start = 12 + 4 * revisions.len();
+        let tables_start = directories_start + 28 * revisions.len();
+
+        let mut data = TTC_TAG.to_vec();
+        data.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        data.extend_from_slice(&(revisions.len() as u32).to_be_bytes());
+
+        for idx in 0..revisions.len() {
+            data.extend_from_slice(&((directories_start + idx * 28) as u32).to_be_bytes());
+        }
+
+        for idx in 0..revisions.len() {
+            data.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+            // One table: searchRange 16, entrySelector 0, rangeShift 0
+            data.extend_from_slice(&[0, 1, 0, 16, 0, 0, 0, 0]);
+            data.extend_from_slice(&Head::TAG.to_be_bytes());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(&((tables_start + idx * 56) as u32).to_be_bytes());
+            data.extend_from_slice(&54u32.to_be_bytes());
+        }
+
+        for &revision in revisions {
+            data.extend(head(revision, 0));
+            data.extend_from_slice(&[0; 2]);
+        }
+
+        data
+    }
+
+    #[test]
+    fn table_checksum_pads_last_word() {
+        assert_eq!(table_checksum(&[]), 0);
+        assert_eq!(table_checksum(&[0, 0, 0, 1, 2]), 0x0200_0001);
+        assert_eq!(table_checksum(&[0xFF; 8]), 0xFFFF_FFFE);
+    }
+
+    #[test]
+    fn head_checksum_ignores_adjustment() {
+        let tag = Head::TAG;
+
+        assert_eq!(
+            checksum_for(tag, &head(0x0001_0000, 0)),
+            checksum_for(tag, &head(0x0001_0000, 0xDEAD_BEEF))
+        );
+        assert_ne!(
+            checksum_for(Tag::new(b"zero"), &head(0x0001_0000, 0)),
+            checksum_for(Tag::new(b"zero"), &head(0x0001_0000, 0xDEAD_BEEF))
+        );
+    }
+
+    #[test]
+    fn update_checksums_sets_magic() {
+        let mut data = font(&[
+            (b"head", &head(0x0001_8000, 0xDEAD_BEEF)),
+            (b"odd ", &[1, 2, 3, 4, 5]),
+        ]);
+        // Corrupt the first record checksum
+        data[16..20].copy_from_slice(&0u32.to_be_bytes());
+
+        update_checksums(&mut data).unwrap();
+
+        assert_eq!(font_checksum(&data, 0).unwrap(), CHECKSUM_MAGIC);
+
+        for record in records(&data, 0).unwrap() {
+            let table = &data[record.offset..record.offset + record.length];
+
+            assert_eq!(
+                read_u32(&data, record.pos + 4).unwrap(),
+                checksum_for(record.tag, table)
+            );
+        }
+    }
+
+    #[test]
+    fn update_checksums_adjusts_every_face() {
+        let mut data = collection(&[0x0001_0000, 0x0002_0000]);
+
+        update_checksums(&mut data).unwrap();
+
+        let offsets = directory_offsets(&data).unwrap();
+        assert_eq!(offsets, [20, 48]);
+
+        let adjustments: Vec<u32> = offsets
+            .iter()
+            .map(|&offset| {
+                assert_eq!(font_checksum(&data, offset).unwrap(), CHECKSUM_MAGIC);
+
+                let head = &records(&data, offset).unwrap()[0];
+                read_u32(&data, head.offset + CHECKSUM_ADJUSTMENT_OFFSET).unwrap()
+            })
+            .collect();
+
+        // Each face gets its own adjustment
+        assert_ne!(adjustments[0], adjustments[1]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+
+use anyhow::{Result, bail};
+
+/// The container a font file is stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    /// A plain TrueType/OpenType font or collection
+    Sfnt,
+    Woff,
+    Woff2,
+}
+
+impl Container {
+    /// Detect the container of `data` from its signature.
+    pub fn detect(data: &[u8]) -> Self {
+        match data.get(0..4) {
+            Some(b"wOFF") => Container::Woff,
+            Some(b"wOF2") => Container::Woff2,
+            _ => Container::Sfnt,
+        }
+    }
+
+    /// File extension for fonts in this container, or `None` to keep the sfnt extension.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Container::Sfnt => None,
+            Container::Woff => Some("woff"),
+            Container::Woff2 => Some("woff2"),
+        }
+    }
+}
+
+/// Unwrap WOFF and WOFF2 data (including WOFF2 collections) into plain sfnt data.
+/// Sfnt data is returned as-is.
+pub fn decode(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match Container::detect(data) {
+        Container::Sfnt => Ok(Cow::Borrowed(data)),
+        #[cfg(feature = "woff")]
+        Container::Woff => Ok(Cow::Owned(crate::woff::decompress(data)?)),
+        #[cfg(feature = "woff2")]
+        Container::Woff2 => {
+            Ok(Cow::Owned(woofwoof::decompress(data).ok_or_else(|| {
+                anyhow::anyhow!("WOFF2 decompression failed")
+            })?))
+        }
+        #[allow(unreachable_patterns)]
+        container => bail!("Reading {container:?} fonts requires the corresponding feature"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_signatures() {
+        assert_eq!(Container::detect(b"wOFF\0\x01\0\0"), Container::Woff);
+        assert_eq!(Container::detect(b"wOF2"), Container::Woff2);
+        assert_eq!(Container::detect(&[0, 1, 0, 0]), Container::Sfnt);
+        assert_eq!(Container::detect(b"ttcf"), Container::Sfnt);
+        // Too short for a signature
+        assert_eq!(Container::detect(b"wOF"), Container::Sfnt);
+    }
+
+    #[test]
+    fn sfnt_is_borrowed() {
+        let data = [0, 1, 0, 0, 0, 0];
+
+        assert!(matches!(decode(&data).unwrap(), Cow::Borrowed(decoded) if decoded == data));
+    }
+
+    #[cfg(feature = "woff")]
+    #[test]
+    fn woff_is_decompressed() {
+        use fontcull_read_fonts::{FontRef, types::Tag};
+
+        use crate::{
+            test_fonts::{font, head},
+            woff::{self, WoffExtras},
+        };
+
+        let tag = Tag::new(b"zero");
+        let sfnt = font(&[(b"head", &head(0x0001_0000, 0)), (b"zero", &[0; 1000])]);
+
+        let woff = woff::compress(&sfnt, WoffExtras::default()).unwrap();
+        let decoded = decode(&woff).unwrap();
+
+        assert!(matches!(decoded, Cow::Owned(_)));
+        assert_eq!(
+            FontRef::new(&decoded)
+                .unwrap()
+                .table_data(tag)
+                .unwrap()
+                .as_bytes(),
+            [0; 1000]
+        );
+    }
+
+    #[cfg(feature = "woff2")]
+    #[test]
+    fn corrupt_woff2_is_an_error() {
+        assert!(decode(b"wOF2\0\0\0\0").is_err());
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{VecDeque, hash_map::Entry};
+
+use anyhow::{Context, Result};
+use fontcull_read_fonts::{
+    tables::{
+        glyf::{Glyf as ReadGlyf, Glyph as ReadGlyph},
+        loca::Loca as ReadLoca,
+    },
+    types::GlyphId,
+};
+use fontcull_write_fonts::{
+    dump_table,
+    tables::{
+        glyf::Glyph,
+        loca::{Loca, LocaFormat},
+    },
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::metrics::GlyphBounds;
+
+/// Maximum component nesting depth followed when inspecting composite glyphs.
+const MAX_COMPONENT_DEPTH: u8 = 64;
+
+/// Builds `glyf` and `loca` from a mix of original glyph data and rebuilt glyphs.
+///
+/// Unlike `GlyfLocaBuilder`, original glyph records can be copied byte-for-byte, which
+/// keeps composite structure and TrueType instructions intact.
+pub struct GlyfBuilder {
+    glyf: Vec<u8>,
+    offsets: Vec<u32>,
+    bounds: Vec<Option<GlyphBounds>>,
+    max_points: u16,
+    max_contours: u16,
+}
+
+/// The output of [`GlyfBuilder`], along with values `maxp`, `head` and `hmtx` derive from it.
+pub struct BuiltGlyf {
+    pub glyf: Vec<u8>,
+    pub loca: Loca,
+    pub loca_format: LocaFormat,
+    /// Bounding box of every glyph, or `None` for glyphs without outlines
+    pub bounds: Vec<Option<GlyphBounds>>,
+    /// Most points in a simple glyph
+    pub max_points: u16,
+    /// Most contours in a simple glyph
+    pub max_contours: u16,
+}
+
+impl GlyfBuilder {
+    pub fn new() -> Self {
+        Self {
+            glyf: Vec::new(),
+            offsets: vec![0],
+            bounds: Vec::new(),
+            max_points: 0,
+            max_contours: 0,
+        }
+    }
+
+    /// Append raw glyph data as-is.
+    pub fn add_raw(&mut self, data: &[u8]) {
+        self.record_header(data);
+        self.glyf.extend_from_slice(data);
+
+        // Short loca offsets are stored divided by two
+        if self.glyf.len() % 2 != 0 {
+            self.glyf.push(0);
+        }
+
+        self.offsets.push(self.glyf.len() as u32);
+    }
+
+    /// Compile and append a rebuilt glyph.
+    pub fn add_glyph(&mut self, glyph: &Glyph) -> Result<()> {
+        let data = dump_table(glyph).context("Failed to compile glyph")?;
+        self.add_raw(&data);
+
+        Ok(())
+    }
+
+    /// Returns the `glyf` data, the `loca` table and its format.
+    pub fn build(self) -> BuiltGlyf {
+        let loca = Loca::new(self.offsets);
+        let loca_format = loca.format();
+
+        BuiltGlyf {
+            glyf: self.glyf,
+            loca,
+            loca_format,
+            bounds: self.bounds,
+            max_points: self.max_points,
+            max_contours: self.max_contours,
+        }
+    }
+
+    /// Track the bounding box and point counts from a glyph header.
+    fn record_header(&mut self, data: &[u8]) {
+        let read_i16 = |offset: usize| {
+            data.get(offset..offset + 2)
+                .map(|bytes| i16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+
+        let contours = read_i16(0).unwrap_or_default();
+
+        let bounds = match (read_i16(2), read_i16(4), read_i16(6), read_i16(8)) {
+            (Some(x_min), Some(y_min), Some(x_max), Some(y_max)) if contours != 0 => {
+                Some(GlyphBounds {
+                    x_min,
+                    y_min,
+                    x_max,
+                    y_max,
+                })
+            }
+            _ => None,
+        };
+
+        self.bounds.push(bounds);
+
+        // Composite glyphs keep their original maxp values
+        if contours > 0 {
+            // The last endPtsOfContours entry is the index of the last point
+            let last_point = read_i16(10 + (contours as usize - 1) * 2).unwrap_or_default();
+
+            self.max_points = self.max_points.max(last_point as u16 + 1);
+            self.max_contours = self.max_contours.max(contours as u16);
+        }
+    }
+}
+
+impl Default for GlyfBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the original `glyf` record for `gid`, or `None` if it cannot be located.
+pub fn raw_glyph<'a>(glyf: &ReadGlyf<'a>, loca: &ReadLoca, gid: GlyphId) -> Option<&'a [u8]> {
+    let idx = gid.to_u32() as usize;
+    let start = loca.get_raw(idx)? as usize;
+    let end = loca.get_raw(idx + 1)? as usize;
+
+    glyf.offset_data().as_bytes().get(start..end)
+}
+
+/// Every glyph `gid` (transitively) uses as a component, each listed once, following at
+/// most [`MAX_COMPONENT_DEPTH`] levels of nesting.
+pub fn components(glyf: &ReadGlyf, loca: &ReadLoca, gid: GlyphId) -> Vec<GlyphId> {
+    let mut found = Vec::new();
+    let mut stack = vec![(gid, 0)];
+
+    while let Some((parent, depth)) = stack.pop() {
+        if depth > MAX_COMPONENT_DEPTH {
+            continue;
+        }
+
+        if let Ok(Some(ReadGlyph::Composite(composite))) = loca.get_glyf(parent, glyf) {
+            for (component, _) in composite.component_glyphs_and_flags() {
+                let component = GlyphId::from(component);
+
+                // Shared and cyclic components are only followed once
+                if component != gid && !found.contains(&component) {
+                    found.push(component);
+                    stack.push((component, depth + 1));
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// Composite glyphs among the first `num_glyphs` that (transitively) use a component
+/// matching `predicate`, following at most [`MAX_COMPONENT_DEPTH`] levels of nesting.
+///
+/// Computed in one breadth-first pass from the matching glyphs up to the composites that
+/// use them, so shared component graphs are walked once rather than per glyph.
+pub fn glyphs_with_component(
+    glyf: &ReadGlyf,
+    loca: &ReadLoca,
+    num_glyphs: u32,
+    predicate: impl Fn(GlyphId) -> bool,
+) -> FxHashSet<GlyphId> {
+    // Composites using each glyph as a component
+    let mut users = FxHashMap::<GlyphId, Vec<GlyphId>>::default();
+
+    for gid in (0..num_glyphs).map(GlyphId::new) {
+        if let Ok(Some(ReadGlyph::Composite(composite))) = loca.get_glyf(gid, glyf) {
+            for (component, _) in composite.component_glyphs_and_flags() {
+                users.entry(GlyphId::from(component)).or_default().push(gid);
+            }
+        }
+    }
+
+    // Fewest nesting levels from each glyph down to a matching component
+    let mut levels = FxHashMap::<GlyphId, u8>::default();
+    let mut queue = VecDeque::new();
+
+    for gid in (0..num_glyphs)
+        .map(GlyphId::new)
+        .filter(|&gid| predicate(gid))
+    {
+        levels.insert(gid, 0);
+        queue.push_back(gid);
+    }
+
+    let mut found = FxHashSet::default();
+
+    while let Some(gid) = queue.pop_front() {
+        let level = levels[&gid];
+
+        if level > MAX_COMPONENT_DEPTH {
+            continue;
+        }
+
+        for &user in users.get(&gid).into_iter().flatten() {
+            found.insert(user);
+
+            if let Entry::Vacant(entry) = levels.entry(user) {
+                entry.insert(level + 1);
+                queue.push_back(user);
+            }
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_read_fonts::{FontData, FontRead};
+
+    use super::*;
+
+    /// A triangle: one contour of three on-curve points, 29 bytes long.
+    fn simple() -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for value in [1i16, 0, 0, 100, 100, 2] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        // No instructions, three on-curve points with 16-bit deltas
+        data.extend_from_slice(&[0, 0, 1, 1, 1]);
+
+        for value in [0i16, 100, -50, 0, 0, 100] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        data
+    }
+
+    /// A composite glyph built from `components`.
+    fn composite(components: &[u16]) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for value in [-1i16, 0, 0, 100, 100] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for (idx, &component) in components.iter().enumerate() {
+            // ARG_1_AND_2_ARE_WORDS | ARGS_ARE_XY_VALUES, plus MORE_COMPONENTS
+            let flags: u16 = if idx + 1 < components.len() {
+                0x0023
+            } else {
+                0x0003
+            };
+
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(&component.to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+        }
+
+        data
+    }
+
+    /// Glyph 0 is empty, 1 is the triangle, 2 and 3 use it directly and transitively, 4
+    /// uses itself, 5 and 6 use each other, and 7 to 77 form a chain ending in glyph 1.
+    fn test_glyf() -> (BuiltGlyf, Vec<u8>) {
+        let mut builder = GlyfBuilder::new();
+
+        builder.add_raw(&[]);
+        builder.add_raw(&simple());
+        builder.add_raw(&composite(&[1]));
+        builder.add_raw(&composite(&[0, 2]));
+        builder.add_raw(&composite(&[4]));
+        builder.add_raw(&composite(&[6]));
+        builder.add_raw(&composite(&[5]));
+
+        for gid in 7..77 {
+            builder.add_raw(&composite(&[gid + 1]));
+        }
+
+        builder.add_raw(&composite(&[1]));
+
+        let built = builder.build();
+        let loca = dump_table(&built.loca).unwrap();
+
+        (built, loca)
+    }
+
+    #[test]
+    fn raw_records_are_padded_for_short_loca() {
+        let mut builder = GlyfBuilder::new();
+        builder.add_raw(&simple());
+        builder.add_raw(&[]);
+
+        let built = builder.build();
+
+        assert_eq!(built.glyf.len(), 30);
+        assert_eq!(built.loca_format, LocaFormat::Short);
+        assert_eq!(
+            built.bounds,
+            [
+                Some(GlyphBounds {
+                    x_min: 0,
+                    y_min: 0,
+                    x_max: 100,
+                    y_max: 100,
+                }),
+                None,
+            ]
+        );
+        assert_eq!((built.max_points, built.max_contours), (3, 1));
+    }
+
+    #[test]
+    fn large_glyf_uses_long_loca() {
+        let mut builder = GlyfBuilder::new();
+        builder.add_raw(&vec![0; 0x20000]);
+        builder.add_raw(&simple());
+
+        assert_eq!(builder.build().loca_format, LocaFormat::Long);
+    }
+
+    #[test]
+    fn composites_keep_maxima_but_record_bounds() {
+        let mut builder = GlyfBuilder::new();
+        builder.add_raw(&composite(&[1, 2]));
+
+        let built = builder.build();
+
+        assert!(built.bounds[0].is_some());
+        assert_eq!((built.max_points, built.max_contours), (0, 0));
+    }
+
+    #[test]
+    fn raw_glyph_returns_original_records() {
+        let (built, loca) = test_glyf();
+        let glyf = ReadGlyf::read(FontData::new(&built.glyf)).unwrap();
+        let loca =
+            ReadLoca::read(FontData::new(&loca), built.loca_format == LocaFormat::Long).unwrap();
+
+        assert_eq!(raw_glyph(&glyf, &loca, GlyphId::new(0)), Some(&[][..]));
+        // Records include their padding byte
+        assert_eq!(
+            raw_glyph(&glyf, &loca, GlyphId::new(1)),
+            Some(&[simple(), vec![0]].concat()[..])
+        );
+        assert_eq!(
+            raw_glyph(&glyf, &loca, GlyphId::new(2)),
+            Some(&composite(&[1])[..])
+        );
+        assert_eq!(raw_glyph(&glyf, &loca, GlyphId::new(78)), None);
+    }
+
+    #[test]
+    fn components_are_listed_once() {
+        let (built, loca) = test_glyf();
+        let glyf = ReadGlyf::read(FontData::new(&built.glyf)).unwrap();
+        let loca =
+            ReadLoca::read(FontData::new(&loca), built.loca_format == LocaFormat::Long).unwrap();
+
+        let components = |gid: u32| {
+            components(&glyf, &loca, GlyphId::new(gid))
+                .into_iter()
+                .map(GlyphId::to_u32)
+                .collect::<Vec<u32>>()
+        };
+
+        assert!(components(1).is_empty());
+        assert_eq!(components(3), [0, 2, 1]);
+        // Cycles end once every glyph has been seen
+        assert!(components(4).is_empty());
+        assert_eq!(components(5), [6]);
+        assert_eq!(components(76), [77, 1]);
+    }
+
+    #[test]
+    fn glyphs_with_component_follow_nesting() {
+        let (built, loca) = test_glyf();
+        let glyf = ReadGlyf::read(FontData::new(&built.glyf)).unwrap();
+        let loca =
+            ReadLoca::read(FontData::new(&loca), built.loca_format == LocaFormat::Long).unwrap();
+
+        let found = glyphs_with_component(&glyf, &loca, 78, |gid| gid.to_u32() == 1);
+        let uses_triangle = |gid: u32| found.contains(&GlyphId::new(gid));
+
+        assert!(!uses_triangle(1));
+        assert!(uses_triangle(2));
+        assert!(uses_triangle(3));
+        // Cycles stop at the depth limit
+        assert!(!uses_triangle(4));
+        assert!(!uses_triangle(5));
+        // Glyph 13 is 64 levels above the triangle, glyph 12 is one too many
+        assert!(uses_triangle(13));
+        assert!(!uses_triangle(12));
+    }
+}
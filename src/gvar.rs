@@ -0,0 +1,620 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use fontcull_read_fonts::{
+    FontRef,
+    tables::gvar::Gvar,
+    types::{F2Dot14, GlyphId},
+};
+use fontcull_skrifa::MetadataProvider;
+use fontcull_write_fonts::tables::{
+    glyf::SimpleGlyph,
+    gvar::{GlyphDelta, iup::iup_delta_optimize},
+};
+use kurbo::{Point, Vec2};
+use rustc_hash::FxHashMap;
+
+/// Tolerance (in font units) used when dropping deltas that IUP can infer.
+const IUP_TOLERANCE: f64 = 0.5;
+
+const EMBEDDED_PEAK_TUPLE: u16 = 0x8000;
+const INTERMEDIATE_REGION: u16 = 0x4000;
+const PRIVATE_POINT_NUMBERS: u16 = 0x2000;
+
+const DELTAS_ARE_ZERO: u8 = 0x80;
+const DELTAS_ARE_WORDS: u8 = 0x40;
+const POINTS_ARE_WORDS: u8 = 0x80;
+
+/// A region of the variation space, as (start, peak, end) per axis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region(Vec<(F2Dot14, F2Dot14, F2Dot14)>);
+
+impl Region {
+    /// The normalized location where this region has full influence.
+    pub fn peak(&self) -> Vec<F2Dot14> {
+        self.0.iter().map(|&(_, peak, _)| peak).collect()
+    }
+
+    /// The influence of this region at `coords`, between 0 and 1.
+    fn scalar(&self, coords: &[F2Dot14]) -> f64 {
+        let mut scalar = 1.0;
+
+        for (&(start, peak, end), &coord) in self.0.iter().zip(coords) {
+            let (start, peak, end, coord) = (
+                start.to_f32() as f64,
+                peak.to_f32() as f64,
+                end.to_f32() as f64,
+                coord.to_f32() as f64,
+            );
+
+            if peak == 0.0 || coord == peak || start > peak || peak > end {
+                continue;
+            }
+
+            if start < 0.0 && end > 0.0 {
+                continue;
+            }
+
+            if coord <= start || coord >= end {
+                return 0.0;
+            }
+
+            scalar *= if coord < peak {
+                (coord - start) / (peak - start)
+            } else {
+                (end - coord) / (end - peak)
+            };
+        }
+
+        scalar
+    }
+
+    /// Whether the region needs explicit start/end tuples.
+    fn is_intermediate(&self) -> bool {
+        self.0.iter().any(|&(start, peak, end)| {
+            (start, end) != (peak.min(F2Dot14::ZERO), peak.max(F2Dot14::ZERO))
+        })
+    }
+}
+
+/// Regions at the minimum and maximum of every axis that extends past its default.
+pub fn axis_extremes(font: &FontRef) -> Vec<Region> {
+    let axes = font.axes();
+    let mut regions = Vec::new();
+
+    for (idx, axis) in axes.iter().enumerate() {
+        let extremes = [
+            (axis.min_value() < axis.default_value()).then_some(-1.0),
+            (axis.max_value() > axis.default_value()).then_some(1.0),
+        ];
+
+        for peak in extremes.into_iter().flatten() {
+            let peak = F2Dot14::from_f32(peak);
+            let mut region = vec![(F2Dot14::ZERO, F2Dot14::ZERO, F2Dot14::ZERO); axes.len()];
+            region[idx] = (peak.min(F2Dot14::ZERO), peak, peak.max(F2Dot14::ZERO));
+
+            regions.push(Region(region));
+        }
+    }
+
+    regions
+}
+
+/// The regions to solve for `glyphs`: the regions of each glyph followed by `extra`.
+///
+/// `glyphs` is a glyph followed by its components, since composites are rebuilt as simple
+/// glyphs and their components' intermediate masters would otherwise be lost. These
+/// regions reproduce the base outline, while the axis extremes let annotations drawn from
+/// a variable ruby font vary as well.
+pub fn glyph_regions(gvar: &Gvar, glyphs: &[GlyphId], extra: &[Region]) -> Vec<Region> {
+    let axis_count = gvar.axis_count() as usize;
+    let mut regions = Vec::<Region>::new();
+
+    for data in glyphs
+        .iter()
+        .filter_map(|&gid| gvar.glyph_variation_data(gid).ok().flatten())
+    {
+        for tuple in data.tuples() {
+            let peak = tuple.peak();
+            let intermediate = tuple.intermediate_start().zip(tuple.intermediate_end());
+
+            let region = Region(
+                (0..axis_count)
+                    .map(|axis| {
+                        let peak = peak.get(axis).unwrap_or_default();
+
+                        match &intermediate {
+                            Some((start, end)) => (
+                                start.get(axis).unwrap_or_default(),
+                                peak,
+                                end.get(axis).unwrap_or_default(),
+                            ),
+                            None => (peak.min(F2Dot14::ZERO), peak, peak.max(F2Dot14::ZERO)),
+                        }
+                    })
+                    .collect(),
+            );
+
+            if !regions.contains(&region) {
+                regions.push(region);
+            }
+        }
+    }
+
+    for region in extra {
+        if !regions.contains(region) {
+            regions.push(region.clone());
+        }
+    }
+
+    regions
+}
+
+/// Encode `GlyphVariationData` for a glyph.
+///
+/// `glyphs[0]` and `advances[0]` are the default master, followed by one master
+/// drawn at the peak of each region in `regions`.
+pub fn glyph_variation_data(
+    glyphs: &[SimpleGlyph],
+    advances: &[f64],
+    regions: &[Region],
+) -> Result<Vec<u8>> {
+    ensure!(
+        glyphs.len() == regions.len() + 1 && advances.len() == glyphs.len(),
+        "Expected one master per region"
+    );
+
+    let contour_ends = glyphs[0]
+        .contours
+        .iter()
+        .scan(0, |total, contour| {
+            *total += contour.len();
+            Some(*total - 1)
+        })
+        .collect::<Vec<usize>>();
+
+    // Outline points followed by the four phantom points
+    let masters = glyphs
+        .iter()
+        .zip(advances)
+        .map(|(glyph, &advance)| {
+            glyph
+                .contours
+                .iter()
+                .flat_map(|contour| contour.iter())
+                .map(|point| Vec2::new(point.x as f64, point.y as f64))
+                .chain([
+                    Vec2::ZERO,
+                    Vec2::new(advance.round(), 0.0),
+                    Vec2::ZERO,
+                    Vec2::ZERO,
+                ])
+                .collect::<Vec<Vec2>>()
+        })
+        .collect::<Vec<_>>();
+
+    ensure!(
+        masters
+            .iter()
+            .all(|points| points.len() == masters[0].len()),
+        "Masters have different point counts"
+    );
+
+    let coords = masters[0]
+        .iter()
+        .map(|point| point.to_point())
+        .collect::<Vec<Point>>();
+
+    let mut tuples = Vec::new();
+
+    for (region, deltas) in regions.iter().zip(solve_deltas(regions, &masters)) {
+        if deltas
+            .iter()
+            .all(|delta| delta.x.round() == 0.0 && delta.y.round() == 0.0)
+        {
+            continue;
+        }
+
+        let deltas = iup_delta_optimize(deltas, coords.clone(), IUP_TOLERANCE, &contour_ends)
+            .map_err(|err| anyhow!("Failed to optimize deltas: {err:?}"))?;
+
+        if deltas.iter().any(|delta| delta.required) {
+            tuples.push((region, deltas));
+        }
+    }
+
+    if tuples.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let tuple_count = tuples.len();
+    let mut headers = Vec::new();
+    let mut serialized = Vec::new();
+
+    for (region, deltas) in tuples {
+        let mut body = Vec::new();
+
+        let points = deltas
+            .iter()
+            .enumerate()
+            .filter(|(_, delta)| delta.required)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<usize>>();
+
+        if points.len() == deltas.len() {
+            // Zero means every point has a delta
+            body.push(0);
+        } else {
+            encode_points(&mut body, &points);
+        }
+
+        let included = deltas
+            .iter()
+            .filter(|delta| delta.required)
+            .collect::<Vec<&GlyphDelta>>();
+        encode_deltas(&mut body, &included.iter().map(|d| d.x).collect::<Vec<_>>());
+        encode_deltas(&mut body, &included.iter().map(|d| d.y).collect::<Vec<_>>());
+
+        let intermediate = region.is_intermediate();
+        let mut flags = EMBEDDED_PEAK_TUPLE | PRIVATE_POINT_NUMBERS;
+
+        if intermediate {
+            flags |= INTERMEDIATE_REGION;
+        }
+
+        headers.extend((body.len() as u16).to_be_bytes());
+        headers.extend(flags.to_be_bytes());
+
+        for &(_, peak, _) in &region.0 {
+            headers.extend(peak.to_bits().to_be_bytes());
+        }
+
+        if intermediate {
+            for &(start, _, _) in &region.0 {
+                headers.extend(start.to_bits().to_be_bytes());
+            }
+
+            for &(_, _, end) in &region.0 {
+                headers.extend(end.to_bits().to_be_bytes());
+            }
+        }
+
+        serialized.extend(body);
+    }
+
+    // tupleVariationCount, offset to serialized data, headers, then the data itself
+    let mut data = Vec::with_capacity(4 + headers.len() + serialized.len());
+    data.extend((tuple_count as u16).to_be_bytes());
+    data.extend(((4 + headers.len()) as u16).to_be_bytes());
+    data.extend(headers);
+    data.extend(serialized);
+
+    if data.len() % 2 != 0 {
+        data.push(0);
+    }
+
+    Ok(data)
+}
+
+/// Solve per-region deltas so that interpolating at each region's peak reproduces the
+/// master drawn there. `masters[0]` is the default master.
+fn solve_deltas(regions: &[Region], masters: &[Vec<Vec2>]) -> Vec<Vec<Vec2>> {
+    let peaks = regions.iter().map(Region::peak).collect::<Vec<_>>();
+    let mut deltas: Vec<Option<Vec<Vec2>>> = vec![None; regions.len()];
+
+    for _ in 0..regions.len() {
+        let pending = |idx: &usize| deltas[*idx].is_none();
+
+        // Regions that contribute at another region's peak must be solved before it
+        let next = (0..regions.len())
+            .filter(pending)
+            .find(|&idx| {
+                (0..regions.len()).all(|other| {
+                    other == idx || !pending(&other) || regions[other].scalar(&peaks[idx]) == 0.0
+                })
+            })
+            .or_else(|| (0..regions.len()).find(pending))
+            .expect("a region is still pending");
+
+        let mut delta = masters[next + 1]
+            .iter()
+            .zip(&masters[0])
+            .map(|(master, default)| *master - *default)
+            .collect::<Vec<Vec2>>();
+
+        for (other, solved) in deltas.iter().enumerate() {
+            let Some(solved) = solved else { continue };
+            let scalar = regions[other].scalar(&peaks[next]);
+
+            if scalar != 0.0 {
+                for (delta, solved) in delta.iter_mut().zip(solved) {
+                    *delta -= *solved * scalar;
+                }
+            }
+        }
+
+        deltas[next] = Some(delta);
+    }
+
+    deltas.into_iter().flatten().collect()
+}
+
+/// Encode packed point numbers.
+fn encode_points(out: &mut Vec<u8>, points: &[usize]) {
+    if points.len() < 0x80 {
+        out.push(points.len() as u8);
+    } else {
+        out.extend((points.len() as u16 | 0x8000).to_be_bytes());
+    }
+
+    let mut last = 0;
+    let diffs = points
+        .iter()
+        .map(|&point| {
+            let diff = point - last;
+            last = point;
+            diff
+        })
+        .collect::<Vec<usize>>();
+
+    for run in diffs.chunks(128) {
+        if run.iter().all(|&diff| diff <= u8::MAX as usize) {
+            out.push((run.len() - 1) as u8);
+            out.extend(run.iter().map(|&diff| diff as u8));
+        } else {
+            out.push(POINTS_ARE_WORDS | (run.len() - 1) as u8);
+
+            for &diff in run {
+                out.extend((diff as u16).to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Encode packed deltas.
+fn encode_deltas(out: &mut Vec<u8>, values: &[i16]) {
+    let is_byte = |value: i16| i8::try_from(value).is_ok();
+    let mut idx = 0;
+
+    while idx < values.len() {
+        let run_len = |pred: &dyn Fn(i16) -> bool| {
+            values[idx..]
+                .iter()
+                .take(64)
+                .take_while(|&&value| pred(value))
+                .count()
+        };
+
+        let len = if values[idx] == 0 {
+            let len = run_len(&|value| value == 0);
+            out.push(DELTAS_ARE_ZERO | (len - 1) as u8);
+            len
+        } else if is_byte(values[idx]) {
+            let len = run_len(&|value| value != 0 && is_byte(value));
+            out.push((len - 1) as u8);
+            out.extend(
+                values[idx..idx + len]
+                    .iter()
+                    .map(|&value| value as i8 as u8),
+            );
+            len
+        } else {
+            let len = run_len(&|value| !is_byte(value));
+            out.push(DELTAS_ARE_WORDS | (len - 1) as u8);
+
+            for &value in &values[idx..idx + len] {
+                out.extend(value.to_be_bytes());
+            }
+
+            len
+        };
+
+        idx += len;
+    }
+}
+
+/// Rewrite a `gvar` table, replacing the variation data of the glyphs in `replaced`.
+pub fn rebuild_gvar(data: &[u8], replaced: &FxHashMap<GlyphId, Vec<u8>>) -> Result<Vec<u8>> {
+    let read_u16 = |offset: usize| -> Result<u16> {
+        Ok(u16::from_be_bytes(
+            data.get(offset..offset + 2)
+                .context("Truncated gvar table")?
+                .try_into()?,
+        ))
+    };
+    let read_u32 = |offset: usize| -> Result<u32> {
+        Ok(u32::from_be_bytes(
+            data.get(offset..offset + 4)
+                .context("Truncated gvar table")?
+                .try_into()?,
+        ))
+    };
+
+    let axis_count = read_u16(4)?;
+    let shared_tuple_count = read_u16(6)?;
+    let shared_tuples_offset = read_u32(8)? as usize;
+    let glyph_count = read_u16(12)?;
+    let flags = read_u16(14)?;
+    let data_array_offset = read_u32(16)? as usize;
+
+    let long_offsets = flags & 1 != 0;
+    let offset_of = |gid: usize| -> Result<usize> {
+        Ok(if long_offsets {
+            read_u32(20 + gid * 4)? as usize
+        } else {
+            read_u16(20 + gid * 2)? as usize * 2
+        })
+    };
+
+    let shared_tuples_len = shared_tuple_count as usize * axis_count as usize * 2;
+    let shared_tuples = data
+        .get(shared_tuples_offset..shared_tuples_offset + shared_tuples_len)
+        .context("Truncated gvar shared tuples")?;
+
+    let mut glyph_data = Vec::new();
+    let mut offsets = vec![0u32];
+
+    for gid in 0..glyph_count as usize {
+        match replaced.get(&GlyphId::new(gid as u32)) {
+            Some(replacement) => glyph_data.extend_from_slice(replacement),
+            None => {
+                let start = data_array_offset + offset_of(gid)?;
+                let end = data_array_offset + offset_of(gid + 1)?;
+
+                glyph_data.extend_from_slice(
+                    data.get(start..end)
+                        .context("Truncated gvar glyph variation data")?,
+                );
+            }
+        }
+
+        if glyph_data.len() % 2 != 0 {
+            glyph_data.push(0);
+        }
+
+        offsets.push(glyph_data.len() as u32);
+    }
+
+    let header_len = 20 + offsets.len() * 4;
+    let mut out = Vec::with_capacity(header_len + shared_tuples.len() + glyph_data.len());
+
+    out.extend_from_slice(&data[0..4]);
+    out.extend(axis_count.to_be_bytes());
+    out.extend(shared_tuple_count.to_be_bytes());
+    out.extend((header_len as u32).to_be_bytes());
+    out.extend(glyph_count.to_be_bytes());
+    out.extend((flags | 1).to_be_bytes());
+    out.extend(((header_len + shared_tuples.len()) as u32).to_be_bytes());
+
+    for offset in offsets {
+        out.extend(offset.to_be_bytes());
+    }
+
+    out.extend_from_slice(shared_tuples);
+    out.extend(glyph_data);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: f32, peak: f32, end: f32) -> Region {
+        Region(vec![(
+            F2Dot14::from_f32(start),
+            F2Dot14::from_f32(peak),
+            F2Dot14::from_f32(end),
+        )])
+    }
+
+    #[test]
+    fn byte_points() {
+        let mut out = Vec::new();
+        encode_points(&mut out, &[1, 2, 5]);
+
+        assert_eq!(out, [3, 2, 1, 1, 3]);
+    }
+
+    #[test]
+    fn word_points() {
+        let mut out = Vec::new();
+        encode_points(&mut out, &[0, 300]);
+
+        assert_eq!(out, [2, POINTS_ARE_WORDS | 1, 0, 0, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn long_point_runs() {
+        let points = (0..200).collect::<Vec<usize>>();
+        let mut out = Vec::new();
+        encode_points(&mut out, &points);
+
+        // Count as a word, then runs of at most 128
+        assert_eq!(out[..3], [0x80, 200, 127]);
+        assert_eq!(out[3 + 128], 71);
+        assert_eq!(out.len(), 2 + 1 + 128 + 1 + 72);
+    }
+
+    #[test]
+    fn delta_runs() {
+        let mut out = Vec::new();
+        encode_deltas(&mut out, &[1, -1, 0, 0, 300, -300, 5]);
+
+        assert_eq!(
+            out,
+            [
+                1,
+                1,
+                0xff,
+                DELTAS_ARE_ZERO | 1,
+                DELTAS_ARE_WORDS | 1,
+                0x01,
+                0x2c,
+                0xfe,
+                0xd4,
+                0,
+                5,
+            ]
+        );
+    }
+
+    #[test]
+    fn long_delta_runs() {
+        let mut out = Vec::new();
+        encode_deltas(&mut out, &[0; 100]);
+
+        assert_eq!(out, [DELTAS_ARE_ZERO | 63, DELTAS_ARE_ZERO | 35]);
+
+        out.clear();
+        encode_deltas(&mut out, &[1; 100]);
+
+        assert_eq!(out.len(), 1 + 64 + 1 + 36);
+        assert_eq!((out[0], out[65]), (63, 35));
+    }
+
+    #[test]
+    fn region_scalar() {
+        let region = region(0.0, 0.5, 1.0);
+
+        assert_eq!(region.scalar(&[F2Dot14::from_f32(0.5)]), 1.0);
+        assert_eq!(region.scalar(&[F2Dot14::from_f32(0.25)]), 0.5);
+        assert_eq!(region.scalar(&[F2Dot14::from_f32(1.0)]), 0.0);
+        assert_eq!(region.scalar(&[F2Dot14::from_f32(-0.5)]), 0.0);
+    }
+
+    #[test]
+    fn solve_overlapping_regions() {
+        // The intermediate region is listed first, but the extreme contributes at its peak
+        let regions = [region(0.0, 0.5, 1.0), region(0.0, 1.0, 1.0)];
+        let masters = [
+            vec![Vec2::ZERO, Vec2::new(100.0, 0.0)],
+            vec![Vec2::new(8.0, 0.0), Vec2::new(100.0, 4.0)],
+            vec![Vec2::new(10.0, 0.0), Vec2::new(100.0, 10.0)],
+        ];
+
+        let deltas = solve_deltas(&regions, &masters);
+
+        assert_eq!(
+            deltas,
+            [
+                vec![Vec2::new(3.0, 0.0), Vec2::new(0.0, -1.0)],
+                vec![Vec2::new(10.0, 0.0), Vec2::new(0.0, 10.0)],
+            ]
+        );
+
+        // Interpolating at each peak reproduces its master
+        for (idx, region) in regions.iter().enumerate() {
+            let peak = region.peak();
+
+            for point in 0..2 {
+                let interpolated = masters[0][point]
+                    + deltas
+                        .iter()
+                        .zip(&regions)
+                        .map(|(deltas, region)| deltas[point] * region.scalar(&peak))
+                        .fold(Vec2::ZERO, |sum, delta| sum + delta);
+
+                assert_eq!(interpolated, masters[idx + 1][point]);
+            }
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use anyhow::{Context, Result};
+use fontcull_font_types::NameId;
+use fontcull_read_fonts::{
+    FontRef, TableProvider, TopLevelTable,
+    tables::{cff::Cff, cff2::Cff2},
+    types::{CFF_SFNT_VERSION, F2Dot14, GlyphId, Tag},
+};
+use fontcull_skrifa::{
+    MetadataProvider,
+    instance::{LocationRef, Size},
+};
+use fontcull_write_fonts::{
+    FontBuilder,
+    from_obj::{ToOwnedObj, ToOwnedTable},
+    tables::{
+        gdef::Gdef,
+        glyf::{Glyf, Glyph, SimpleGlyph},
+        head::{Head, MacStyle},
+        hhea::Hhea,
+        hmtx::{Hmtx, LongMetric},
+        name::Name,
+        os2::{Os2, SelectionFlags},
+    },
+    types::UfWord,
+};
+use kurbo::BezPath;
+
+use crate::{
+    EmptyCause, EmptyGlyphs,
+    cff::{CffTable, encode_charstring},
+    glyf::GlyfBuilder,
+    mapped_codepoint, name, outline,
+    pen::PathPen,
+};
+
+/// Tables that only make sense in a variable font.
+const VARIATION_TABLES: [Tag; 8] = [
+    Tag::new(b"fvar"),
+    Tag::new(b"gvar"),
+    Tag::new(b"avar"),
+    Tag::new(b"cvar"),
+    Tag::new(b"HVAR"),
+    Tag::new(b"VVAR"),
+    Tag::new(b"MVAR"),
+    Tag::new(b"STAT"),
+];
+
+/// usWidthClass values, indexed by class - 1, as a percentage of normal width.
+const WIDTH_CLASSES: [f32; 9] = [50.0, 62.5, 75.0, 87.5, 100.0, 112.5, 125.0, 150.0, 200.0];
+
+/// A static font instantiated from a named instance of a variable font.
+pub struct StaticInstance {
+    pub data: Vec<u8>,
+    pub postscript_name: String,
+    /// Normalized coordinates of the instance, by axis tag
+    pub location: Vec<(Tag, F2Dot14)>,
+}
+
+/// Returns true if `font` is a variable font with named instances.
+pub fn has_named_instances(font: &FontRef) -> bool {
+    !font.axes().is_empty() && !font.named_instances().is_empty()
+}
+
+/// Instantiate `font` at each named instance listed in `fvar`.
+///
+/// Outlines and advances are resolved at each instance's location. Composite glyphs are
+/// flattened into simple glyphs and glyph hinting instructions are dropped, while
+/// layout tables keep their default-location values. `CFF2` outlines are written as a
+/// CID-keyed `CFF ` table, since static fonts have no use for its variation data.
+///
+/// Glyphs that cannot be drawn or encoded are left empty and summarized in a warning, or
+/// fail the instance if `strict`.
+pub fn named_instances(
+    font: &FontRef,
+    tolerance: f64,
+    strict: bool,
+) -> Result<Vec<StaticInstance>> {
+    font.named_instances()
+        .iter()
+        .map(|instance| {
+            let names = InstanceNames::new(
+                font,
+                instance.subfamily_name_id(),
+                instance.postscript_name_id(),
+            )?;
+            let user_coords = font
+                .axes()
+                .iter()
+                .zip(instance.user_coords())
+                .map(|(axis, value)| (axis.tag(), value))
+                .collect::<Vec<(Tag, f32)>>();
+            let location = instance.location();
+
+            let data = instantiate(
+                font,
+                LocationRef::from(&location),
+                &user_coords,
+                &names,
+                tolerance,
+                strict,
+            )
+            .with_context(|| format!("Failed to instantiate {}", names.postscript))?;
+
+            Ok(StaticInstance {
+                data,
+                postscript_name: names.postscript,
+                location: font
+                    .axes()
+                    .iter()
+                    .map(|axis| axis.tag())
+                    .zip(location.coords().iter().copied())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Names of a named instance, following the usual RIBBI grouping.
+struct InstanceNames {
+    family: String,
+    subfamily: String,
+    postscript: String,
+}
+
+impl InstanceNames {
+    fn new(font: &FontRef, subfamily_id: NameId, postscript_id: Option<NameId>) -> Result<Self> {
+        let family = name::english_name(font, NameId::TYPOGRAPHIC_FAMILY_NAME)
+            .or_else(|| name::english_name(font, NameId::FAMILY_NAME))
+            .context("Missing family name")?;
+        let subfamily =
+            name::english_name(font, subfamily_id).context("Missing instance subfamily name")?;
+        let postscript = postscript_id
+            .and_then(|id| name::english_name(font, id))
+            .unwrap_or_else(|| format!("{family}-{subfamily}").replace(' ', ""));
+
+        Ok(Self {
+            family,
+            subfamily,
+            postscript,
+        })
+    }
+
+    fn is_italic(&self) -> bool {
+        self.subfamily
+            .split_whitespace()
+            .any(|word| word == "Italic")
+    }
+
+    fn is_bold(&self) -> bool {
+        matches!(self.subfamily.as_str(), "Bold" | "Bold Italic")
+    }
+
+    /// Legacy (name IDs 1 and 2) family and subfamily, limited to Regular/Bold/Italic/Bold Italic.
+    fn legacy(&self) -> (String, String) {
+        if matches!(
+            self.subfamily.as_str(),
+            "Regular" | "Bold" | "Italic" | "Bold Italic"
+        ) {
+            return (self.family.clone(), self.subfamily.clone());
+        }
+
+        let style = self
+            .subfamily
+            .split_whitespace()
+            .filter(|&word| word != "Italic")
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let subfamily = if self.is_italic() {
+            "Italic"
+        } else {
+            "Regular"
+        };
+
+        (format!("{} {style}", self.family), subfamily.to_string())
+    }
+}
+
+fn instantiate(
+    font: &FontRef,
+    location: LocationRef,
+    user_coords: &[(Tag, f32)],
+    names: &InstanceNames,
+    tolerance: f64,
+    strict: bool,
+) -> Result<Vec<u8>> {
+    let font_file_data = font.table_directory.offset_data();
+    let num_glyphs = font.maxp()?.num_glyphs() as u32;
+    let outlines = font.outline_glyphs();
+    let glyph_metrics = font.glyph_metrics(Size::unscaled(), location);
+
+    let cff = match font.data_for_tag(Cff2::TAG) {
+        Some(data) => {
+            Some(CffTable::parse(data.as_bytes(), true).context("Failed to parse CFF2 table")?)
+        }
+        None => None,
+    };
+    let mut charstrings = Vec::new();
+    let mut glyf_builder = GlyfBuilder::new();
+    let mut h_metrics = Vec::with_capacity(num_glyphs as usize);
+    let empty_glyphs = EmptyGlyphs::new(strict);
+
+    for gid in (0..num_glyphs).map(GlyphId::new) {
+        let mut path = BezPath::new();
+
+        if let Some(glyph) = outlines.get(fontcull_skrifa::GlyphId::new(gid.to_u32())) {
+            let mut pen = PathPen::new();
+
+            match glyph.draw((Size::unscaled(), location), &mut pen) {
+                Ok(_) => path = pen.path,
+                Err(err) => {
+                    let codepoint = mapped_codepoint(font, gid);
+                    empty_glyphs.empty(gid, codepoint, EmptyCause::Draw, err)?;
+                }
+            }
+        }
+        let advance = glyph_metrics.advance_width(gid).unwrap_or_default().round();
+        let lsb = if path.elements().is_empty() {
+            0.0
+        } else {
+            path.control_box().x0.round()
+        };
+
+        h_metrics.push(LongMetric::new(advance as u16, lsb as i16));
+
+        if cff.is_some() {
+            // The converted table keeps the default nominalWidthX of 0
+            let charstring = encode_charstring(&path, Some(advance as f64), false)
+                .with_context(|| format!("Failed to encode glyph {gid}"))?;
+            charstrings.push(charstring);
+
+            continue;
+        }
+
+        let path = if outline::has_cubics(&path) {
+            outline::cubic_to_quadratic(&path, tolerance)
+        } else {
+            path
+        };
+
+        let glyph = match SimpleGlyph::from_bezpath(&path) {
+            Ok(glyph) => Glyph::Simple(glyph),
+            Err(err) => {
+                let codepoint = mapped_codepoint(font, gid);
+                empty_glyphs.empty(gid, codepoint, EmptyCause::Encode, format!("{err:?}"))?;
+
+                Glyph::Empty
+            }
+        };
+
+        glyf_builder.add_glyph(&glyph)?;
+    }
+
+    empty_glyphs.report();
+
+    let mut font_builder = FontBuilder::new();
+    let mut loca_fmt = None;
+
+    if let Some(cff) = &cff {
+        font_builder.add_raw(
+            Cff::TAG,
+            cff.to_cid_keyed(&names.postscript, &charstrings)
+                .context("Failed to convert CFF2 table")?,
+        );
+    } else {
+        let built = glyf_builder.build();
+
+        font_builder
+            .add_raw(Glyf::TAG, built.glyf)
+            .add_table(&built.loca)
+            .context("Failed to add loca table")?;
+
+        loca_fmt = Some(built.loca_format);
+    }
+
+    let advance_width_max = h_metrics
+        .iter()
+        .map(|m| m.advance)
+        .max()
+        .unwrap_or_default();
+
+    font_builder
+        .add_table(&Hmtx::new(h_metrics, Vec::new()))
+        .context("Failed to add hmtx table")?;
+
+    let mut hhea: Hhea = font.hhea()?.to_owned_table();
+    hhea.number_of_h_metrics = num_glyphs as u16;
+    hhea.advance_width_max = UfWord::new(advance_width_max);
+
+    font_builder
+        .add_table(&hhea)
+        .context("Failed to add hhea table")?;
+
+    let mut head: Head = font.head()?.to_owned_obj(font_file_data);
+
+    if let Some(loca_fmt) = loca_fmt {
+        head.index_to_loc_format = loca_fmt as i16;
+    }
+
+    head.mac_style.remove(MacStyle::BOLD | MacStyle::ITALIC);
+
+    if names.is_bold() {
+        head.mac_style.insert(MacStyle::BOLD);
+    }
+
+    if names.is_italic() {
+        head.mac_style.insert(MacStyle::ITALIC);
+    }
+
+    head.checksum_adjustment = 0;
+
+    font_builder
+        .add_table(&head)
+        .context("Failed to add head table")?;
+
+    if let Ok(os2) = font.os2() {
+        let mut os2: Os2 = os2.to_owned_table();
+
+        for &(tag, value) in user_coords {
+            if tag == Tag::new(b"wght") {
+                os2.us_weight_class = value.round().clamp(1.0, 1000.0) as u16;
+            } else if tag == Tag::new(b"wdth") {
+                os2.us_width_class = WIDTH_CLASSES
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| (*a - value).abs().total_cmp(&(*b - value).abs()))
+                    .map(|(idx, _)| idx as u16 + 1)
+                    .unwrap_or(5);
+            }
+        }
+
+        os2.fs_selection
+            .remove(SelectionFlags::BOLD | SelectionFlags::ITALIC | SelectionFlags::REGULAR);
+
+        if names.is_bold() {
+            os2.fs_selection.insert(SelectionFlags::BOLD);
+        }
+
+        if names.is_italic() {
+            os2.fs_selection.insert(SelectionFlags::ITALIC);
+        }
+
+        if !names.is_bold() && !names.is_italic() {
+            os2.fs_selection.insert(SelectionFlags::REGULAR);
+        }
+
+        font_builder
+            .add_table(&os2)
+            .context("Failed to add OS/2 table")?;
+    }
+
+    if let Ok(name_table) = font.name() {
+        let mut name_table: Name = name_table.to_owned_table();
+        let (legacy_family, legacy_subfamily) = names.legacy();
+
+        name::set_name(&mut name_table, NameId::FAMILY_NAME, &legacy_family);
+        name::set_name(&mut name_table, NameId::SUBFAMILY_NAME, &legacy_subfamily);
+        name::set_name(&mut name_table, NameId::UNIQUE_ID, &names.postscript);
+        name::set_name(
+            &mut name_table,
+            NameId::FULL_NAME,
+            &format!("{} {}", names.family, names.subfamily),
+        );
+        name::set_name(&mut name_table, NameId::POSTSCRIPT_NAME, &names.postscript);
+        name::set_name(
+            &mut name_table,
+            NameId::TYPOGRAPHIC_FAMILY_NAME,
+            &names.family,
+        );
+        name::set_name(
+            &mut name_table,
+            NameId::TYPOGRAPHIC_SUBFAMILY_NAME,
+            &names.subfamily,
+        );
+
+        font_builder
+            .add_table(&name_table)
+            .context("Failed to add name table")?;
+    }
+
+    // Without variation tables nothing refers to the variation store any more, and
+    // VariationIndex device tables resolve to no adjustment
+    if let Ok(gdef) = font.gdef() {
+        let mut gdef: Gdef = gdef.to_owned_table();
+        gdef.item_var_store = None.into();
+
+        font_builder
+            .add_table(&gdef)
+            .context("Failed to add GDEF table")?;
+    }
+
+    for record in font.table_directory.table_records() {
+        let tag = record.tag();
+
+        if font_builder.contains(tag) || tag == Cff2::TAG || VARIATION_TABLES.contains(&tag) {
+            continue;
+        }
+
+        if let Some(data) = font.data_for_tag(tag) {
+            font_builder.add_raw(tag, data.as_bytes().to_vec());
+        }
+    }
+
+    let mut data = font_builder.build();
+
+    // `FontBuilder` always writes the TrueType sfnt version
+    if cff.is_some() {
+        data[0..4].copy_from_slice(&CFF_SFNT_VERSION.to_be_bytes());
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_write_fonts::tables::{
+        gvar::{GlyphDelta, GlyphDeltas, GlyphVariations, Gvar, Tent},
+        maxp::Maxp,
+    };
+
+    use super::*;
+
+    const WEIGHT_NAME: NameId = NameId::new(256);
+    const BOLD_NAME: NameId = NameId::new(257);
+
+    /// An `fvar` table with a wght axis from 400 to 700 and Regular and Bold instances.
+    fn fvar() -> Vec<u8> {
+        let fixed = |value: i32| (value << 16).to_be_bytes();
+
+        let mut fvar = vec![0, 1, 0, 0, 0, 16, 0, 2, 0, 1, 0, 20, 0, 2, 0, 8];
+        fvar.extend_from_slice(b"wght");
+
+        for value in [400, 400, 700] {
+            fvar.extend_from_slice(&fixed(value));
+        }
+
+        fvar.extend_from_slice(&[0, 0]);
+        fvar.extend_from_slice(&WEIGHT_NAME.to_u16().to_be_bytes());
+
+        for (name_id, value) in [(NameId::SUBFAMILY_NAME, 400), (BOLD_NAME, 700)] {
+            fvar.extend_from_slice(&name_id.to_u16().to_be_bytes());
+            fvar.extend_from_slice(&[0, 0]);
+            fvar.extend_from_slice(&fixed(value));
+        }
+
+        fvar
+    }
+
+    /// A variable font whose only outline is a 100 unit square that grows 50 units wider
+    /// towards the Bold instance.
+    fn test_font() -> Vec<u8> {
+        let mut square = BezPath::new();
+        square.move_to((0.0, 0.0));
+        square.line_to((100.0, 0.0));
+        square.line_to((100.0, 100.0));
+        square.line_to((0.0, 100.0));
+        square.close_path();
+
+        let mut glyf_builder = GlyfBuilder::new();
+        glyf_builder.add_glyph(&Glyph::Empty).unwrap();
+        glyf_builder
+            .add_glyph(&Glyph::Simple(SimpleGlyph::from_bezpath(&square).unwrap()))
+            .unwrap();
+        let built = glyf_builder.build();
+
+        // The two right points move, the four phantom points stay
+        let deltas = [0, 50, 50, 0, 0, 0, 0, 0]
+            .map(|x| GlyphDelta::required(x, 0))
+            .to_vec();
+        let gvar = Gvar::new(
+            vec![
+                GlyphVariations::new(GlyphId::new(0), Vec::new()),
+                GlyphVariations::new(
+                    GlyphId::new(1),
+                    vec![GlyphDeltas::new(
+                        vec![Tent::new(F2Dot14::ONE, None)],
+                        deltas,
+                    )],
+                ),
+            ],
+            1,
+        )
+        .unwrap();
+
+        let mut name_table = Name::new(Vec::new());
+
+        for (name_id, value) in [
+            (NameId::FAMILY_NAME, "Test"),
+            (NameId::SUBFAMILY_NAME, "Regular"),
+            (WEIGHT_NAME, "Weight"),
+            (BOLD_NAME, "Bold"),
+        ] {
+            name::set_name(&mut name_table, name_id, value);
+        }
+
+        let head = Head {
+            units_per_em: 1000,
+            index_to_loc_format: built.loca_format as i16,
+            ..Default::default()
+        };
+        let hhea = Hhea {
+            number_of_h_metrics: 2,
+            ..Default::default()
+        };
+        let hmtx = Hmtx::new(vec![LongMetric::new(500, 0); 2], Vec::new());
+
+        let mut builder = FontBuilder::new();
+        builder
+            .add_raw(Glyf::TAG, built.glyf)
+            .add_raw(Tag::new(b"fvar"), fvar());
+        builder.add_table(&built.loca).unwrap();
+        builder.add_table(&head).unwrap();
+        builder.add_table(&hhea).unwrap();
+        builder.add_table(&hmtx).unwrap();
+        builder.add_table(&Maxp::new(2)).unwrap();
+        builder.add_table(&name_table).unwrap();
+        builder.add_table(&gvar).unwrap();
+
+        builder.build()
+    }
+
+    #[test]
+    fn named_instances_round_trip() {
+        let data = test_font();
+        let font = FontRef::new(&data).unwrap();
+
+        assert!(has_named_instances(&font));
+
+        let instances = named_instances(&font, 1.0, true).unwrap();
+
+        assert_eq!(
+            instances
+                .iter()
+                .map(|instance| instance.postscript_name.as_str())
+                .collect::<Vec<&str>>(),
+            ["Test-Regular", "Test-Bold"]
+        );
+        assert_eq!(instances[1].location, [(Tag::new(b"wght"), F2Dot14::ONE)]);
+
+        for (instance, (subfamily, x_max)) in
+            instances.iter().zip([("Regular", 100), ("Bold", 150)])
+        {
+            let font = FontRef::new(&instance.data).unwrap();
+
+            assert!(!has_named_instances(&font));
+
+            for tag in VARIATION_TABLES {
+                assert!(font.data_for_tag(tag).is_none(), "{tag}");
+            }
+
+            let glyph = font
+                .loca(None)
+                .unwrap()
+                .get_glyf(GlyphId::new(1), &font.glyf().unwrap())
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(glyph.x_max(), x_max);
+            assert_eq!(font.hmtx().unwrap().advance(GlyphId::new(1)), Some(500));
+            assert_eq!(
+                name::english_name(&font, NameId::SUBFAMILY_NAME).as_deref(),
+                Some(subfamily)
+            );
+        }
+    }
+}
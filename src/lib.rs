@@ -1,52 +1,263 @@
+pub mod advance;
+pub mod build_info;
+pub mod cff;
+pub mod checksum;
+pub mod container;
+pub mod glyf;
+pub mod gvar;
+pub mod instance;
+pub mod license;
+pub mod metrics;
+pub mod name;
+pub mod naming;
+pub mod outline;
 pub mod pen;
 pub mod renderer;
 pub mod ttc;
+pub mod validate;
+#[cfg(feature = "woff")]
+pub mod woff;
 
-use anyhow::{Context, Result};
+#[cfg(test)]
+mod test_fonts;
+
+use std::{
+    fmt,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use fontcull_font_types::NameId;
 use fontcull_klippa::{Plan, SubsetFlags, subset_font};
 use fontcull_read_fonts::{
     FileRef, FontRef, TableProvider, TopLevelTable,
     collections::IntSet,
-    types::{GlyphId, Tag},
+    tables::{cff::Cff, cff2::Cff2, gvar::Gvar, loca::Loca},
+    types::{CFF_SFNT_VERSION, F2Dot14, GlyphId, Tag},
+};
+use fontcull_skrifa::{
+    MetadataProvider,
+    instance::{LocationRef, Size},
 };
-use fontcull_skrifa::MetadataProvider;
 use fontcull_write_fonts::{
     FontBuilder,
-    from_obj::ToOwnedObj,
+    from_obj::{ToOwnedObj, ToOwnedTable},
     tables::{
-        glyf::{Glyf, GlyfLocaBuilder, Glyph, SimpleGlyph},
+        glyf::{Glyf, Glyph, SimpleGlyph},
         head::Head,
-        loca::Loca,
+        maxp::Maxp,
+        name::Name,
+        os2::Os2,
     },
 };
 use indicatif::ProgressStyle;
-use kurbo::BezPath;
-use rayon::iter::{ParallelBridge, ParallelIterator};
-use rustc_hash::FxHashMap;
-use tracing::{info, info_span};
+use kurbo::{BezPath, Shape};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use tracing::{debug, info, info_span, warn};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
-use crate::{pen::PathPen, renderer::RubyRenderer};
+use crate::{
+    advance::{AdvanceFit, AdvancePolicy},
+    build_info::BuildInfo,
+    cff::CffTable,
+    glyf::GlyfBuilder,
+    license::Credits,
+    metrics::{GlyphBounds, VerticalMetrics},
+    name::Rename,
+    outline,
+    pen::PathPen,
+    renderer::{BaselineTarget, RubyRenderer},
+};
+
+/// A glyph outline with its annotation, as drawn at one location.
+struct DrawnGlyph {
+    path: BezPath,
+    /// Index of the first path element belonging to the ruby
+    ruby_start: usize,
+    has_content: bool,
+    advance: f64,
+}
+
+/// Why a glyph was left empty.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EmptyCause {
+    /// Its outline could not be drawn
+    Draw,
+    /// Its outline could not be encoded as a TrueType glyph
+    Encode,
+}
+
+impl fmt::Display for EmptyCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EmptyCause::Draw => "failed to draw",
+            EmptyCause::Encode => "could not be encoded as a TrueType glyph",
+        })
+    }
+}
+
+/// The first character `font` maps to each glyph.
+fn codepoints_by_glyph(font: &FontRef) -> FxHashMap<GlyphId, u32> {
+    let mut codepoints = FxHashMap::default();
+
+    for (codepoint, gid) in font.charmap().mappings() {
+        codepoints.entry(gid).or_insert(codepoint);
+    }
+
+    codepoints
+}
+
+/// Glyphs left empty because they could not be drawn or encoded, or the first such
+/// failure in strict mode.
+struct EmptyGlyphs {
+    strict: bool,
+    /// By glyph, so glyphs drawn at several locations count once
+    emptied: Mutex<FxHashMap<GlyphId, EmptyCause>>,
+}
+
+impl EmptyGlyphs {
+    fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            emptied: Mutex::default(),
+        }
+    }
+
+    /// Leave `gid` (mapped from `codepoint`) empty, or fail in strict mode.
+    fn empty(
+        &self,
+        gid: GlyphId,
+        codepoint: Option<u32>,
+        cause: EmptyCause,
+        err: impl fmt::Display,
+    ) -> Result<()> {
+        let glyph = match codepoint {
+            Some(codepoint) => format!("Glyph {gid} (U+{codepoint:04X})"),
+            None => format!("Glyph {gid} (unmapped)"),
+        };
+
+        if self.strict {
+            bail!("{glyph} {cause}: {err}");
+        }
+
+        debug!("{glyph} {cause} and was left empty: {err}");
+        self.emptied.lock().unwrap().insert(gid, cause);
+
+        Ok(())
+    }
+
+    /// Summarize the glyphs left empty in a warning.
+    fn report(self) {
+        let emptied = self.emptied.into_inner().unwrap();
+
+        if emptied.is_empty() {
+            return;
+        }
+
+        let reasons = [EmptyCause::Draw, EmptyCause::Encode]
+            .into_iter()
+            .filter_map(|cause| {
+                let count = emptied.values().filter(|&&c| c == cause).count();
+
+                (count > 0).then(|| format!("{count} {cause}"))
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        warn!(
+            "Left {} glyphs empty ({reasons}); use --strict to fail instead",
+            emptied.len()
+        );
+    }
+}
+
+/// A glyph record for the rebuilt `glyf` table.
+enum GlyphRecord<'a> {
+    /// The original record, copied as-is
+    Raw(&'a [u8]),
+    Drawn {
+        glyph: Glyph,
+        /// Serialized `gvar` data, for variable fonts
+        variations: Option<Vec<u8>>,
+        /// The widened advance, if the ruby did not fit
+        advance: Option<f64>,
+    },
+}
 
 pub struct ProcessedFont {
     pub data: Vec<u8>,
     pub file_name: Option<String>,
+    /// Index of the face in the input collection this font was built from, when it was
+    /// split out of a collection
+    pub face_index: Option<usize>,
+}
+
+/// Options controlling how fonts are rebuilt.
+pub struct ProcessOptions {
+    /// Subset the font to include only annotation characters
+    pub subset: bool,
+    /// Split font collections into separate fonts instead of rebuilding a TTC
+    pub split: bool,
+    /// Maximum error (in font units) when converting cubic outlines to quadratic
+    pub tolerance: f64,
+    /// Instantiate variable fonts at each named instance, writing a static font for each
+    pub instances: bool,
+    /// How vertical line metrics respond to ruby extending past them
+    pub vertical_metrics: VerticalMetrics,
+    /// How advances respond to ruby overhanging the base glyph
+    pub advance: AdvancePolicy,
+    /// Fail on glyphs that cannot be drawn or encoded instead of leaving them empty
+    pub strict: bool,
+    /// How output fonts are renamed
+    pub rename: Rename,
+    /// Process fonts even if their license restrictions forbid it
+    pub ignore_license: bool,
+    /// Copyright and license of a separate ruby font, credited in every output
+    pub ruby_credits: Option<Credits>,
+    /// Settings recorded in each output's build info table, or `None` to record nothing
+    pub build_settings: Option<Vec<(String, String)>>,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            subset: false,
+            split: false,
+            tolerance: 1.0,
+            instances: false,
+            vertical_metrics: VerticalMetrics::default(),
+            advance: AdvancePolicy::default(),
+            strict: false,
+            rename: Rename::default(),
+            ignore_license: false,
+            ruby_credits: None,
+            build_settings: None,
+        }
+    }
 }
 
 pub fn process_font_file(
     file: FileRef,
-    renderer: &Box<dyn RubyRenderer>,
-    subset: bool,
-    split: bool,
+    renderer: &dyn RubyRenderer,
+    options: &ProcessOptions,
 ) -> Result<Vec<ProcessedFont>> {
     match file {
         FileRef::Font(font) => {
-            let data = process_font_ref(&font, &renderer)?;
-            let data = if subset {
+            if options.instances && instance::has_named_instances(&font) {
+                return process_instances(&font, None, renderer, options);
+            }
+
+            let data = process_font_ref(&font, renderer, options)?;
+            let data = if options.subset {
                 info!("Subsetting font");
 
-                subset_by_renderers(&data, &renderer)?
+                subset_by_renderers(&data, renderer)?
             } else {
                 data
             };
@@ -54,10 +265,11 @@ pub fn process_font_file(
             Ok(vec![ProcessedFont {
                 data,
                 file_name: None,
+                face_index: None,
             }])
         }
         FileRef::Collection(collection) => {
-            if split {
+            if options.split {
                 // Split mode: write each font as a separate TTF file
                 let collection_span = info_span!("split_fonts_in_collection");
                 collection_span.pb_set_style(
@@ -69,46 +281,53 @@ pub fn process_font_file(
 
                 let split_span_enter = collection_span.enter();
 
-                let fonts = collection
-                    .iter()
+                // Faces are processed in parallel, then returned in collection order
+                let faces = collection.iter().collect::<Vec<_>>();
+
+                let fonts = faces
+                    .into_par_iter()
                     .enumerate()
                     .map(|(idx, font)| {
                         collection_span.pb_inc(1);
 
                         let font = font.context("Failed to read font")?;
-                        let mut data = process_font_ref(&font, &renderer)?;
 
-                        if subset {
+                        if options.instances && instance::has_named_instances(&font) {
+                            return process_instances(&font, Some(idx), renderer, options);
+                        }
+
+                        let mut data = process_font_ref(&font, renderer, options)?;
+
+                        if options.subset {
                             collection_span.pb_set_message("Subsetting font");
-                            data = subset_by_renderers(&data, &renderer)?;
+                            data = subset_by_renderers(&data, renderer)?;
                         }
 
-                        // Generate output filename
-                        let file_name = if let Ok(name_table) = font.name() {
-                            // Try to get family name from name table
-                            name_table
-                                .name_record()
-                                .iter()
-                                .find(|n| n.name_id() == NameId::POSTSCRIPT_NAME)
-                                .and_then(|rec| rec.string(name_table.string_data()).ok())
-                                .map(|name| format!("{name}.ttf"))
-                                .unwrap_or_else(|| format!("font-{idx}.ttf"))
-                        } else {
-                            format!("font-{idx}.ttf")
-                        };
-
-                        Ok(ProcessedFont {
+                        let file_name = output_file_name(&data, &format!("font-{idx}"))?;
+
+                        Ok(vec![ProcessedFont {
                             data,
                             file_name: Some(file_name),
-                        })
+                            face_index: Some(idx),
+                        }])
                     })
-                    .collect::<Result<Vec<ProcessedFont>>>();
+                    .collect::<Result<Vec<Vec<ProcessedFont>>>>()
+                    .map(|fonts| fonts.into_iter().flatten().collect());
 
                 drop(split_span_enter);
                 drop(collection_span);
 
                 fonts
             } else {
+                ensure!(
+                    !options.instances
+                        || !collection
+                            .iter()
+                            .flatten()
+                            .any(|font| instance::has_named_instances(&font)),
+                    "Instancing variable fonts in a collection requires --split"
+                );
+
                 let collection_span = info_span!("process_fonts_in_collection");
                 collection_span.pb_set_style(
                     &ProgressStyle::with_template("{msg} [{wide_bar:.green/cyan}] {pos}/{len}")
@@ -119,30 +338,36 @@ pub fn process_font_file(
 
                 let process_span_enter = collection_span.enter();
 
-                let fonts = collection
-                    .iter()
-                    .par_bridge()
+                // Collected first, since bridged iterators do not keep the face order
+                let faces = collection.iter().collect::<Vec<_>>();
+
+                let fonts = faces
+                    .into_par_iter()
                     .map(|font| {
                         collection_span.pb_inc(1);
                         collection_span.pb_set_message("Processing font");
 
                         let font = font.context("Failed to read font")?;
 
-                        let mut data = process_font_ref(&font, &renderer)?;
+                        let mut data = process_font_ref(&font, renderer, options)?;
 
-                        if subset {
+                        if options.subset {
                             collection_span.pb_set_message("Subsetting font");
-                            data = subset_by_renderers(&data, &renderer)?;
+                            data = subset_by_renderers(&data, renderer)?;
                         }
 
-                        let data = Box::leak(data.into_boxed_slice());
-
-                        FontRef::new(data).context("Failed to create font ref")
+                        Ok(data)
                     })
-                    .collect::<Result<Vec<FontRef>>>()?;
+                    .collect::<Result<Vec<Vec<u8>>>>()?;
 
                 drop(process_span_enter);
 
+                // Processed faces stay owned here for as long as the collection borrows them
+                let fonts = fonts
+                    .iter()
+                    .map(|data| FontRef::new(data).context("Failed to create font ref"))
+                    .collect::<Result<Vec<FontRef>>>()?;
+
                 info_span!("Building TTC");
 
                 let data = ttc::build_collection(&fonts).context("Failed to build TTC")?;
@@ -150,13 +375,83 @@ pub fn process_font_file(
                 Ok(vec![ProcessedFont {
                     data,
                     file_name: None,
+                    face_index: None,
                 }])
             }
         }
     }
 }
 
-pub fn process_font_ref(font: &FontRef, renderer: &Box<dyn RubyRenderer>) -> Result<Vec<u8>> {
+/// Instantiate a variable font at each named instance and process every instance as
+/// its own static font.
+fn process_instances(
+    font: &FontRef,
+    face_index: Option<usize>,
+    renderer: &dyn RubyRenderer,
+    options: &ProcessOptions,
+) -> Result<Vec<ProcessedFont>> {
+    let instances = instance::named_instances(font, options.tolerance, options.strict)?;
+
+    info!("Instantiating {} named instances", instances.len());
+
+    instances
+        .into_iter()
+        .map(|instance| {
+            let font = FontRef::new(&instance.data).context("Failed to parse instance")?;
+            let mut data = process_font_at(&font, renderer, options, &instance.location)?;
+
+            if options.subset {
+                data = subset_by_renderers(&data, renderer)?;
+            }
+
+            let file_name = output_file_name(&data, &instance.postscript_name)?;
+
+            Ok(ProcessedFont {
+                data,
+                file_name: Some(file_name),
+                face_index,
+            })
+        })
+        .collect()
+}
+
+/// Fonts with PostScript outlines are written as OTF, everything else as TTF.
+pub fn font_extension(font: &FontRef) -> &'static str {
+    if font.data_for_tag(Cff::TAG).is_some() || font.data_for_tag(Cff2::TAG).is_some() {
+        "otf"
+    } else {
+        "ttf"
+    }
+}
+
+/// File name for the processed font `data`, after the (possibly renamed) PostScript name
+/// it was written with, or `fallback`.
+fn output_file_name(data: &[u8], fallback: &str) -> Result<String> {
+    let font = FontRef::new(data).context("Failed to parse processed font")?;
+    let name =
+        name::english_name(&font, NameId::POSTSCRIPT_NAME).unwrap_or_else(|| fallback.to_string());
+    let name = naming::sanitize(&name);
+
+    Ok(format!("{name}.{}", font_extension(&font)))
+}
+
+pub fn process_font_ref(
+    font: &FontRef,
+    renderer: &dyn RubyRenderer,
+    options: &ProcessOptions,
+) -> Result<Vec<u8>> {
+    process_font_at(font, renderer, options, &[])
+}
+
+/// Process `font`, drawing the ruby at `ruby_location` as well as at the font's own
+/// locations. Static instances pass the location they were instantiated at, so ruby
+/// drawn from the same variable font follows the instance.
+fn process_font_at(
+    font: &FontRef,
+    renderer: &dyn RubyRenderer,
+    options: &ProcessOptions,
+    ruby_location: &[(Tag, F2Dot14)],
+) -> Result<Vec<u8>> {
     let font_file_data = font.table_directory.offset_data();
     let charmap = font.charmap();
     let hmtx = font.hmtx()?;
@@ -164,6 +459,59 @@ pub fn process_font_ref(font: &FontRef, renderer: &Box<dyn RubyRenderer>) -> Res
     let outlines = font.outline_glyphs();
     let upem = font.head()?.units_per_em() as f64;
 
+    let rename = match &options.rename {
+        Rename::Renderer => Rename::Suffix(renderer.name().to_string()),
+        rename => rename.clone(),
+    };
+
+    // The name table is only required when it is rewritten
+    let rewrites_names = rename != Rename::Keep || options.ruby_credits.is_some();
+    let mut name_table: Option<Name> = if rewrites_names {
+        Some(font.name()?.to_owned_table())
+    } else {
+        None
+    };
+    let renamed = match &mut name_table {
+        Some(name_table) => {
+            name::rename(font, name_table, &rename).context("Failed to rename font")?
+        }
+        None => None,
+    };
+
+    // Pre-flight: refuse to build fonts the license forbids before doing any work
+    let output_family = match &renamed {
+        Some(renamed) => renamed.family.clone(),
+        None => name::english_name(font, NameId::TYPOGRAPHIC_FAMILY_NAME)
+            .or_else(|| name::english_name(font, NameId::FAMILY_NAME))
+            .unwrap_or_default(),
+    };
+
+    let license_issues = license::check(font, &output_family, options.subset)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>();
+
+    if !license_issues.is_empty() {
+        let license_issues = license_issues.join("\n  ");
+
+        ensure!(
+            options.ignore_license,
+            "License restrictions forbid processing this font (override with --ignore-license):\n  {license_issues}"
+        );
+
+        warn!("Ignoring license restrictions:\n  {license_issues}");
+    }
+
+    if let Some(ruby_credits) = &options.ruby_credits {
+        for issue in license::incompatibilities(font, ruby_credits) {
+            warn!("{issue}");
+        }
+
+        if let Some(name_table) = &mut name_table {
+            license::credit(name_table, ruby_credits);
+        }
+    }
+
     let gid_char_map = renderer
         .ranges()
         .iter()
@@ -200,70 +548,570 @@ pub fn process_font_ref(font: &FontRef, renderer: &Box<dyn RubyRenderer>) -> Res
     glyphs_span.pb_set_style(&progress_style);
     glyphs_span.pb_set_length(glyphs.len() as u64);
 
-    if let Some(ttc_index) = font.ttc_index() {
-        glyphs_span.pb_set_message(&format!("Processing glyphs ({})", ttc_index));
-    } else {
-        glyphs_span.pb_set_message("Processing glyphs");
-    }
-
     let glyphs_span_enter = glyphs_span.enter();
 
-    let mut glyf_loca_builder = GlyfLocaBuilder::new();
+    // Glyphs past the last long metric share its advance
+    let advance_of = |gid: GlyphId| hmtx.advance(gid).unwrap_or(upem as u16) as f64;
+
+    let axis_tags = font
+        .axes()
+        .iter()
+        .map(|axis| axis.tag())
+        .collect::<Vec<Tag>>();
+
+    let location_of = |coords: &[F2Dot14]| {
+        ruby_location
+            .iter()
+            .copied()
+            .chain(axis_tags.iter().copied().zip(coords.iter().copied()))
+            .collect::<Vec<(Tag, F2Dot14)>>()
+    };
 
-    for gid in glyphs {
-        glyphs_span.pb_inc(1);
+    let empty_glyphs = EmptyGlyphs::new(options.strict);
+    // Only built once a glyph is left empty, which most fonts never need
+    let codepoints = OnceLock::new();
 
-        let mut final_path = BezPath::new();
+    // Leave a glyph empty, or fail in strict mode
+    let empty_glyph = |gid: GlyphId, cause: EmptyCause, err: String| -> Result<()> {
+        let codepoint = gid_char_map.get(&gid).map(|&ch| ch as u32).or_else(|| {
+            codepoints
+                .get_or_init(|| codepoints_by_glyph(font))
+                .get(&gid)
+                .copied()
+        });
+
+        empty_glyphs.empty(gid, codepoint, cause, err)
+    };
+
+    // Draw a glyph's own outline at the normalized `coords` (empty for the default location)
+    let draw_base = |gid: GlyphId, coords: &[F2Dot14]| -> Result<DrawnGlyph> {
+        let mut path = BezPath::new();
         let mut has_content = false;
 
+        let location = LocationRef::new(coords);
+        let advance = if coords.is_empty() {
+            advance_of(gid)
+        } else {
+            font.glyph_metrics(Size::unscaled(), location)
+                .advance_width(gid)
+                .map(|advance| advance as f64)
+                .unwrap_or_else(|| advance_of(gid))
+        };
+
         if let Some(glyph) = outlines.get(fontcull_skrifa::GlyphId::new(gid.to_u32())) {
             let mut pen = PathPen::new();
 
-            match glyph.draw(fontcull_skrifa::instance::Size::unscaled(), &mut pen) {
+            match glyph.draw((Size::unscaled(), location), &mut pen) {
                 Ok(_) => {
-                    final_path = pen.path;
+                    path = pen.path;
                     has_content = true;
                 }
-                Err(_) => {}
+                Err(err) => empty_glyph(gid, EmptyCause::Draw, err.to_string())?,
+            }
+        }
+
+        Ok(DrawnGlyph {
+            ruby_start: path.elements().len(),
+            path,
+            has_content,
+            advance,
+        })
+    };
+
+    let cff_tag = [Cff::TAG, Cff2::TAG]
+        .into_iter()
+        .find(|&tag| font.data_for_tag(tag).is_some());
+
+    // Charstrings are written without blend operators, so annotated glyphs would be static
+    ensure!(
+        cff_tag != Some(Cff2::TAG) || axis_tags.is_empty(),
+        "Variable CFF2 fonts cannot be annotated without losing their variations; use --instances to write a static font per named instance"
+    );
+
+    // Variable fonts: rebuilt glyphs get new point structures, so their gvar data is
+    // regenerated from outlines drawn at every master. Advances only ever change by the
+    // same amount at every location, so HVAR stays valid.
+    let variations = font
+        .gvar()
+        .ok()
+        .filter(|_| cff_tag.is_none() && !axis_tags.is_empty());
+    let extremes = gvar::axis_extremes(font);
+    let original = font.glyf().ok().zip(font.loca(None).ok());
+
+    // A glyph followed by its components, whose regions it is drawn at
+    let region_glyphs = |gid: GlyphId| {
+        let mut glyphs = vec![gid];
+
+        if let Some((src_glyf, src_loca)) = &original {
+            glyphs.extend(glyf::components(src_glyf, src_loca, gid));
+        }
+
+        glyphs
+    };
+
+    // Every location annotated glyphs are drawn at, if the renderer needs a shared baseline
+    let mut locations = FxHashSet::default();
+
+    if renderer.needs_baseline() {
+        locations.insert(Vec::new());
+
+        if let Some(gvar) = &variations {
+            for &gid in gid_char_map.keys() {
+                locations.extend(
+                    gvar::glyph_regions(gvar, &region_glyphs(gid), &extremes)
+                        .iter()
+                        .map(|region| region.peak()),
+                );
             }
         }
+    }
+
+    // First pass: measure every annotation, so they all share one baseline per location.
+    // Targets are merged with max/min, so the result does not depend on drawing order.
+    glyphs_span.pb_set_message("Measuring annotations");
+
+    let baselines = locations
+        .into_iter()
+        .map(|coords| {
+            let location = location_of(&coords);
+            let target = gid_char_map
+                .par_iter()
+                .map(|(&gid, &ch)| {
+                    let base = draw_base(gid, &coords)?;
+
+                    renderer
+                        .measure(ch, &base.path, upem, &location)
+                        .context("Failed to measure annotation")
+                })
+                .try_reduce(
+                    || None,
+                    |a, b| match (a, b) {
+                        (Some(a), Some(b)) => Ok(Some(a.merge(b))),
+                        (a, b) => Ok(a.or(b)),
+                    },
+                )?;
+
+            Ok((coords, target))
+        })
+        .collect::<Result<FxHashMap<Vec<F2Dot14>, Option<BaselineTarget>>>>()?;
+
+    if let Some(ttc_index) = font.ttc_index() {
+        glyphs_span.pb_set_message(&format!("Processing glyphs ({})", ttc_index));
+    } else {
+        glyphs_span.pb_set_message("Processing glyphs");
+    }
+
+    // Second pass: draw a glyph at `coords` and add its annotation, if any, on the shared
+    // baseline for that location
+    let draw_glyph = |gid: GlyphId, coords: &[F2Dot14]| -> Result<DrawnGlyph> {
+        let mut drawn = draw_base(gid, coords)?;
 
         if let Some(&ch) = gid_char_map.get(&gid) {
-            let orig_advance = hmtx
-                .h_metrics()
-                .get(gid.to_u32() as usize)
-                .map(|m| m.advance.get())
-                .unwrap_or(upem as u16) as f64;
+            let baseline = baselines.get(coords).copied().flatten();
 
             renderer
-                .annotate(ch, &mut final_path, orig_advance, upem)
+                .annotate(
+                    ch,
+                    &mut drawn.path,
+                    drawn.advance,
+                    upem,
+                    &location_of(coords),
+                    baseline,
+                )
                 .context("Failed to annotate")?;
         }
 
-        let write_glyph = if !has_content && final_path.elements().is_empty() {
-            Glyph::Empty
-        } else {
-            match SimpleGlyph::from_bezpath(&final_path) {
-                Ok(s) => Glyph::Simple(s),
-                Err(_) => Glyph::Empty,
+        Ok(drawn)
+    };
+
+    let monospace = advance::is_monospace(font);
+    // Glyphs that picked up an annotation, for the build info
+    let annotated = Mutex::new(FxHashSet::<GlyphId>::default());
+    let widened = AtomicUsize::new(0);
+    let compressed = AtomicUsize::new(0);
+    let refused = AtomicUsize::new(0);
+
+    // Draw a glyph at the default location, widening its advance if the ruby overhangs.
+    // The same fit is applied at every other location, so advance deltas stay unchanged.
+    let draw_fitted = |gid: GlyphId| -> Result<(DrawnGlyph, Option<AdvanceFit>)> {
+        let mut drawn = draw_glyph(gid, &[])?;
+
+        if drawn.path.elements().len() > drawn.ruby_start {
+            annotated.lock().unwrap().insert(gid);
+        }
+
+        let AdvancePolicy::Expand { max_scale } = options.advance else {
+            return Ok((drawn, None));
+        };
+
+        let Some(fit) = advance::ruby_bounds(&drawn.path, drawn.ruby_start)
+            .and_then(|ruby| advance::fit(ruby, drawn.advance, max_scale))
+        else {
+            return Ok((drawn, None));
+        };
+
+        if monospace {
+            refused.fetch_add(1, Ordering::Relaxed);
+
+            return Ok((drawn, None));
+        }
+
+        widened.fetch_add(1, Ordering::Relaxed);
+
+        if fit.is_compressed() {
+            compressed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fit.apply(&mut drawn.path, drawn.ruby_start);
+        drawn.advance += fit.extra;
+
+        Ok((drawn, Some(fit)))
+    };
+
+    let mut font_builder = FontBuilder::new();
+    let mut loca_fmt = None;
+    let mut glyf_maxima = None;
+    let mut bounds = Vec::with_capacity(glyphs.len());
+    let mut advances = FxHashMap::<GlyphId, u16>::default();
+
+    if let Some(cff_tag) = cff_tag {
+        let cff_data = font.data_for_tag(cff_tag).context("Missing CFF table")?;
+        let mut cff = CffTable::parse(cff_data.as_bytes(), cff_tag == Cff2::TAG)
+            .context("Failed to parse CFF table")?;
+
+        let path_bounds = |path: &BezPath| {
+            (!path.elements().is_empty()).then(|| GlyphBounds::from_rect(path.bounding_box()))
+        };
+
+        // Glyphs are drawn and encoded in parallel, then collected in glyph order
+        let drawn = glyphs
+            .par_iter()
+            .map(|&gid| {
+                // Unannotated charstrings are kept as-is, along with their hints and subroutine
+                // calls. Their outline is only measured, so one that fails to draw is not left
+                // empty and keeps its original side bearing.
+                if !gid_char_map.contains_key(&gid) {
+                    let glyph_bounds = outlines
+                        .get(fontcull_skrifa::GlyphId::new(gid.to_u32()))
+                        .and_then(|glyph| {
+                            let mut pen = PathPen::new();
+
+                            glyph
+                                .draw((Size::unscaled(), LocationRef::default()), &mut pen)
+                                .ok()
+                                .and_then(|_| path_bounds(&pen.path))
+                        });
+
+                    glyphs_span.pb_inc(1);
+
+                    return Ok((gid, glyph_bounds, None));
+                }
+
+                let (drawn, fit) = draw_fitted(gid)?;
+                let charstring = cff
+                    .encode_glyph(gid.to_u32(), &drawn.path, drawn.advance)
+                    .with_context(|| format!("Failed to encode glyph {gid}"))?;
+
+                glyphs_span.pb_inc(1);
+
+                Ok((
+                    gid,
+                    path_bounds(&drawn.path),
+                    Some((fit.map(|_| drawn.advance), charstring)),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (gid, glyph_bounds, charstring) in drawn {
+            bounds.push(glyph_bounds);
+
+            let Some((advance, charstring)) = charstring else {
+                continue;
+            };
+
+            if let Some(advance) = advance {
+                advances.insert(gid, advance.round() as u16);
+            }
+
+            cff.set_charstring(gid.to_u32(), charstring)?;
+        }
+
+        if let Some(renamed) = &renamed {
+            cff.set_font_name(&renamed.postscript);
+        }
+
+        font_builder.add_raw(cff_tag, cff.build().context("Failed to build CFF table")?);
+    } else {
+        // Composites built from annotated glyphs, which would otherwise pick up their ruby
+        let uses_annotated = match &original {
+            Some((src_glyf, src_loca)) => {
+                glyf::glyphs_with_component(src_glyf, src_loca, maxp.num_glyphs() as u32, |gid| {
+                    gid_char_map.contains_key(&gid)
+                })
+            }
+            None => FxHashSet::default(),
+        };
+        let mut glyf_builder = GlyfBuilder::new();
+
+        let mut glyph_variations = FxHashMap::<GlyphId, Vec<u8>>::default();
+
+        // Draw the other masters of a glyph drawn at the default location as `default`
+        let draw_variable_glyph = |gvar: &Gvar,
+                                   gid: GlyphId,
+                                   default: &DrawnGlyph,
+                                   fit: Option<&AdvanceFit>|
+         -> Result<(Glyph, Vec<u8>, Option<f64>)> {
+            let regions = gvar::glyph_regions(gvar, &region_glyphs(gid), &extremes);
+            let advance = fit.map(|_| default.advance);
+
+            let mut paths = vec![default.path.clone()];
+            let mut master_advances = vec![default.advance];
+
+            for region in &regions {
+                let mut drawn = draw_glyph(gid, &region.peak())?;
+
+                if let Some(fit) = fit {
+                    fit.apply(&mut drawn.path, drawn.ruby_start);
+                    drawn.advance += fit.extra;
+                }
+
+                paths.push(drawn.path);
+                master_advances.push(drawn.advance);
+            }
+
+            if paths.iter().all(|path| path.elements().is_empty()) {
+                return Ok((Glyph::Empty, Vec::new(), advance));
+            }
+
+            let paths = outline::cubic_to_quadratic_compatible(&paths, options.tolerance)
+                .context("Masters are not interpolation compatible")?;
+            let mut glyphs = SimpleGlyph::interpolatable_glyphs_from_bezpaths(&paths)
+                .map_err(|err| anyhow!("Masters are not interpolation compatible: {err:?}"))?;
+            let data = gvar::glyph_variation_data(&glyphs, &master_advances, &regions)?;
+
+            Ok((Glyph::Simple(glyphs.swap_remove(0)), data, advance))
+        };
+
+        let draw_glyph_record = |gid: GlyphId| -> Result<GlyphRecord> {
+            // Keep the original record unless the glyph is annotated, or is a composite
+            // built from annotated glyphs (which would otherwise pick up their ruby)
+            if let Some((src_glyf, src_loca)) = &original {
+                if !gid_char_map.contains_key(&gid)
+                    && !uses_annotated.contains(&gid)
+                    && let Some(data) = glyf::raw_glyph(src_glyf, src_loca, gid)
+                {
+                    return Ok(GlyphRecord::Raw(data));
+                }
             }
+
+            // Drawn once, so the advance counters see each glyph once
+            let (drawn, fit) = draw_fitted(gid)?;
+
+            if let Some(gvar) = &variations {
+                match draw_variable_glyph(gvar, gid, &drawn, fit.as_ref()) {
+                    Ok((glyph, data, advance)) => {
+                        return Ok(GlyphRecord::Drawn {
+                            glyph,
+                            variations: Some(data),
+                            advance,
+                        });
+                    }
+                    Err(err) if options.strict => {
+                        return Err(err.context(format!("Glyph {gid} cannot keep its variations")));
+                    }
+                    Err(err) => {
+                        // Fall back to a static glyph without variations
+                        warn!("Glyph {gid} will not vary: {err:#}");
+                    }
+                }
+            }
+
+            let (mut final_path, has_content) = (drawn.path, drawn.has_content);
+
+            // CFF outlines (from the ruby font) are cubic, but glyf only supports quadratics
+            if outline::has_cubics(&final_path) {
+                final_path = outline::cubic_to_quadratic(&final_path, options.tolerance);
+            }
+
+            let glyph = if !has_content && final_path.elements().is_empty() {
+                Glyph::Empty
+            } else {
+                match SimpleGlyph::from_bezpath(&final_path) {
+                    Ok(s) => Glyph::Simple(s),
+                    Err(err) => {
+                        empty_glyph(gid, EmptyCause::Encode, format!("{err:?}"))?;
+
+                        Glyph::Empty
+                    }
+                }
+            };
+
+            Ok(GlyphRecord::Drawn {
+                glyph,
+                // Static glyphs in variable fonts get empty variation data
+                variations: variations.as_ref().map(|_| Vec::new()),
+                advance: fit.map(|_| drawn.advance),
+            })
         };
 
-        glyf_loca_builder.add_glyph(&write_glyph)?;
+        // Glyphs are drawn in parallel, then added to the builder in glyph order
+        let records = glyphs
+            .par_iter()
+            .map(|&gid| -> Result<GlyphRecord> {
+                let record = draw_glyph_record(gid)?;
+
+                glyphs_span.pb_inc(1);
+
+                Ok(record)
+            })
+            .collect::<Result<Vec<GlyphRecord>>>()?;
+
+        for (gid, record) in glyphs.iter().copied().zip(records) {
+            match record {
+                GlyphRecord::Raw(data) => glyf_builder.add_raw(data),
+                GlyphRecord::Drawn {
+                    glyph,
+                    variations,
+                    advance,
+                } => {
+                    if let Some(advance) = advance {
+                        advances.insert(gid, advance.round() as u16);
+                    }
+
+                    if let Some(variations) = variations {
+                        glyph_variations.insert(gid, variations);
+                    }
+
+                    glyf_builder.add_glyph(&glyph)?;
+                }
+            }
+        }
+
+        let built = glyf_builder.build();
+
+        font_builder
+            .add_raw(Glyf::TAG, built.glyf)
+            .add_table(&built.loca)
+            .context("Failed to add loca table")?;
+
+        if variations.is_some() {
+            let gvar_data = font.data_for_tag(Gvar::TAG).context("Missing gvar table")?;
+
+            font_builder.add_raw(
+                Gvar::TAG,
+                gvar::rebuild_gvar(gvar_data.as_bytes(), &glyph_variations)
+                    .context("Failed to rebuild gvar table")?,
+            );
+        }
+
+        loca_fmt = Some(built.loca_format);
+        glyf_maxima = Some((built.max_points, built.max_contours));
+        bounds = built.bounds;
     }
 
     drop(glyphs_span_enter);
     drop(glyphs_span);
 
-    let (glyf_data, loca_data, loca_fmt) = glyf_loca_builder.build();
+    empty_glyphs.report();
 
-    let mut font_builder = FontBuilder::new();
+    // Side bearings and extents follow the new outlines
+    let widened = widened.into_inner();
+
+    if widened > 0 {
+        info!(
+            "Widened {widened} advances to fit ruby ({} with compressed ruby)",
+            compressed.into_inner()
+        );
+    }
+
+    let refused = refused.into_inner();
+
+    if refused > 0 {
+        warn!(
+            "Ruby overhangs the advance of {refused} glyphs, but the font is monospaced so advances were not widened"
+        );
+    }
+
+    let (hmtx_table, mut hhea_table) = metrics::rebuild_hmtx(font, &bounds, &advances)?;
+    let mut os2_table: Option<Os2> = font.os2().ok().map(|os2| os2.to_owned_table());
+
+    let annotated = annotated.into_inner().unwrap();
+
+    // Ruby usually extends past the original ascender or descender. Only annotated glyphs
+    // are measured, so other tall glyphs do not change the line metrics.
+    let ruby_bounds = annotated
+        .iter()
+        .filter_map(|gid| bounds.get(gid.to_u32() as usize).copied().flatten())
+        .reduce(GlyphBounds::union);
+
+    if let Some(ruby_bounds) = ruby_bounds {
+        metrics::apply_vertical_metrics(
+            options.vertical_metrics,
+            ruby_bounds,
+            &mut hhea_table,
+            os2_table.as_mut(),
+        );
+    }
+
+    font_builder
+        .add_table(&hmtx_table)
+        .context("Failed to add hmtx table")?
+        .add_table(&hhea_table)
+        .context("Failed to add hhea table")?;
+
+    if let Some(os2_table) = &os2_table {
+        font_builder
+            .add_table(os2_table)
+            .context("Failed to add OS/2 table")?;
+    }
+
+    if let Some(name_table) = &name_table {
+        font_builder
+            .add_table(name_table)
+            .context("Failed to add name table")?;
+    }
+
+    if let Some(ruby_credits) = &options.ruby_credits
+        && let Some(meta) = font.data_for_tag(license::META)
+        && let Some(meta) = license::credit_meta(meta.as_bytes(), ruby_credits)
+    {
+        font_builder.add_raw(license::META, meta);
+    }
+
+    if let Some(settings) = &options.build_settings {
+        // Characters are recorded rather than glyphs, which subsetting renumbers
+        let mut codepoints = charmap
+            .mappings()
+            .filter(|(_, gid)| annotated.contains(gid))
+            .map(|(codepoint, _)| codepoint)
+            .collect::<Vec<u32>>();
+        codepoints.sort_unstable();
+
+        let info = BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            settings: settings.clone(),
+            ranges: renderer.ranges().to_vec(),
+            annotated: build_info::to_ranges(codepoints),
+        };
+
+        font_builder.add_raw(build_info::TAG, info.to_bytes());
+    }
+
+    if let Some((max_points, max_contours)) = glyf_maxima {
+        let mut maxp: Maxp = maxp.to_owned_table();
+        maxp.max_points = Some(max_points);
+        maxp.max_contours = Some(max_contours);
+
+        font_builder
+            .add_table(&maxp)
+            .context("Failed to add maxp table")?;
+    }
 
     for record in font.table_directory.table_records() {
         let tag = record.tag();
 
-        // Skip glyf/loca - we'll insert rebuilt data later
-        if tag == Glyf::TAG || tag == Loca::TAG {
+        // Skip rebuilt tables, and drop those that can no longer be trusted
+        if font_builder.contains(tag) || metrics::STALE_TABLES.contains(&tag) {
             continue;
         }
 
@@ -271,7 +1119,16 @@ pub fn process_font_ref(font: &FontRef, renderer: &Box<dyn RubyRenderer>) -> Res
             if let Ok(head) = font.head() {
                 let mut head: Head = head.to_owned_obj(font_file_data);
 
-                head.index_to_loc_format = loca_fmt as i16;
+                if let Some(loca_fmt) = loca_fmt {
+                    head.index_to_loc_format = loca_fmt as i16;
+                }
+
+                metrics::update_head_bounds(&mut head, &bounds);
+
+                if let Some(renamed) = &renamed {
+                    head.font_revision = renamed.revision;
+                }
+
                 head.checksum_adjustment = 0;
 
                 font_builder
@@ -287,16 +1144,39 @@ pub fn process_font_ref(font: &FontRef, renderer: &Box<dyn RubyRenderer>) -> Res
         }
     }
 
-    font_builder
-        .add_table(&glyf_data)
-        .context("Failed to add glyf table")?
-        .add_table(&loca_data)
-        .context("Failed to add loca table")?;
+    let mut data = font_builder.build();
+
+    // `FontBuilder` always writes the TrueType sfnt version, but fonts with PostScript
+    // outlines must be tagged `OTTO`
+    if cff_tag.is_some() {
+        data[0..4].copy_from_slice(&CFF_SFNT_VERSION.to_be_bytes());
+    }
+
+    checksum::update_checksums(&mut data)?;
+
+    Ok(data)
+}
+
+/// Pack separately processed fonts into one collection, sharing identical tables.
+///
+/// Faces of fonts that are already collections are included individually.
+pub fn merge_fonts(fonts: &[ProcessedFont]) -> Result<Vec<u8>> {
+    let mut faces = Vec::new();
 
-    Ok(font_builder.build())
+    for font in fonts {
+        let file = FileRef::new(&font.data).context("Failed to parse font to merge")?;
+
+        for face in file.fonts() {
+            faces.push(face.context("Failed to read font to merge")?);
+        }
+    }
+
+    ensure!(!faces.is_empty(), "No fonts to merge");
+
+    ttc::build_collection(&faces).context("Failed to build TTC")
 }
 
-pub fn subset_by_renderers(font_data: &[u8], renderer: &Box<dyn RubyRenderer>) -> Result<Vec<u8>> {
+pub fn subset_by_renderers(font_data: &[u8], renderer: &dyn RubyRenderer) -> Result<Vec<u8>> {
     let font = FontRef::new(font_data).context("Failed to parse font for subsetting")?;
 
     // Build unicodes set based on provided character sets
@@ -311,7 +1191,9 @@ pub fn subset_by_renderers(font_data: &[u8], renderer: &Box<dyn RubyRenderer>) -
     let glyph_ids = IntSet::<GlyphId>::empty();
     let drop_tables = IntSet::<Tag>::empty();
     let no_subset_tables = IntSet::<Tag>::empty();
-    let passthrough_tables = IntSet::<Tag>::empty();
+    // Build info records characters rather than glyphs, so it is still accurate after subsetting
+    let mut passthrough_tables = IntSet::<Tag>::empty();
+    passthrough_tables.insert(build_info::TAG);
     let name_ids = IntSet::<NameId>::empty();
     let name_languages = IntSet::<u16>::empty();
 
@@ -327,10 +1209,197 @@ pub fn subset_by_renderers(font_data: &[u8], renderer: &Box<dyn RubyRenderer>) -
         &name_languages,
     );
 
-    subset_font(&font, &plan).context("Subset error")
+    let mut data = subset_font(&font, &plan).context("Subset error")?;
+    checksum::update_checksums(&mut data)?;
+
+    Ok(data)
+}
+
+#[cfg(feature = "woff")]
+/// Compress a font to WOFF 1.0, with optional metadata and private data blocks.
+pub fn convert_to_woff(font_data: &[u8], extras: woff::WoffExtras) -> Result<Vec<u8>> {
+    woff::compress(font_data, extras).context("WOFF compression failed")
 }
 
 #[cfg(feature = "woff2")]
+/// Compress a font or font collection to WOFF2.
+///
+/// Collections get a WOFF2 collection directory, with tables shared between faces stored once.
 pub fn convert_to_woff2(font_data: &[u8]) -> Result<Vec<u8>> {
-    woofwoof::compress(font_data, &[], 11, true).context("WOFF2 compression failed")
+    let file = FileRef::new(font_data).context("Failed to parse font for WOFF2 compression")?;
+
+    let transform = match file {
+        FileRef::Font(_) => true,
+        FileRef::Collection(collection) => {
+            // The glyf transform rebuilds loca from glyf, so a shared glyf table can only be
+            // transformed if every face using it also shares its loca
+            let mut loca_for_glyf = FxHashMap::<u32, u32>::default();
+            let mut consistent = true;
+
+            for font in collection.iter() {
+                let font = font.context("Failed to read font in collection")?;
+                let offset_of = |tag: Tag| {
+                    font.table_directory
+                        .table_records()
+                        .iter()
+                        .find(|record| record.tag() == tag)
+                        .map(|record| record.offset())
+                };
+
+                if let (Some(glyf), Some(loca)) = (offset_of(Glyf::TAG), offset_of(Loca::TAG)) {
+                    consistent &= *loca_for_glyf.entry(glyf).or_insert(loca) == loca;
+                }
+            }
+
+            if !consistent {
+                warn!(
+                    "Faces share glyf tables but not their loca, so glyf is stored untransformed"
+                );
+            }
+
+            consistent
+        }
+    };
+
+    woofwoof::compress(font_data, &[], 11, transform).context("WOFF2 compression failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::RangeInclusive;
+
+    use fontcull_write_fonts::{
+        OffsetMarker,
+        tables::{
+            cmap::Cmap,
+            hhea::Hhea,
+            hmtx::{Hmtx, LongMetric},
+            name::NameRecord,
+        },
+    };
+
+    use super::*;
+
+    const RANGE: RangeInclusive<u32> = 0x4E00..=0x4E00;
+
+    /// A renderer covering only U+4E00, which never annotates anything.
+    struct NoRuby;
+
+    impl RubyRenderer for NoRuby {
+        fn annotate(
+            &self,
+            _ch: char,
+            _final_path: &mut BezPath,
+            _orig_advance: f64,
+            _main_upem: f64,
+            _location: &[(Tag, F2Dot14)],
+            _baseline: Option<BaselineTarget>,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn measure(
+            &self,
+            _ch: char,
+            _base_path: &BezPath,
+            _main_upem: f64,
+            _location: &[(Tag, F2Dot14)],
+        ) -> Result<Option<BaselineTarget>> {
+            Ok(None)
+        }
+
+        fn ranges(&self) -> &[RangeInclusive<u32>] {
+            &[RANGE]
+        }
+
+        fn name(&self) -> &str {
+            "None"
+        }
+    }
+
+    #[test]
+    fn subsetting_keeps_build_info() {
+        let mut square = BezPath::new();
+        square.move_to((0.0, 0.0));
+        square.line_to((100.0, 0.0));
+        square.line_to((100.0, 100.0));
+        square.line_to((0.0, 100.0));
+        square.close_path();
+        let square = Glyph::Simple(SimpleGlyph::from_bezpath(&square).unwrap());
+
+        let mut glyf_builder = GlyfBuilder::new();
+        glyf_builder.add_glyph(&Glyph::Empty).unwrap();
+        glyf_builder.add_glyph(&square).unwrap();
+        glyf_builder.add_glyph(&square).unwrap();
+        let built = glyf_builder.build();
+
+        let head = Head {
+            units_per_em: 1000,
+            index_to_loc_format: built.loca_format as i16,
+            ..Default::default()
+        };
+        let hhea = Hhea {
+            number_of_h_metrics: 3,
+            ..Default::default()
+        };
+        let cmap = Cmap::from_mappings([('一', GlyphId::new(1)), ('a', GlyphId::new(2))]).unwrap();
+        let info = BuildInfo {
+            version: "0.1.0".to_string(),
+            settings: vec![("renderer".to_string(), "pinyin".to_string())],
+            ranges: vec![RANGE],
+            annotated: vec![RANGE],
+        };
+
+        let mut builder = FontBuilder::new();
+        builder
+            .add_raw(Glyf::TAG, built.glyf)
+            .add_raw(build_info::TAG, info.to_bytes());
+        builder.add_table(&built.loca).unwrap();
+        builder.add_table(&head).unwrap();
+        builder.add_table(&hhea).unwrap();
+        builder
+            .add_table(&Hmtx::new(vec![LongMetric::new(500, 0); 3], Vec::new()))
+            .unwrap();
+        builder.add_table(&Maxp::new(3)).unwrap();
+        builder.add_table(&cmap).unwrap();
+
+        let subset = subset_by_renderers(&builder.build(), &NoRuby).unwrap();
+        let font = FontRef::new(&subset).unwrap();
+
+        // 'a' is dropped, but the build info survives
+        assert_eq!(font.maxp().unwrap().num_glyphs(), 2);
+        assert_eq!(BuildInfo::read(&font).unwrap(), Some(info));
+    }
+
+    #[test]
+    fn instances_are_named_apart() {
+        let font = |postscript: Option<&str>| {
+            let records = postscript
+                .map(|postscript| {
+                    NameRecord::new(
+                        3,
+                        1,
+                        0x409,
+                        NameId::POSTSCRIPT_NAME,
+                        OffsetMarker::new(postscript.to_string()),
+                    )
+                })
+                .into_iter()
+                .collect();
+
+            let mut builder = FontBuilder::new();
+            builder.add_table(&Name::new(records)).unwrap();
+            builder.build()
+        };
+
+        // Each instance is written under its own PostScript name, or the instance's
+        assert_eq!(
+            output_file_name(&font(Some("Sarasa-Bold")), "Sarasa-Light").unwrap(),
+            "Sarasa-Bold.ttf"
+        );
+        assert_eq!(
+            output_file_name(&font(None), "Sarasa-Light").unwrap(),
+            "Sarasa-Light.ttf"
+        );
+    }
 }
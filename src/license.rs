@@ -0,0 +1,456 @@
+use std::fmt;
+
+use fontcull_font_types::NameId;
+use fontcull_read_fonts::{FontRef, TableProvider, types::Tag};
+use fontcull_write_fonts::tables::name::Name;
+
+use crate::name;
+
+/// `fsType` bit: the font must not be modified, embedded or exchanged without permission.
+const FS_TYPE_RESTRICTED: u16 = 0x0002;
+/// `fsType` bit: the font must not be subset before embedding.
+const FS_TYPE_NO_SUBSETTING: u16 = 0x0100;
+/// `fsType` bit: only bitmaps may be embedded, never outlines.
+const FS_TYPE_BITMAP_ONLY: u16 = 0x0200;
+
+/// The `meta` table, whose language tags are merged when crediting a ruby font.
+pub const META: Tag = Tag::new(b"meta");
+/// `meta` entries listing the design and supported languages, as comma-separated tags.
+const META_LANGUAGES: [Tag; 2] = [Tag::new(b"dlng"), Tag::new(b"slng")];
+
+/// A license restriction that forbids building the requested output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LicenseIssue {
+    /// `fsType` has the Restricted License embedding bit set
+    RestrictedLicense,
+    /// `fsType` has the Bitmap embedding only bit set
+    BitmapOnly,
+    /// `fsType` forbids subsetting, but subsetting was requested
+    NoSubsetting,
+    /// An OFL font keeps one of its Reserved Font Names in the modified family name
+    ReservedFontName(String),
+}
+
+impl fmt::Display for LicenseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LicenseIssue::RestrictedLicense => {
+                f.write_str("OS/2 fsType restricts the font from being modified")
+            }
+            LicenseIssue::BitmapOnly => {
+                f.write_str("OS/2 fsType only allows bitmaps to be embedded")
+            }
+            LicenseIssue::NoSubsetting => f.write_str("OS/2 fsType forbids subsetting"),
+            LicenseIssue::ReservedFontName(reserved) => write!(
+                f,
+                "The OFL forbids modified fonts from using the Reserved Font Name \"{reserved}\"; rename the family with --family-name"
+            ),
+        }
+    }
+}
+
+fn is_ofl_license(description: Option<&str>, url: Option<&str>) -> bool {
+    description.is_some_and(|description| description.contains("Open Font License"))
+        || url.is_some_and(|url| {
+            url.contains("scripts.sil.org/OFL") || url.contains("openfontlicense.org")
+        })
+}
+
+/// Returns whether the license of `font` is the SIL Open Font License.
+pub fn is_ofl(font: &FontRef) -> bool {
+    is_ofl_license(
+        name::english_name(font, NameId::LICENSE_DESCRIPTION).as_deref(),
+        name::english_name(font, NameId::LICENSE_URL).as_deref(),
+    )
+}
+
+/// Add the quoted names following each "Reserved Font Name" in `text` to `reserved`.
+fn reserved_names_in(text: &str, reserved: &mut Vec<String>) {
+    // ASCII lowercasing keeps byte offsets valid in `text`
+    let lower = text.to_ascii_lowercase();
+
+    for (start, _) in lower.match_indices("reserved font name") {
+        // Names are quoted in the rest of the sentence, e.g. `Names "A" and "B".`
+        let rest = &text[start..];
+        let sentence = rest.split(['\n', ';']).next().unwrap_or(rest);
+        let sentence = sentence.split(". ").next().unwrap_or(sentence);
+
+        let names = sentence
+            .split(['"', '“', '”'])
+            .skip(1)
+            .step_by(2)
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+
+        for name in names {
+            if !reserved.contains(&name) {
+                reserved.push(name);
+            }
+        }
+    }
+}
+
+/// Reserved Font Names declared in the copyright notice or license description, such as
+/// `with Reserved Font Name "Source"`.
+pub fn reserved_font_names(font: &FontRef) -> Vec<String> {
+    let mut reserved = Vec::new();
+
+    for name_id in [NameId::COPYRIGHT_NOTICE, NameId::LICENSE_DESCRIPTION] {
+        if let Some(text) = name::english_name(font, name_id) {
+            reserved_names_in(&text, &mut reserved);
+        }
+    }
+
+    reserved
+}
+
+/// Check that `font` may be modified (and subset, if `subset`), and that an OFL font's
+/// Reserved Font Names do not survive into `output_family`.
+pub fn check(font: &FontRef, output_family: &str, subset: bool) -> Vec<LicenseIssue> {
+    let mut issues = Vec::new();
+    let fs_type = font.os2().map(|os2| os2.fs_type()).unwrap_or_default();
+
+    if fs_type & FS_TYPE_RESTRICTED != 0 {
+        issues.push(LicenseIssue::RestrictedLicense);
+    }
+
+    if fs_type & FS_TYPE_BITMAP_ONLY != 0 {
+        issues.push(LicenseIssue::BitmapOnly);
+    }
+
+    if subset && fs_type & FS_TYPE_NO_SUBSETTING != 0 {
+        issues.push(LicenseIssue::NoSubsetting);
+    }
+
+    if is_ofl(font) {
+        let output_family = output_family.to_lowercase();
+
+        issues.extend(
+            reserved_font_names(font)
+                .into_iter()
+                .filter(|reserved| output_family.contains(&reserved.to_lowercase()))
+                .map(LicenseIssue::ReservedFontName),
+        );
+    }
+
+    issues
+}
+
+/// Copyright and license details of a font whose outlines end up in another font.
+#[derive(Clone, Debug, Default)]
+pub struct Credits {
+    pub family: String,
+    /// Name ID 0
+    pub copyright: Option<String>,
+    /// Name ID 13
+    pub license: Option<String>,
+    /// Name ID 14
+    pub license_url: Option<String>,
+    pub fs_type: u16,
+    /// `dlng` and `slng` entries of `meta`
+    pub languages: Vec<(Tag, String)>,
+}
+
+impl Credits {
+    pub fn read(font: &FontRef) -> Self {
+        Self {
+            family: name::english_name(font, NameId::TYPOGRAPHIC_FAMILY_NAME)
+                .or_else(|| name::english_name(font, NameId::FAMILY_NAME))
+                .unwrap_or_else(|| "the ruby font".to_string()),
+            copyright: name::english_name(font, NameId::COPYRIGHT_NOTICE),
+            license: name::english_name(font, NameId::LICENSE_DESCRIPTION),
+            license_url: name::english_name(font, NameId::LICENSE_URL),
+            fs_type: font.os2().map(|os2| os2.fs_type()).unwrap_or_default(),
+            languages: font
+                .data_for_tag(META)
+                .and_then(|meta| read_meta(meta.as_bytes()))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(tag, _)| META_LANGUAGES.contains(tag))
+                .filter_map(|(tag, data)| Some((tag, String::from_utf8(data).ok()?)))
+                .collect(),
+        }
+    }
+
+    pub fn is_ofl(&self) -> bool {
+        is_ofl_license(self.license.as_deref(), self.license_url.as_deref())
+    }
+}
+
+/// Reasons the license of the `ruby` font may not allow its outlines in `base`.
+pub fn incompatibilities(base: &FontRef, ruby: &Credits) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    if ruby.fs_type & (FS_TYPE_RESTRICTED | FS_TYPE_BITMAP_ONLY) != 0 {
+        issues.push(format!(
+            "OS/2 fsType of {} forbids embedding its outlines in another font",
+            ruby.family
+        ));
+    }
+
+    // Everything derived from an OFL font must stay under the OFL
+    if is_ofl(base) && !ruby.is_ofl() {
+        issues.push(format!(
+            "The base font is licensed under the OFL, but {} is not, so the output may not be distributable under either license",
+            ruby.family
+        ));
+    }
+
+    issues
+}
+
+/// Credit the `ruby` font alongside the base font in every copyright (0), license
+/// description (13) and license URL (14) record of `name`.
+pub fn credit(name: &mut Name, ruby: &Credits) {
+    let license = match (&ruby.license, &ruby.license_url) {
+        (Some(license), Some(url)) => Some(format!("{license} ({url})")),
+        (license, url) => license.clone().or_else(|| url.clone()),
+    };
+
+    for (name_id, ruby_value) in [
+        (NameId::COPYRIGHT_NOTICE, ruby.copyright.clone()),
+        (NameId::LICENSE_DESCRIPTION, license),
+    ] {
+        let Some(ruby_value) = ruby_value else {
+            continue;
+        };
+
+        let credit = format!("Ruby glyphs from {}: {ruby_value}", ruby.family);
+
+        name::rewrite_name(name, name_id, &credit, |record| {
+            let base = record.string.as_str();
+
+            if base.contains(ruby_value.as_str()) {
+                base.to_string()
+            } else {
+                format!("{base}\n\n{credit}")
+            }
+        });
+    }
+
+    // One URL per line, so each stays usable on its own
+    if let Some(url) = &ruby.license_url {
+        name::rewrite_name(name, NameId::LICENSE_URL, url, |record| {
+            let base = record.string.as_str();
+
+            if base.lines().any(|line| line.trim() == url) {
+                base.to_string()
+            } else {
+                format!("{base}\n{url}")
+            }
+        });
+    }
+}
+
+/// The `(tag, data)` entries of a `meta` table, or `None` if it is malformed.
+fn read_meta(data: &[u8]) -> Option<Vec<(Tag, Vec<u8>)>> {
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+
+    let count = u32_at(12)? as usize;
+
+    (0..count)
+        .map(|idx| {
+            let record = 16 + idx * 12;
+            let tag = Tag::from_u32(u32_at(record)?);
+            let offset = u32_at(record + 4)? as usize;
+            let length = u32_at(record + 8)? as usize;
+
+            Some((tag, data.get(offset..offset.checked_add(length)?)?.to_vec()))
+        })
+        .collect()
+}
+
+fn write_meta(entries: &[(Tag, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // Version 1, then the unused flags and reserved fields
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+
+    let mut offset = 16 + 12 * entries.len();
+
+    for (tag, data) in entries {
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&(offset as u32).to_be_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+
+    for (_, data) in entries {
+        out.extend_from_slice(data);
+    }
+
+    out
+}
+
+/// Add the `ruby` font's design and supported languages to the entries the base font's
+/// `meta` table already has, since its glyphs now cover them too.
+///
+/// Returns `None` if `meta` is malformed or nothing was added.
+pub fn credit_meta(meta: &[u8], ruby: &Credits) -> Option<Vec<u8>> {
+    let mut entries = read_meta(meta)?;
+    let mut changed = false;
+
+    for (tag, data) in &mut entries {
+        let Some((_, ruby_languages)) = ruby
+            .languages
+            .iter()
+            .find(|(ruby_tag, _)| *ruby_tag == *tag)
+        else {
+            continue;
+        };
+
+        let Ok(languages) = std::str::from_utf8(data) else {
+            continue;
+        };
+
+        let mut languages = languages
+            .split(',')
+            .map(str::trim)
+            .filter(|language| !language.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+        let count = languages.len();
+
+        for language in ruby_languages.split(',').map(str::trim) {
+            if !language.is_empty() && !languages.iter().any(|known| known == language) {
+                languages.push(language.to_string());
+            }
+        }
+
+        if languages.len() != count {
+            *data = languages.join(",").into_bytes();
+            changed = true;
+        }
+    }
+
+    changed.then(|| write_meta(&entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserved(text: &str) -> Vec<String> {
+        let mut reserved = Vec::new();
+        reserved_names_in(text, &mut reserved);
+        reserved
+    }
+
+    #[test]
+    fn finds_quoted_reserved_names() {
+        assert_eq!(
+            reserved("Copyright 2014 Adobe, with Reserved Font Name \"Source\"."),
+            ["Source"]
+        );
+        assert_eq!(
+            reserved("with Reserved Font Names \"Noto\" and “Noto Sans”. Other \"Text\"."),
+            ["Noto", "Noto Sans"]
+        );
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        assert_eq!(reserved("WITH RESERVED FONT NAME \"Fira\""), ["Fira"]);
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_sentence() {
+        assert_eq!(
+            reserved("Reserved Font Name \"A\"; see \"LICENSE\"\nAlso \"B\""),
+            ["A"]
+        );
+        assert!(reserved("No reserved names here, just \"quotes\"").is_empty());
+    }
+
+    #[test]
+    fn credit_adds_the_ruby_license_url() {
+        use fontcull_write_fonts::{OffsetMarker, tables::name::NameRecord};
+
+        let record = |name_id, value: &str| {
+            NameRecord::new(3, 1, 0x409, name_id, OffsetMarker::new(value.to_string()))
+        };
+        let mut name = Name::new(vec![
+            record(NameId::LICENSE_DESCRIPTION, "Base license"),
+            record(NameId::LICENSE_URL, "https://base.example/license"),
+        ]);
+        let ruby = Credits {
+            family: "Ruby Sans".to_string(),
+            license: Some("Ruby license".to_string()),
+            license_url: Some("https://ruby.example/license".to_string()),
+            ..Credits::default()
+        };
+
+        credit(&mut name, &ruby);
+
+        let string = |name_id| {
+            name.name_record
+                .iter()
+                .find(|record| record.name_id == name_id)
+                .map(|record| record.string.as_str())
+        };
+
+        assert_eq!(
+            string(NameId::LICENSE_DESCRIPTION),
+            Some(
+                "Base license\n\nRuby glyphs from Ruby Sans: Ruby license (https://ruby.example/license)"
+            )
+        );
+        assert_eq!(
+            string(NameId::LICENSE_URL),
+            Some("https://base.example/license\nhttps://ruby.example/license")
+        );
+
+        // Crediting again adds nothing
+        credit(&mut name, &ruby);
+        assert_eq!(
+            string(NameId::LICENSE_URL),
+            Some("https://base.example/license\nhttps://ruby.example/license")
+        );
+
+        // Without a ruby copyright the base copyright is left alone
+        assert_eq!(string(NameId::COPYRIGHT_NOTICE), None);
+    }
+
+    #[test]
+    fn skips_duplicates_and_empty_names() {
+        let mut names = reserved("Reserved Font Name \"Fira\" and \" \"");
+        reserved_names_in("Reserved Font Name \"Fira\"", &mut names);
+
+        assert_eq!(names, ["Fira"]);
+    }
+
+    #[test]
+    fn credit_meta_merges_existing_language_entries() {
+        let base = write_meta(&[
+            (Tag::new(b"dlng"), b"Hani".to_vec()),
+            (Tag::new(b"slng"), b"Hani, Latn".to_vec()),
+        ]);
+        let ruby = Credits {
+            languages: vec![
+                (Tag::new(b"slng"), "Latn,Grek".to_string()),
+                (Tag::new(b"dlng"), "Hani".to_string()),
+            ],
+            ..Credits::default()
+        };
+
+        let merged = read_meta(&credit_meta(&base, &ruby).unwrap()).unwrap();
+
+        assert_eq!(
+            merged,
+            [
+                (Tag::new(b"dlng"), b"Hani".to_vec()),
+                (Tag::new(b"slng"), b"Hani,Latn,Grek".to_vec()),
+            ]
+        );
+
+        // Entries the base font does not declare are not added
+        let base = write_meta(&[(Tag::new(b"dlng"), b"Hani".to_vec())]);
+        assert!(credit_meta(&base, &ruby).is_none());
+        assert!(credit_meta(b"meta", &ruby).is_none());
+    }
+}
@@ -1,14 +1,31 @@
-use std::{fs, path::PathBuf, str::FromStr};
-
-use anyhow::{Context, Error, Result, anyhow};
+use std::{
+    borrow::Cow,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Error, Result, anyhow, ensure};
 use facet::Facet;
 use figue::{self as args, FigueBuiltins};
-use fontcull_read_fonts::FileRef;
+use fontcull_read_fonts::{FileRef, FontRef};
 use glob::glob;
 use indicatif::ProgressStyle;
-use rubify::renderer::{self, RubyPosition, RubyRenderer};
+use rubify::{
+    ProcessOptions, ProcessedFont,
+    advance::AdvancePolicy,
+    build_info::BuildInfo,
+    container::{self, Container},
+    license::Credits,
+    metrics::VerticalMetrics,
+    name::Rename,
+    naming::{NameFields, NameTemplate},
+    renderer::{self, RubyPosition, RubyRenderer},
+    validate::{self, IssueKind, Reference, ValidationReport},
+};
 use rustc_hash::FxHashSet;
-use tracing::{info, info_span};
+use tracing::{error, info, info_span};
 use tracing_indicatif::{IndicatifLayer, span_ext::IndicatifSpanExt};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -18,13 +35,13 @@ struct Cli {
     #[facet(args::positional)]
     inputs: Vec<String>,
 
-    /// Output directory
+    /// Output directory (required unless inspecting)
     #[facet(args::named, args::short = 'o')]
-    out: PathBuf,
+    out: Option<PathBuf>,
 
-    /// Ruby characters. Can be repeated to enable multiple sets.
+    /// Ruby characters (required unless inspecting). Can be repeated to enable multiple sets.
     #[facet(args::named)]
-    ruby: String,
+    ruby: Option<String>,
 
     /// Separate font file to use for ruby characters
     #[facet(args::named)]
@@ -38,6 +55,29 @@ struct Cli {
     #[facet(args::named, default = false)]
     split: bool,
 
+    /// Pack every output font into one collection (TTC) with this file name in the output directory.
+    #[facet(args::named)]
+    merge: Option<String>,
+
+    /// Name outputs with a template, e.g. '{stem}-{postscript}.{ext}'. Placeholders: stem, index, postscript, family, weight, renderer, ext.
+    #[facet(args::named)]
+    name_template: Option<String>,
+
+    /// Convert all outputs to WOFF 1.0
+    #[cfg(feature = "woff")]
+    #[facet(args::named, default = false)]
+    woff: bool,
+
+    /// Extended metadata XML file to embed in WOFF 1.0 outputs
+    #[cfg(feature = "woff")]
+    #[facet(args::named)]
+    woff_metadata: Option<PathBuf>,
+
+    /// Private data file to embed in WOFF 1.0 outputs
+    #[cfg(feature = "woff")]
+    #[facet(args::named)]
+    woff_private: Option<PathBuf>,
+
     /// Convert all outputs to WOFF2
     #[cfg(feature = "woff2")]
     #[facet(args::named, default = false)]
@@ -63,9 +103,80 @@ struct Cli {
     #[facet(args::named, default = 0.0)]
     offset: f64,
 
+    /// Maximum error (in font units) when converting cubic (CFF) outlines to quadratic.
+    #[facet(args::named, default = 1.0)]
+    tolerance: f64,
+
+    /// Instantiate variable fonts at each named instance, writing a static font per instance.
+    #[facet(args::named, default = false)]
+    instances: bool,
+
+    /// How vertical metrics make room for ruby: keep them, grow the line metrics, or grow only the win metrics.
+    #[facet(args::named, default = "keep")]
+    vertical_metrics: String,
+
+    /// How advances respond to ruby wider than the base glyph: keep them, or expand them to fit.
+    #[facet(args::named, default = "keep")]
+    advance: String,
+
+    /// Maximum advance when expanding, as a multiple of the original. Wider ruby is compressed.
+    #[facet(args::named, default = 1.5)]
+    max_advance_scale: f64,
+
+    /// Fail on glyphs that cannot be drawn or encoded instead of leaving them empty.
+    #[facet(args::named, default = false)]
+    strict: bool,
+
+    /// Suffix appended to the family name of outputs. Defaults to the ruby renderer, e.g. 'Pinyin'.
+    #[facet(args::named)]
+    family_suffix: Option<String>,
+
+    /// Family name for outputs, replacing the original instead of appending a suffix.
+    #[facet(args::named)]
+    family_name: Option<String>,
+
+    /// Keep the original font names, so outputs replace the originals when installed.
+    #[facet(args::named, default = false)]
+    keep_names: bool,
+
+    /// Process fonts even if OS/2 fsType or OFL Reserved Font Names forbid the output.
+    #[facet(args::named, default = false)]
+    ignore_license: bool,
+
     /// Standard CLI options (--help, --version, --completions)
     #[facet(flatten)]
     builtins: FigueBuiltins,
+
+    /// Run a subcommand instead of processing fonts
+    #[facet(args::subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    /// The output directory, checked to be set before any font is processed.
+    fn out(&self) -> &Path {
+        self.out
+            .as_deref()
+            .expect("--out is checked before processing")
+    }
+
+    /// The ruby renderer name, checked to be set before any font is processed.
+    fn ruby(&self) -> &str {
+        self.ruby
+            .as_deref()
+            .expect("--ruby is checked before processing")
+    }
+}
+
+#[derive(Facet)]
+#[repr(u8)]
+enum Command {
+    /// Print how rubified fonts were built and how many of their characters are annotated
+    Inspect {
+        /// Fonts to inspect
+        #[facet(args::positional)]
+        fonts: Vec<String>,
+    },
 }
 
 pub enum Ruby {
@@ -103,6 +214,126 @@ fn position_from_str(s: &str) -> Result<RubyPosition> {
     }
 }
 
+fn vertical_metrics_from_str(s: &str) -> Result<VerticalMetrics> {
+    match s.to_lowercase().as_str() {
+        "grow" => Ok(VerticalMetrics::Grow),
+        "keep" => Ok(VerticalMetrics::Keep),
+        "win" => Ok(VerticalMetrics::GrowWin),
+        other => Err(anyhow!("Unknown vertical metrics argument: {other}")),
+    }
+}
+
+fn advance_policy_from_str(s: &str, max_scale: f64) -> Result<AdvancePolicy> {
+    match s.to_lowercase().as_str() {
+        "keep" => Ok(AdvancePolicy::Keep),
+        "expand" => Ok(AdvancePolicy::Expand { max_scale }),
+        other => Err(anyhow!("Unknown advance argument: {other}")),
+    }
+}
+
+fn rename_from_cli(cli: &Cli) -> Result<Rename> {
+    let requested = [
+        cli.family_suffix.is_some(),
+        cli.family_name.is_some(),
+        cli.keep_names,
+    ];
+
+    ensure!(
+        requested.iter().filter(|&&set| set).count() <= 1,
+        "--family-suffix, --family-name and --keep-names cannot be used together"
+    );
+
+    if cli.keep_names {
+        return Ok(Rename::Keep);
+    }
+
+    if let Some(family) = &cli.family_name {
+        return Ok(Rename::Family(family.clone()));
+    }
+
+    Ok(cli
+        .family_suffix
+        .clone()
+        .map(Rename::Suffix)
+        .unwrap_or_default())
+}
+
+/// Settings recorded in every output, so a font can be traced back to how it was built.
+fn build_settings(cli: &Cli) -> Vec<(String, String)> {
+    let ruby_font = cli
+        .font
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "base font".to_string());
+
+    [
+        ("renderer", cli.ruby().to_string()),
+        ("ruby_font", ruby_font),
+        ("position", cli.position.clone()),
+        ("scale", cli.scale.to_string()),
+        ("gutter", cli.gutter.to_string()),
+        ("offset", cli.offset.to_string()),
+        ("tight", cli.tight.to_string()),
+        ("tolerance", cli.tolerance.to_string()),
+        ("vertical_metrics", cli.vertical_metrics.clone()),
+        ("advance", cli.advance.clone()),
+        ("max_advance_scale", cli.max_advance_scale.to_string()),
+        ("subset", cli.subset.to_string()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect()
+}
+
+/// Print how each font in `paths` was built, and how many of the characters in its
+/// renderer's ranges were annotated.
+fn inspect(paths: &[String]) -> Result<()> {
+    ensure!(!paths.is_empty(), "Usage: rubify inspect <font>...");
+
+    for path in paths {
+        let data = fs::read(path).with_context(|| anyhow!("Failed to read font: {path:?}"))?;
+        let data =
+            container::decode(&data).with_context(|| anyhow!("Failed to decode font: {path:?}"))?;
+        let file =
+            FileRef::new(&data).with_context(|| anyhow!("Failed to parse font: {path:?}"))?;
+        let is_collection = matches!(file, FileRef::Collection(_));
+
+        for (idx, font) in file.fonts().enumerate() {
+            let font = font.context("Failed to read font")?;
+            let label = if is_collection {
+                format!("{path} (face {idx})")
+            } else {
+                path.clone()
+            };
+
+            let Some(info) = BuildInfo::read(&font).context("Failed to read build info")? else {
+                println!("{label}: not built by rubify");
+                continue;
+            };
+
+            println!("{label}: rubify {}", info.version);
+
+            for (key, value) in &info.settings {
+                println!("  {key}: {value}");
+            }
+
+            let (covered, annotated) = info.coverage(&font);
+            let percent = if covered == 0 {
+                0.0
+            } else {
+                annotated as f64 * 100.0 / covered as f64
+            };
+
+            println!(
+                "  coverage: {annotated} of {covered} characters in the renderer's ranges annotated ({percent:.1}%)"
+            );
+        }
+    }
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let indicatif_layer = IndicatifLayer::new();
 
@@ -117,8 +348,16 @@ fn main() -> Result<()> {
 
     let cli: Cli = args::from_std_args().unwrap();
 
-    let ruby = Ruby::from_str(&cli.ruby)
-        .with_context(|| anyhow!("Failed to parse --ruby argument: {}", &cli.ruby))?;
+    // `rubify inspect <font>...` reads back the build info of rubified fonts
+    if let Some(Command::Inspect { fonts }) = &cli.command {
+        return inspect(fonts);
+    }
+
+    ensure!(cli.out.is_some(), "Missing --out <dir>");
+    ensure!(cli.ruby.is_some(), "Missing --ruby <characters>");
+
+    let ruby = Ruby::from_str(cli.ruby())
+        .with_context(|| anyhow!("Failed to parse --ruby argument: {}", cli.ruby()))?;
 
     let mut input_paths: FxHashSet<PathBuf> = FxHashSet::default();
 
@@ -140,19 +379,41 @@ fn main() -> Result<()> {
         return Err(anyhow!("No input files found"));
     }
 
-    if !cli.out.exists() {
-        fs::create_dir_all(&cli.out)
-            .with_context(|| anyhow!("Failed to create out-dir: {:?}", cli.out))?;
+    if !cli.out().exists() {
+        fs::create_dir_all(cli.out())
+            .with_context(|| anyhow!("Failed to create out-dir: {:?}", cli.out()))?;
     }
 
-    #[cfg(feature = "woff2")]
-    if cli.woff2 && !cli.split {
-        anyhow::bail!(
-            "WOFF2 output is only supported when --split is enabled, because we don't currently support converting TTC collections to WOFF2. Please enable --split or disable --woff2."
-        );
+    #[cfg(all(feature = "woff", feature = "woff2"))]
+    if cli.woff && cli.woff2 {
+        anyhow::bail!("--woff and --woff2 cannot be used together");
     }
 
-    info!("Processing {} inputs -> {:?}", input_paths.len(), cli.out);
+    #[cfg(feature = "woff")]
+    ensure!(
+        !(cli.woff && cli.merge.is_some()),
+        "--woff cannot be used with --merge, since WOFF 1.0 cannot hold font collections"
+    );
+
+    // Curve approximation never converges without a positive tolerance
+    ensure!(
+        cli.tolerance > 0.0,
+        "--tolerance must be greater than 0, got {}",
+        cli.tolerance
+    );
+
+    let template = cli
+        .name_template
+        .as_deref()
+        .map(NameTemplate::parse)
+        .transpose()
+        .context("Invalid --name-template")?;
+
+    // Sorted, so merged collections list their faces in a stable order
+    let mut input_paths = input_paths.into_iter().collect::<Vec<PathBuf>>();
+    input_paths.sort();
+
+    info!("Processing {} inputs -> {:?}", input_paths.len(), cli.out());
 
     let inputs_span = info_span!("process_fonts_in_inputs");
     inputs_span.pb_set_style(
@@ -166,45 +427,191 @@ fn main() -> Result<()> {
 
     let inputs_span_enter = inputs_span.enter();
 
+    let mut merged = Vec::new();
+    // Decoded inputs of the merged fonts, with the face index of each font built from them
+    let mut merged_inputs = Vec::<(Vec<u8>, Vec<Option<usize>>)>::new();
+    let mut ranges = Vec::new();
+    let mut failed = 0;
+    // Merged faces that failed validation, which keep the collection from being written
+    let mut merged_failed = 0;
+    // Outputs written so far, so two fonts never overwrite each other. Outputs are named
+    // from the processed fonts, so a collision stops the run after earlier outputs are
+    // written. Paths are lowercased, since macOS and Windows ignore case.
+    let mut paths = FxHashSet::<String>::default();
+
     for in_path in &input_paths {
         inputs_span.pb_inc(1);
         inputs_span.pb_set_message(&format!("Processing {}", in_path.display()));
 
-        let file_name = in_path
-            .file_name()
-            .and_then(|s| s.to_str())
-            .context("Invalid file name")?
-            .to_string();
+        let processed = process_file(&cli, &ruby, in_path)?;
+        ranges = processed.ranges;
+
+        if let Some(input) = processed.input {
+            let face_indexes = processed
+                .fonts
+                .iter()
+                .map(|(font, _)| font.face_index)
+                .collect();
+
+            merged_inputs.push((input, face_indexes));
+        }
+
+        for (font, report) in processed.fonts {
+            if cli.merge.is_some() {
+                // Merged fonts are written together, so their reports are logged here
+                if !report.is_ok() {
+                    error!("Validation failed for {in_path:?}:\n{report}");
+                    failed += 1;
+                    merged_failed += 1;
+                }
+
+                merged.push(font);
+                continue;
+            }
 
-        let out_path = cli.out.join(file_name);
+            let container = output_container(&cli, processed.container);
+            let path = output_path(&cli, template.as_ref(), in_path, &font, container)?;
 
-        process_file(&cli, &ruby, &in_path, &out_path)?;
+            ensure!(
+                paths.insert(path.to_string_lossy().to_lowercase()),
+                "More than one output would be written to {path:?} (ignoring case); use --name-template to name them apart"
+            );
+
+            if !write_font(&cli, font, report, &path, container)? {
+                failed += 1;
+            }
+        }
     }
 
     drop(inputs_span_enter);
     drop(inputs_span);
 
+    if merged_failed > 0 {
+        error!("Not writing the merged collection: {merged_failed} of its fonts failed validation");
+    } else if let Some(merge) = &cli.merge {
+        info!("Merging {} fonts into {merge}", merged.len());
+
+        let font = ProcessedFont {
+            data: rubify::merge_fonts(&merged).context("Failed to merge fonts")?,
+            file_name: Some(merge.clone()),
+            face_index: None,
+        };
+
+        // The merged collection is compared against every input face it was built from
+        let mut faces = Vec::new();
+
+        for (input, face_indexes) in &merged_inputs {
+            let input_faces = FileRef::new(input)
+                .context("Failed to parse merged input")?
+                .fonts()
+                .collect::<Result<Vec<FontRef>, _>>()
+                .context("Failed to read merged input fonts")?;
+
+            for &face_index in face_indexes {
+                faces.extend(reference_faces(&input_faces, face_index));
+            }
+        }
+
+        let reference = Reference {
+            faces,
+            ranges: &ranges,
+            subset: cli.subset,
+        };
+        let report = validate::validate(&font.data, Some(&reference));
+
+        let container = output_container(&cli, Container::Sfnt);
+        let path = cli.out().join(merge);
+        let path = match container.extension() {
+            Some(extension) => path.with_extension(extension),
+            None => path,
+        };
+
+        if !write_font(&cli, font, report, &path, container)? {
+            failed += 1;
+        }
+    }
+
+    ensure!(failed == 0, "{failed} outputs failed validation");
+
     info!("Done processing inputs.");
 
     Ok(())
 }
 
-fn process_file(cli: &Cli, ruby: &Ruby, in_path: &PathBuf, out_path: &PathBuf) -> Result<()> {
-    let base_font_data =
-        fs::read(in_path).with_context(|| anyhow!("Failed to read input file: {in_path:?}"))?;
-    let base_file = FileRef::new(&base_font_data)
-        .map_err(|e| anyhow!("Failed to parse base font file: {:?}", e))?;
+/// The outputs built from one input file.
+struct ProcessedFile {
+    fonts: Vec<(ProcessedFont, ValidationReport)>,
+    /// The container the input was stored in
+    container: Container,
+    /// The decoded input, kept when merging so the merged collection can be validated
+    input: Option<Vec<u8>>,
+    /// Character ranges the renderer annotates
+    ranges: Vec<RangeInclusive<u32>>,
+}
 
-    info!("Processing {:?} -> {:?}", in_path, out_path);
+/// The input faces a font with `face_index` was built from: that face, or every face.
+fn reference_faces<'a>(input_faces: &[FontRef<'a>], face_index: Option<usize>) -> Vec<FontRef<'a>> {
+    match face_index {
+        Some(idx) => input_faces.get(idx).into_iter().cloned().collect(),
+        None => input_faces.to_vec(),
+    }
+}
 
-    let ruby_font_data = if let Some(path) = &cli.font {
-        fs::read(path).with_context(|| anyhow!("Failed to read ruby font file: {path:?}"))?
-    } else {
-        base_font_data.clone()
+/// Read an input file, decoding WOFF and WOFF2 to plain sfnt data. Sfnt inputs are
+/// returned as read, without a copy.
+fn read_input(path: &Path) -> Result<(Container, Vec<u8>)> {
+    let data = fs::read(path).with_context(|| anyhow!("Failed to read input file: {path:?}"))?;
+    let container = Container::detect(&data);
+
+    let decoded = match container::decode(&data)
+        .with_context(|| anyhow!("Failed to decode input file: {path:?}"))?
+    {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(decoded) => Some(decoded),
     };
 
-    let ruby_font_data = Box::leak(ruby_font_data.into_boxed_slice());
-    let ruby_file = FileRef::new(ruby_font_data).context("Failed to parse ruby font file")?;
+    Ok((container, decoded.unwrap_or(data)))
+}
+
+fn process_file(cli: &Cli, ruby: &Ruby, in_path: &PathBuf) -> Result<ProcessedFile> {
+    let (container, base_font_data) = read_input(in_path)?;
+    let mut processed = process_input(cli, ruby, in_path, container, &base_font_data)?;
+
+    // Nothing borrows the input any more, so it moves into the result instead of a copy
+    if cli.merge.is_some() {
+        processed.input = Some(base_font_data);
+    }
+
+    Ok(processed)
+}
+
+fn process_input(
+    cli: &Cli,
+    ruby: &Ruby,
+    in_path: &Path,
+    container: Container,
+    base_font_data: &[u8],
+) -> Result<ProcessedFile> {
+    let base_file = FileRef::new(base_font_data)
+        .map_err(|e| anyhow!("Failed to parse base font file: {:?}", e))?;
+
+    info!("Processing {in_path:?}");
+
+    // Without --font, the ruby is drawn from the base font's own buffer
+    let ruby_font_data = match &cli.font {
+        Some(path) => Some(
+            fs::read(path).with_context(|| anyhow!("Failed to read ruby font file: {path:?}"))?,
+        ),
+        None => None,
+    };
+    let ruby_font_data = ruby_font_data
+        .as_deref()
+        .map(container::decode)
+        .transpose()
+        .context("Failed to decode ruby font file")?;
+
+    let ruby_file = FileRef::new(ruby_font_data.as_deref().unwrap_or(base_font_data))
+        .context("Failed to parse ruby font file")?;
     let ruby_fonts: Vec<_> = ruby_file.fonts().collect();
 
     if ruby_fonts.is_empty() {
@@ -215,7 +622,10 @@ fn process_file(cli: &Cli, ruby: &Ruby, in_path: &PathBuf, out_path: &PathBuf) -
         .clone()
         .context("Failed to load font from ruby font file")?;
 
-    let renderer: Box<dyn RubyRenderer> = match ruby {
+    // A separate ruby font is credited in the output, since its outlines are baked in
+    let ruby_credits = cli.font.as_ref().map(|_| Credits::read(&ruby_font));
+
+    let renderer: Box<dyn RubyRenderer + '_> = match ruby {
         #[cfg(feature = "pinyin")]
         Ruby::Pinyin => {
             let renderer = renderer::pinyin::PinyinRenderer::new(
@@ -244,23 +654,178 @@ fn process_file(cli: &Cli, ruby: &Ruby, in_path: &PathBuf, out_path: &PathBuf) -
         }
     };
 
-    let fonts = rubify::process_font_file(base_file, &renderer, cli.subset, cli.split)?;
+    let options = ProcessOptions {
+        subset: cli.subset,
+        split: cli.split,
+        tolerance: cli.tolerance,
+        instances: cli.instances,
+        vertical_metrics: vertical_metrics_from_str(&cli.vertical_metrics)?,
+        advance: advance_policy_from_str(&cli.advance, cli.max_advance_scale)?,
+        strict: cli.strict,
+        rename: rename_from_cli(cli)?,
+        ignore_license: cli.ignore_license,
+        ruby_credits,
+        build_settings: Some(build_settings(cli)),
+    };
+
+    let fonts = rubify::process_font_file(base_file.clone(), renderer.as_ref(), &options)?;
+
+    // Every output is compared against the input faces it was built from
+    let input_faces = base_file
+        .fonts()
+        .collect::<Result<Vec<FontRef>, _>>()
+        .context("Failed to read input fonts")?;
+
+    let fonts = fonts
+        .into_iter()
+        .map(|font| {
+            let reference = Reference {
+                faces: reference_faces(&input_faces, font.face_index),
+                ranges: renderer.ranges(),
+                subset: cli.subset,
+            };
+            let report = validate::validate(&font.data, Some(&reference));
+
+            (font, report)
+        })
+        .collect();
+
+    Ok(ProcessedFile {
+        fonts,
+        container,
+        input: None,
+        ranges: renderer.ranges().to_vec(),
+    })
+}
+
+/// The container outputs are written in: the input's, unless another format is requested.
+fn output_container(cli: &Cli, mut container: Container) -> Container {
+    #[cfg(feature = "woff")]
+    if cli.woff {
+        container = Container::Woff;
+    }
+
+    #[cfg(feature = "woff2")]
+    if cli.woff2 {
+        container = Container::Woff2;
+    }
+
+    container
+}
+
+/// Where to write `font`: named by the `template` if there is one, otherwise by the font's
+/// own file name or the input's.
+fn output_path(
+    cli: &Cli,
+    template: Option<&NameTemplate>,
+    in_path: &Path,
+    font: &ProcessedFont,
+    container: Container,
+) -> Result<PathBuf> {
+    if let Some(template) = template {
+        let stem = in_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .context("Invalid file name")?;
+
+        let fields = NameFields::from_font(
+            &font.data,
+            stem,
+            font.face_index.unwrap_or_default(),
+            cli.ruby(),
+            container.extension(),
+        )?;
+
+        return Ok(cli.out().join(template.render(&fields)?));
+    }
+
+    let file_name = match &font.file_name {
+        Some(file_name) => file_name.as_str(),
+        None => in_path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .context("Invalid file name")?,
+    };
+
+    let path = cli.out().join(file_name);
+
+    Ok(match container.extension() {
+        Some(extension) => path.with_extension(extension),
+        None => path,
+    })
+}
+
+/// Write a processed font to `path`, converting it to `container`.
+///
+/// Fonts that fail validation are not written: this returns false after logging their
+/// `report`.
+#[cfg_attr(not(feature = "woff"), allow(unused_variables))]
+fn write_font(
+    cli: &Cli,
+    font: ProcessedFont,
+    mut report: ValidationReport,
+    path: &Path,
+    container: Container,
+) -> Result<bool> {
+    let mut data = font.data;
+
+    #[cfg(feature = "woff")]
+    if container == Container::Woff {
+        let read = |path: &Option<PathBuf>| -> Result<Vec<u8>> {
+            match path {
+                Some(path) => {
+                    fs::read(path).with_context(|| anyhow!("Failed to read WOFF block: {path:?}"))
+                }
+                None => Ok(Vec::new()),
+            }
+        };
+
+        let metadata = read(&cli.woff_metadata)?;
+        let private = read(&cli.woff_private)?;
+
+        info!("Converting to WOFF");
+        data = rubify::convert_to_woff(
+            &data,
+            rubify::woff::WoffExtras {
+                metadata: &metadata,
+                private: &private,
+            },
+        )?;
+    }
 
-    for font in fonts {
-        let mut data = font.data;
-        let mut path = out_path.to_owned();
+    #[cfg(feature = "woff2")]
+    if container == Container::Woff2 {
+        info!("Converting to WOFF2");
+        data = rubify::convert_to_woff2(&data)?;
+    }
 
-        #[cfg(feature = "woff2")]
-        if cli.woff2 {
-            info!("Converting to WOFF2");
-            data = rubify::convert_to_woff2(&data)?;
-            path = out_path.with_extension("woff2");
+    // Check the container round-trips to the same structure
+    if container != Container::Sfnt {
+        match container::decode(&data) {
+            Ok(decoded) => report.extend(validate::validate(&decoded, None)),
+            Err(err) => report.push(
+                0,
+                IssueKind::Parse,
+                format!("Failed to decode the {container:?} output: {err:#}"),
+            ),
         }
+    }
 
-        fs::write(&path, data).with_context(|| anyhow!("Failed to write output file: {path:?}"))?;
+    if !report.is_ok() {
+        error!("Validation failed for {path:?}, so it was not written:\n{report}");
 
-        info!("Wrote {path:?}");
+        return Ok(false);
     }
 
-    Ok(())
+    // Templates can name subdirectories of the output directory
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Failed to create output directory: {parent:?}"))?;
+    }
+
+    fs::write(path, data).with_context(|| anyhow!("Failed to write output file: {path:?}"))?;
+
+    info!("Wrote {path:?}");
+
+    Ok(true)
 }
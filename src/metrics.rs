@@ -0,0 +1,445 @@
+use anyhow::Result;
+use fontcull_read_fonts::{
+    FontRef, TableProvider,
+    types::{GlyphId, Tag},
+};
+use fontcull_write_fonts::{
+    from_obj::ToOwnedTable,
+    tables::{
+        head::Head,
+        hhea::Hhea,
+        hmtx::{Hmtx, LongMetric},
+        os2::{Os2, SelectionFlags},
+    },
+    types::{FWord, UfWord},
+};
+use kurbo::Rect;
+use rustc_hash::FxHashMap;
+use tracing::warn;
+
+/// Tables caching values derived from outlines or hinting, which become invalid (or, for
+/// `DSIG`, unverifiable) once glyphs change. These are dropped rather than rebuilt.
+pub const STALE_TABLES: [Tag; 4] = [
+    Tag::new(b"hdmx"),
+    Tag::new(b"LTSH"),
+    Tag::new(b"VDMX"),
+    Tag::new(b"DSIG"),
+];
+
+/// How vertical line metrics respond to ruby extending past them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerticalMetrics {
+    /// Leave every vertical metric unchanged
+    #[default]
+    Keep,
+    /// Grow the line metrics (`hhea`, plus `OS/2` typo metrics when `USE_TYPO_METRICS` is set)
+    /// and the win metrics to fit the ruby
+    Grow,
+    /// Only grow `usWinAscent`/`usWinDescent`, which prevents clipping on Windows
+    GrowWin,
+}
+
+/// A glyph bounding box in font units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GlyphBounds {
+    pub x_min: i16,
+    pub y_min: i16,
+    pub x_max: i16,
+    pub y_max: i16,
+}
+
+impl GlyphBounds {
+    /// Round `rect` outwards to whole font units.
+    pub fn from_rect(rect: Rect) -> Self {
+        Self {
+            x_min: rect.x0.floor() as i16,
+            y_min: rect.y0.floor() as i16,
+            x_max: rect.x1.ceil() as i16,
+            y_max: rect.y1.ceil() as i16,
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            x_min: self.x_min.min(other.x_min),
+            y_min: self.y_min.min(other.y_min),
+            x_max: self.x_max.max(other.x_max),
+            y_max: self.y_max.max(other.y_max),
+        }
+    }
+}
+
+/// Set the font bounding box in `head` to the union of all glyph bounds.
+pub fn update_head_bounds(head: &mut Head, bounds: &[Option<GlyphBounds>]) {
+    if let Some(font_bounds) = bounds.iter().flatten().copied().reduce(GlyphBounds::union) {
+        head.x_min = font_bounds.x_min;
+        head.y_min = font_bounds.y_min;
+        head.x_max = font_bounds.x_max;
+        head.y_max = font_bounds.y_max;
+    }
+}
+
+/// Rebuild `hmtx` with left side bearings matching `bounds`, along with the `hhea`
+/// extents derived from them. Advances are kept as they are, except for those in `advances`.
+///
+/// Glyphs without bounds keep their original side bearing.
+pub fn rebuild_hmtx(
+    font: &FontRef,
+    bounds: &[Option<GlyphBounds>],
+    advances: &FxHashMap<GlyphId, u16>,
+) -> Result<(Hmtx, Hhea)> {
+    let hmtx = font.hmtx()?;
+    let mut hhea: Hhea = font.hhea()?.to_owned_table();
+
+    let long_metrics = hmtx.h_metrics();
+    let side_bearings = hmtx.left_side_bearings();
+
+    // Glyphs sharing the last long metric need their own once their advance changes
+    let long_count = advances
+        .keys()
+        .map(|gid| gid.to_u32() as usize + 1)
+        .max()
+        .unwrap_or_default()
+        .max(long_metrics.len());
+
+    let mut h_metrics = Vec::with_capacity(long_count);
+    let mut left_side_bearings = Vec::with_capacity(bounds.len().saturating_sub(long_count));
+
+    let mut min_lsb = i16::MAX;
+    let mut min_rsb = i16::MAX;
+    let mut x_max_extent = i16::MIN;
+
+    for (idx, bounds) in bounds.iter().enumerate() {
+        // Glyphs past the last long metric share its advance
+        let advance = advances
+            .get(&GlyphId::new(idx as u32))
+            .copied()
+            .or_else(|| {
+                long_metrics
+                    .get(idx)
+                    .or(long_metrics.last())
+                    .map(|metric| metric.advance())
+            })
+            .unwrap_or_default();
+
+        let lsb = match bounds {
+            Some(bounds) => {
+                min_lsb = min_lsb.min(bounds.x_min);
+                min_rsb = min_rsb.min((advance as i32 - bounds.x_max as i32) as i16);
+                x_max_extent = x_max_extent.max(bounds.x_max);
+
+                bounds.x_min
+            }
+            None => match long_metrics.get(idx) {
+                Some(metric) => metric.side_bearing(),
+                None => side_bearings
+                    .get(idx - long_metrics.len())
+                    .map(|lsb| lsb.get())
+                    .unwrap_or_default(),
+            },
+        };
+
+        if idx < long_count {
+            h_metrics.push(LongMetric::new(advance, lsb));
+        } else {
+            left_side_bearings.push(lsb);
+        }
+    }
+
+    hhea.number_of_h_metrics = h_metrics.len() as u16;
+    hhea.advance_width_max = UfWord::new(
+        h_metrics
+            .iter()
+            .map(|metric| metric.advance)
+            .max()
+            .unwrap_or_default(),
+    );
+
+    if x_max_extent != i16::MIN {
+        hhea.min_left_side_bearing = FWord::new(min_lsb);
+        hhea.min_right_side_bearing = FWord::new(min_rsb);
+        hhea.x_max_extent = FWord::new(x_max_extent);
+    }
+
+    Ok((Hmtx::new(h_metrics, left_side_bearings), hhea))
+}
+
+/// Grow vertical metrics according to `policy` so that `ruby_bounds`, the union of the
+/// annotated glyphs' bounds, fits.
+///
+/// Metrics are only ever grown, never shrunk.
+pub fn apply_vertical_metrics(
+    policy: VerticalMetrics,
+    ruby_bounds: GlyphBounds,
+    hhea: &mut Hhea,
+    os2: Option<&mut Os2>,
+) {
+    if policy == VerticalMetrics::Keep {
+        return;
+    }
+
+    let ascent = ruby_bounds.y_max.max(0);
+    let descent = ruby_bounds.y_min.min(0);
+
+    let Some(os2) = os2 else {
+        // Without OS/2, hhea is the only source of line metrics
+        if policy == VerticalMetrics::Grow {
+            hhea.ascender = FWord::new(hhea.ascender.to_i16().max(ascent));
+            hhea.descender = FWord::new(hhea.descender.to_i16().min(descent));
+        }
+
+        return;
+    };
+
+    let win_ascent = os2.us_win_ascent.max(ascent as u16);
+    let win_descent = os2.us_win_descent.max(descent.unsigned_abs());
+    let win_grew = (win_ascent, win_descent) != (os2.us_win_ascent, os2.us_win_descent);
+
+    os2.us_win_ascent = win_ascent;
+    os2.us_win_descent = win_descent;
+
+    let use_typo_metrics = os2.fs_selection.contains(SelectionFlags::USE_TYPO_METRICS);
+
+    if policy == VerticalMetrics::GrowWin {
+        if win_grew && !use_typo_metrics {
+            warn!(
+                "USE_TYPO_METRICS is not set, so Windows also uses the grown win metrics for line spacing"
+            );
+        }
+
+        return;
+    }
+
+    hhea.ascender = FWord::new(hhea.ascender.to_i16().max(ascent));
+    hhea.descender = FWord::new(hhea.descender.to_i16().min(descent));
+
+    // Typo metrics only drive line spacing when USE_TYPO_METRICS is set
+    if use_typo_metrics {
+        os2.s_typo_ascender = os2.s_typo_ascender.max(ascent);
+        os2.s_typo_descender = os2.s_typo_descender.min(descent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_write_fonts::FontBuilder;
+
+    use super::*;
+
+    fn bounds(x_min: i16, x_max: i16) -> GlyphBounds {
+        GlyphBounds {
+            x_min,
+            y_min: -10,
+            x_max,
+            y_max: 100,
+        }
+    }
+
+    /// A font with three glyphs, the last of which shares the advance of the second.
+    fn test_font() -> Vec<u8> {
+        let hmtx = Hmtx::new(
+            vec![LongMetric::new(500, 10), LongMetric::new(600, 20)],
+            vec![30],
+        );
+        let hhea = Hhea {
+            number_of_h_metrics: 2,
+            min_left_side_bearing: FWord::new(10),
+            min_right_side_bearing: FWord::new(50),
+            x_max_extent: FWord::new(550),
+            ..Default::default()
+        };
+
+        let mut builder = FontBuilder::new();
+        builder.add_table(&hmtx).unwrap();
+        builder.add_table(&hhea).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn side_bearings_follow_bounds() {
+        let data = test_font();
+        let font = FontRef::new(&data).unwrap();
+
+        let (hmtx, hhea) = rebuild_hmtx(
+            &font,
+            &[None, Some(bounds(-5, 700)), Some(bounds(40, 200))],
+            &FxHashMap::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            hmtx.h_metrics,
+            [LongMetric::new(500, 10), LongMetric::new(600, -5)]
+        );
+        assert_eq!(hmtx.left_side_bearings, [40]);
+        assert_eq!(hhea.number_of_h_metrics, 2);
+        assert_eq!(hhea.advance_width_max, UfWord::new(600));
+        assert_eq!(hhea.min_left_side_bearing, FWord::new(-5));
+        assert_eq!(hhea.min_right_side_bearing, FWord::new(-100));
+        assert_eq!(hhea.x_max_extent, FWord::new(700));
+    }
+
+    #[test]
+    fn changed_advances_get_long_metrics() {
+        let data = test_font();
+        let font = FontRef::new(&data).unwrap();
+        let advances = FxHashMap::from_iter([(GlyphId::new(2), 800)]);
+
+        let (hmtx, hhea) =
+            rebuild_hmtx(&font, &[None, None, Some(bounds(40, 200))], &advances).unwrap();
+
+        assert_eq!(
+            hmtx.h_metrics,
+            [
+                LongMetric::new(500, 10),
+                LongMetric::new(600, 20),
+                LongMetric::new(800, 40),
+            ]
+        );
+        assert!(hmtx.left_side_bearings.is_empty());
+        assert_eq!(hhea.number_of_h_metrics, 3);
+        assert_eq!(hhea.advance_width_max, UfWord::new(800));
+        assert_eq!(hhea.min_right_side_bearing, FWord::new(600));
+    }
+
+    #[test]
+    fn glyphs_without_bounds_keep_side_bearings() {
+        let data = test_font();
+        let font = FontRef::new(&data).unwrap();
+
+        let (hmtx, hhea) = rebuild_hmtx(&font, &[None; 3], &FxHashMap::default()).unwrap();
+
+        assert_eq!(
+            hmtx.h_metrics,
+            [LongMetric::new(500, 10), LongMetric::new(600, 20)]
+        );
+        assert_eq!(hmtx.left_side_bearings, [30]);
+        // Extents are only recomputed from bounds
+        assert_eq!(hhea.min_left_side_bearing, FWord::new(10));
+        assert_eq!(hhea.min_right_side_bearing, FWord::new(50));
+        assert_eq!(hhea.x_max_extent, FWord::new(550));
+    }
+
+    #[test]
+    fn head_bounds_are_the_union() {
+        let mut head = Head::default();
+
+        update_head_bounds(
+            &mut head,
+            &[None, Some(bounds(-5, 700)), Some(bounds(40, 900))],
+        );
+
+        assert_eq!(
+            (head.x_min, head.y_min, head.x_max, head.y_max),
+            (-5, -10, 900, 100)
+        );
+
+        update_head_bounds(&mut head, &[None, None]);
+
+        assert_eq!((head.x_min, head.x_max), (-5, 900));
+    }
+
+    const RUBY: GlyphBounds = GlyphBounds {
+        x_min: 0,
+        y_min: -300,
+        x_max: 100,
+        y_max: 1000,
+    };
+
+    fn line_metrics(fs_selection: SelectionFlags) -> (Hhea, Os2) {
+        let hhea = Hhea {
+            ascender: FWord::new(800),
+            descender: FWord::new(-200),
+            ..Default::default()
+        };
+        let os2 = Os2 {
+            s_typo_ascender: 800,
+            s_typo_descender: -200,
+            us_win_ascent: 900,
+            us_win_descent: 250,
+            fs_selection,
+            ..Default::default()
+        };
+
+        (hhea, os2)
+    }
+
+    /// hhea ascender and descender, typo ascender and descender, then win ascent and descent.
+    fn vertical(hhea: &Hhea, os2: &Os2) -> [i32; 6] {
+        [
+            hhea.ascender.to_i16() as i32,
+            hhea.descender.to_i16() as i32,
+            os2.s_typo_ascender as i32,
+            os2.s_typo_descender as i32,
+            os2.us_win_ascent as i32,
+            os2.us_win_descent as i32,
+        ]
+    }
+
+    #[test]
+    fn keep_leaves_metrics_alone() {
+        let (mut hhea, mut os2) = line_metrics(SelectionFlags::USE_TYPO_METRICS);
+
+        apply_vertical_metrics(VerticalMetrics::Keep, RUBY, &mut hhea, Some(&mut os2));
+
+        assert_eq!(vertical(&hhea, &os2), [800, -200, 800, -200, 900, 250]);
+    }
+
+    #[test]
+    fn grow_only_touches_typo_metrics_when_used() {
+        let (mut hhea, mut os2) = line_metrics(SelectionFlags::empty());
+
+        apply_vertical_metrics(VerticalMetrics::Grow, RUBY, &mut hhea, Some(&mut os2));
+
+        assert_eq!(vertical(&hhea, &os2), [1000, -300, 800, -200, 1000, 300]);
+
+        let (mut hhea, mut os2) = line_metrics(SelectionFlags::USE_TYPO_METRICS);
+
+        apply_vertical_metrics(VerticalMetrics::Grow, RUBY, &mut hhea, Some(&mut os2));
+
+        assert_eq!(vertical(&hhea, &os2), [1000, -300, 1000, -300, 1000, 300]);
+    }
+
+    #[test]
+    fn grow_win_only_touches_win_metrics() {
+        let (mut hhea, mut os2) = line_metrics(SelectionFlags::empty());
+
+        apply_vertical_metrics(VerticalMetrics::GrowWin, RUBY, &mut hhea, Some(&mut os2));
+
+        assert_eq!(vertical(&hhea, &os2), [800, -200, 800, -200, 1000, 300]);
+    }
+
+    #[test]
+    fn metrics_never_shrink() {
+        let small = GlyphBounds {
+            x_min: 0,
+            y_min: -100,
+            x_max: 100,
+            y_max: 500,
+        };
+        let (mut hhea, mut os2) = line_metrics(SelectionFlags::USE_TYPO_METRICS);
+
+        apply_vertical_metrics(VerticalMetrics::Grow, small, &mut hhea, Some(&mut os2));
+
+        assert_eq!(vertical(&hhea, &os2), [800, -200, 800, -200, 900, 250]);
+    }
+
+    #[test]
+    fn without_os2_only_grow_changes_hhea() {
+        let (mut hhea, _) = line_metrics(SelectionFlags::empty());
+
+        apply_vertical_metrics(VerticalMetrics::GrowWin, RUBY, &mut hhea, None);
+
+        assert_eq!(
+            (hhea.ascender, hhea.descender),
+            (FWord::new(800), FWord::new(-200))
+        );
+
+        apply_vertical_metrics(VerticalMetrics::Grow, RUBY, &mut hhea, None);
+
+        assert_eq!(
+            (hhea.ascender, hhea.descender),
+            (FWord::new(1000), FWord::new(-300))
+        );
+    }
+}
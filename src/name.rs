@@ -0,0 +1,381 @@
+use anyhow::{Context, Result, bail};
+use fontcull_font_types::{Fixed, NameId};
+use fontcull_read_fonts::{
+    FontRef, TableProvider,
+    tables::name::{Encoding, MacRomanMapping},
+};
+use fontcull_skrifa::MetadataProvider;
+use fontcull_write_fonts::{
+    OffsetMarker,
+    tables::name::{Name, NameRecord},
+};
+
+const PLATFORM_WINDOWS: u16 = 3;
+const WINDOWS_UNICODE_BMP: u16 = 1;
+const WINDOWS_ENGLISH_US: u16 = 0x409;
+
+/// PostScript names are limited to 63 characters.
+const MAX_POSTSCRIPT_LEN: usize = 63;
+
+/// Returns the English (or first available) string for `name_id`.
+pub fn english_name(font: &FontRef, name_id: NameId) -> Option<String> {
+    font.localized_strings(name_id)
+        .english_or_first()
+        .map(|name| name.to_string())
+}
+
+/// Whether `value` can be stored in a record with the platform and encoding of `record`.
+fn can_encode(record: &NameRecord, value: &str) -> bool {
+    match Encoding::new(record.platform_id, record.encoding_id) {
+        Encoding::Utf16Be => true,
+        Encoding::MacRoman => value.chars().all(|ch| MacRomanMapping.encode(ch).is_some()),
+        Encoding::Unknown => false,
+    }
+}
+
+/// Rewrite every record of `name_id` from its own string, so localized records stay in
+/// their language. Records whose encoding cannot hold the new string are dropped, and a
+/// Windows English `fallback` record is added if none remain.
+pub fn rewrite_name(
+    name: &mut Name,
+    name_id: NameId,
+    fallback: &str,
+    rewrite: impl Fn(&NameRecord) -> String,
+) {
+    name.name_record.retain_mut(|record| {
+        if record.name_id != name_id {
+            return true;
+        }
+
+        let value = rewrite(record);
+
+        if !can_encode(record, &value) {
+            return false;
+        }
+
+        record.string = OffsetMarker::new(value);
+
+        true
+    });
+
+    if !name
+        .name_record
+        .iter()
+        .any(|record| record.name_id == name_id)
+    {
+        name.name_record.push(NameRecord::new(
+            PLATFORM_WINDOWS,
+            WINDOWS_UNICODE_BMP,
+            WINDOWS_ENGLISH_US,
+            name_id,
+            OffsetMarker::new(fallback.to_string()),
+        ));
+    }
+
+    // Records must be sorted by platform, encoding, language and name ID
+    name.name_record.sort();
+}
+
+/// Set every record of `name_id` to `value`, adding a Windows English record if none exist.
+pub fn set_name(name: &mut Name, name_id: NameId, value: &str) {
+    rewrite_name(name, name_id, value, |_| value.to_string());
+}
+
+/// The string of `name_id` in the same platform, encoding and language as `record`.
+fn sibling(name: &Name, record: &NameRecord, name_id: NameId) -> Option<String> {
+    name.name_record
+        .iter()
+        .find(|other| {
+            other.name_id == name_id
+                && other.platform_id == record.platform_id
+                && other.encoding_id == record.encoding_id
+                && other.language_id == record.language_id
+        })
+        .map(|other| other.string.as_str().to_string())
+}
+
+/// How output fonts are renamed, so they install side by side with the originals.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Rename {
+    /// Append the renderer's name to the family, e.g. "Sarasa Gothic SC Pinyin"
+    #[default]
+    Renderer,
+    /// Keep the original names
+    Keep,
+    /// Append a suffix to the family, e.g. "Sarasa Gothic SC" to "Sarasa Gothic SC Pinyin"
+    Suffix(String),
+    /// Replace the family
+    Family(String),
+}
+
+/// The names of a renamed font.
+pub struct Renamed {
+    /// The new typographic family
+    pub family: String,
+    /// The new PostScript name, also used as the CFF FontName
+    pub postscript: String,
+    /// The bumped `head.fontRevision`
+    pub revision: Fixed,
+}
+
+/// Strip the characters PostScript names cannot contain.
+fn postscript_safe(name: &str) -> String {
+    name.chars()
+        .filter(|ch| ch.is_ascii_graphic() && !"[](){}<>/%".contains(*ch))
+        .collect()
+}
+
+/// Rename the family of `font` in name IDs 1, 3, 4, 6, 16 and 17 of its `name` table, and
+/// bump its version in name ID 5 and the returned `head.fontRevision`. Returns `None` if
+/// names are kept. [`Rename::Renderer`] must already be resolved to a suffix.
+pub fn rename(font: &FontRef, name: &mut Name, rename: &Rename) -> Result<Option<Renamed>> {
+    if *rename == Rename::Keep {
+        return Ok(None);
+    }
+
+    let family = english_name(font, NameId::TYPOGRAPHIC_FAMILY_NAME)
+        .or_else(|| english_name(font, NameId::FAMILY_NAME))
+        .context("Missing family name")?;
+    let subfamily = english_name(font, NameId::TYPOGRAPHIC_SUBFAMILY_NAME)
+        .or_else(|| english_name(font, NameId::SUBFAMILY_NAME))
+        .unwrap_or_else(|| "Regular".to_string());
+
+    let new_family = match rename {
+        Rename::Suffix(suffix) => format!("{family} {suffix}"),
+        Rename::Family(new_family) => new_family.clone(),
+        Rename::Keep | Rename::Renderer => bail!("Unresolved rename {rename:?}"),
+    };
+
+    // Names starting with the family keep the rest (usually the style) after the new family
+    let rebase = |name_id: NameId| {
+        english_name(font, name_id).and_then(|name| {
+            name.strip_prefix(&family)
+                .map(|style| format!("{new_family}{style}"))
+        })
+    };
+
+    let legacy_family = rebase(NameId::FAMILY_NAME).unwrap_or_else(|| new_family.clone());
+    let full_name =
+        rebase(NameId::FULL_NAME).unwrap_or_else(|| format!("{new_family} {subfamily}"));
+
+    let old_postscript = english_name(font, NameId::POSTSCRIPT_NAME);
+    let style = old_postscript
+        .as_deref()
+        .and_then(|postscript| postscript.split_once('-'))
+        .map(|(_, style)| style.to_string())
+        .unwrap_or_else(|| postscript_safe(&subfamily));
+
+    let mut postscript = format!("{}-{style}", postscript_safe(&new_family));
+    postscript.truncate(MAX_POSTSCRIPT_LEN);
+
+    let unique_id = match (english_name(font, NameId::UNIQUE_ID), &old_postscript) {
+        (Some(unique_id), Some(old)) if unique_id.contains(old.as_str()) => {
+            unique_id.replace(old.as_str(), &postscript)
+        }
+        _ => postscript.clone(),
+    };
+
+    // Bump the revision by the smallest step the version string shows
+    let revision = font.head()?.font_revision().to_f64();
+    let revision = ((revision * 1000.0).round() + 1.0) / 1000.0;
+
+    // Keep anything after the version number, such as build notes
+    let version_rest = english_name(font, NameId::VERSION_STRING)
+        .and_then(|version| {
+            version
+                .strip_prefix("Version ")
+                .map(|rest| rest.trim_start_matches(|ch: char| ch.is_ascii_digit() || ch == '.'))
+                .map(str::to_string)
+        })
+        .unwrap_or_default();
+
+    // Every record is renamed from its own language's family and style
+    let original = name.clone();
+    let family_of = |record: &NameRecord| {
+        sibling(&original, record, NameId::TYPOGRAPHIC_FAMILY_NAME)
+            .or_else(|| sibling(&original, record, NameId::FAMILY_NAME))
+            .unwrap_or_else(|| family.clone())
+    };
+    let subfamily_of = |record: &NameRecord| {
+        sibling(&original, record, NameId::TYPOGRAPHIC_SUBFAMILY_NAME)
+            .or_else(|| sibling(&original, record, NameId::SUBFAMILY_NAME))
+            .unwrap_or_else(|| subfamily.clone())
+    };
+    // A suffix is appended in every language, while a replaced family is used as given
+    let renamed_family = |old: &str| match rename {
+        Rename::Suffix(suffix) => format!("{old} {suffix}"),
+        _ => new_family.clone(),
+    };
+    let rebase_record = |record: &NameRecord| {
+        let old = family_of(record);
+        let new = renamed_family(&old);
+
+        match record.string.strip_prefix(old.as_str()) {
+            Some(style) => format!("{new}{style}"),
+            None if record.name_id == NameId::FULL_NAME => {
+                format!("{new} {}", subfamily_of(record))
+            }
+            None => new,
+        }
+    };
+    let version_string = format!("Version {revision:.3}{version_rest}");
+    let version = |version: &str| match version.strip_prefix("Version ") {
+        Some(rest) => {
+            let rest = rest.trim_start_matches(|ch: char| ch.is_ascii_digit() || ch == '.');
+
+            format!("Version {revision:.3}{rest}")
+        }
+        None => version_string.clone(),
+    };
+
+    rewrite_name(name, NameId::FAMILY_NAME, &legacy_family, rebase_record);
+    rewrite_name(
+        name,
+        NameId::UNIQUE_ID,
+        &unique_id,
+        |record| match &old_postscript {
+            Some(old) if record.string.contains(old.as_str()) => {
+                record.string.replace(old.as_str(), &postscript)
+            }
+            _ => unique_id.clone(),
+        },
+    );
+    rewrite_name(name, NameId::FULL_NAME, &full_name, rebase_record);
+    rewrite_name(name, NameId::VERSION_STRING, &version_string, |record| {
+        version(record.string.as_str())
+    });
+    set_name(name, NameId::POSTSCRIPT_NAME, &postscript);
+    rewrite_name(
+        name,
+        NameId::TYPOGRAPHIC_FAMILY_NAME,
+        &new_family,
+        rebase_record,
+    );
+    // The style is unchanged, so existing records are kept as they are
+    rewrite_name(
+        name,
+        NameId::TYPOGRAPHIC_SUBFAMILY_NAME,
+        &subfamily,
+        |record| record.string.as_str().to_string(),
+    );
+
+    Ok(Some(Renamed {
+        family: new_family,
+        postscript,
+        revision: Fixed::from_f64(revision),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_write_fonts::FontBuilder;
+
+    use super::*;
+
+    const WINDOWS_JAPANESE: u16 = 0x411;
+
+    fn record(platform_id: u16, encoding_id: u16, language_id: u16, value: &str) -> NameRecord {
+        NameRecord::new(
+            platform_id,
+            encoding_id,
+            language_id,
+            NameId::FAMILY_NAME,
+            OffsetMarker::new(value.to_string()),
+        )
+    }
+
+    fn strings(name: &Name) -> Vec<(u16, u16, &str)> {
+        name.name_record
+            .iter()
+            .map(|record| {
+                (
+                    record.platform_id,
+                    record.language_id,
+                    record.string.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_each_record_from_its_own_string() {
+        let mut name = Name::new(vec![
+            record(
+                PLATFORM_WINDOWS,
+                WINDOWS_UNICODE_BMP,
+                WINDOWS_JAPANESE,
+                "源ノ角ゴシック",
+            ),
+            record(1, 0, 0, "Source Han Sans"),
+            record(
+                PLATFORM_WINDOWS,
+                WINDOWS_UNICODE_BMP,
+                WINDOWS_ENGLISH_US,
+                "Source Han Sans",
+            ),
+        ]);
+
+        rewrite_name(&mut name, NameId::FAMILY_NAME, "unused", |record| {
+            format!("{} Pinyin", record.string.as_str())
+        });
+
+        assert_eq!(
+            strings(&name),
+            [
+                (1, 0, "Source Han Sans Pinyin"),
+                (
+                    PLATFORM_WINDOWS,
+                    WINDOWS_ENGLISH_US,
+                    "Source Han Sans Pinyin"
+                ),
+                (PLATFORM_WINDOWS, WINDOWS_JAPANESE, "源ノ角ゴシック Pinyin"),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_records_that_cannot_hold_the_new_string() {
+        let mut name = Name::new(vec![
+            record(1, 0, 0, "Sans"),
+            record(
+                PLATFORM_WINDOWS,
+                WINDOWS_UNICODE_BMP,
+                WINDOWS_ENGLISH_US,
+                "Sans",
+            ),
+        ]);
+
+        // Mac Roman has no CJK characters
+        set_name(&mut name, NameId::FAMILY_NAME, "Sans 拼音");
+
+        assert_eq!(
+            strings(&name),
+            [(PLATFORM_WINDOWS, WINDOWS_ENGLISH_US, "Sans 拼音")]
+        );
+    }
+
+    #[test]
+    fn adds_fallback_when_no_records_exist() {
+        let mut name = Name::new(vec![record(1, 0, 0, "Sans")]);
+
+        set_name(&mut name, NameId::TYPOGRAPHIC_FAMILY_NAME, "Sans Pinyin");
+
+        assert_eq!(name.name_record.len(), 2);
+        assert!(name.name_record.iter().any(|record| {
+            record.name_id == NameId::TYPOGRAPHIC_FAMILY_NAME
+                && record.platform_id == PLATFORM_WINDOWS
+                && record.string.as_str() == "Sans Pinyin"
+        }));
+    }
+
+    #[test]
+    fn keeping_names_reads_nothing() {
+        // A font without any names
+        let data = FontBuilder::new().build();
+        let font = FontRef::new(&data).unwrap();
+        let mut name = Name::new(Vec::new());
+
+        assert!(rename(&font, &mut name, &Rename::Keep).unwrap().is_none());
+        assert!(name.name_record.is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use anyhow::{Context, Result, bail};
+use fontcull_font_types::NameId;
+use fontcull_read_fonts::{FileRef, TableProvider};
+
+use crate::{font_extension, name};
+
+/// A value substituted into a [`NameTemplate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Stem,
+    Index,
+    PostScript,
+    Family,
+    Weight,
+    Renderer,
+    Extension,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stem" => Some(Placeholder::Stem),
+            "index" => Some(Placeholder::Index),
+            "postscript" => Some(Placeholder::PostScript),
+            "family" => Some(Placeholder::Family),
+            "weight" => Some(Placeholder::Weight),
+            "renderer" => Some(Placeholder::Renderer),
+            "ext" => Some(Placeholder::Extension),
+            _ => None,
+        }
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// An output file name template, such as `{stem}-{postscript}.{ext}`.
+///
+/// Placeholders are `{stem}` (input file stem), `{index}` (face index in the input),
+/// `{postscript}`, `{family}`, `{weight}` (OS/2 weight class), `{renderer}` and `{ext}`
+/// (output extension). `{{` and `}}` are literal braces.
+pub struct NameTemplate {
+    segments: Vec<Segment>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .with_context(|| format!("Unclosed placeholder in {template:?}"))?;
+
+                    let placeholder = Placeholder::from_name(&rest[..end]).with_context(|| {
+                        format!("Unknown placeholder {{{}}} in {template:?}", &rest[..end])
+                    })?;
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    segments.push(Segment::Placeholder(placeholder));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => bail!("Unmatched '}}' in {template:?}"),
+                ch => literal.push(ch),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Render the file name for `fields`, which must stay a plain relative path: empty,
+    /// `.` and `..` components and control characters are rejected.
+    pub fn render(&self, fields: &NameFields) -> Result<String> {
+        let name: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.clone(),
+                Segment::Placeholder(placeholder) => match placeholder {
+                    Placeholder::Stem => sanitize(&fields.stem),
+                    Placeholder::Index => fields.index.to_string(),
+                    Placeholder::PostScript => sanitize(&fields.postscript),
+                    Placeholder::Family => sanitize(&fields.family),
+                    Placeholder::Weight => fields.weight.to_string(),
+                    Placeholder::Renderer => sanitize(&fields.renderer),
+                    Placeholder::Extension => fields.extension.clone(),
+                },
+            })
+            .collect();
+
+        if name.chars().any(char::is_control) {
+            bail!("Output name {name:?} contains control characters");
+        }
+
+        if name
+            .split(['/', '\\'])
+            .any(|component| matches!(component, "" | "." | ".."))
+        {
+            bail!("Output name {name:?} is not a plain relative path");
+        }
+
+        Ok(name)
+    }
+}
+
+/// The values a [`NameTemplate`] is rendered with for one output font. All but the
+/// numbers are sanitized when rendered, so none of them can add path components.
+pub struct NameFields {
+    pub stem: String,
+    pub index: usize,
+    pub postscript: String,
+    pub family: String,
+    pub weight: u16,
+    pub renderer: String,
+    pub extension: String,
+}
+
+/// Names can contain characters that are not allowed in file names, or be `.` or `..`.
+pub(crate) fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|ch| match ch {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            ch if ch.is_control() => '_',
+            ch => ch,
+        })
+        .collect();
+
+    match name.as_str() {
+        "." | ".." => "_".repeat(name.len()),
+        _ => name,
+    }
+}
+
+impl NameFields {
+    /// Read the font fields from sfnt `data`, taking names from the first face of
+    /// collections. Without a container `extension`, the sfnt extension is used.
+    pub fn from_font(
+        data: &[u8],
+        stem: &str,
+        index: usize,
+        renderer: &str,
+        extension: Option<&str>,
+    ) -> Result<Self> {
+        let file = FileRef::new(data).context("Failed to parse font for naming")?;
+        let font = file
+            .fonts()
+            .next()
+            .context("Font has no faces")?
+            .context("Failed to read font for naming")?;
+
+        let extension = match (extension, &file) {
+            (Some(extension), _) => extension,
+            (None, FileRef::Collection(_)) => "ttc",
+            (None, FileRef::Font(_)) => font_extension(&font),
+        };
+
+        let family = name::english_name(&font, NameId::TYPOGRAPHIC_FAMILY_NAME)
+            .or_else(|| name::english_name(&font, NameId::FAMILY_NAME))
+            .unwrap_or_default();
+        let postscript = name::english_name(&font, NameId::POSTSCRIPT_NAME)
+            .unwrap_or_else(|| format!("{family}-{index}").replace(' ', ""));
+
+        Ok(Self {
+            stem: stem.to_string(),
+            index,
+            postscript,
+            family,
+            weight: font.os2().map(|os2| os2.us_weight_class()).unwrap_or(400),
+            renderer: renderer.to_string(),
+            extension: extension.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> NameFields {
+        NameFields {
+            stem: "Sarasa-Regular".to_string(),
+            index: 2,
+            postscript: "Sarasa-Gothic-SC".to_string(),
+            family: "Sarasa Gothic SC".to_string(),
+            weight: 400,
+            renderer: "pinyin".to_string(),
+            extension: "ttf".to_string(),
+        }
+    }
+
+    fn parse_error(template: &str) -> String {
+        match NameTemplate::parse(template) {
+            Ok(_) => panic!("{template:?} should not parse"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_every_placeholder() {
+        let template =
+            NameTemplate::parse("{stem}_{index}_{postscript}_{family}_{weight}_{renderer}.{ext}")
+                .unwrap();
+
+        assert_eq!(
+            template.render(&fields()).unwrap(),
+            "Sarasa-Regular_2_Sarasa-Gothic-SC_Sarasa Gothic SC_400_pinyin.ttf"
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let render = |template: &str| {
+            NameTemplate::parse(template)
+                .unwrap()
+                .render(&fields())
+                .unwrap()
+        };
+
+        assert_eq!(render("{{stem}}"), "{stem}");
+        assert_eq!(render("{{{index}}}"), "{2}");
+        assert_eq!(render("plain"), "plain");
+        assert_eq!(render("plain.{ext}"), "plain.ttf");
+    }
+
+    #[test]
+    fn rejects_unclosed_placeholders() {
+        assert!(parse_error("{stem").starts_with("Unclosed placeholder"));
+        assert!(parse_error("{stem}-{").starts_with("Unclosed placeholder"));
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!(parse_error("{name}").starts_with("Unknown placeholder {name}"));
+        assert!(parse_error("{}").starts_with("Unknown placeholder {}"));
+        // Placeholder names are case-sensitive
+        assert!(parse_error("{Stem}").starts_with("Unknown placeholder {Stem}"));
+    }
+
+    #[test]
+    fn rejects_unmatched_closing_braces() {
+        assert!(parse_error("stem}").starts_with("Unmatched '}'"));
+        assert!(parse_error("{ext}}").starts_with("Unmatched '}'"));
+    }
+
+    #[test]
+    fn sanitizes_path_separators() {
+        assert_eq!(sanitize("A/B\\C:D"), "A_B_C_D");
+        assert_eq!(sanitize("Sarasa Gothic"), "Sarasa Gothic");
+    }
+
+    #[test]
+    fn sanitizes_dot_names_and_control_characters() {
+        assert_eq!(sanitize(".."), "__");
+        assert_eq!(sanitize("."), "_");
+        assert_eq!(sanitize("..Sarasa"), "..Sarasa");
+        assert_eq!(sanitize("Sarasa\nGothic\0"), "Sarasa_Gothic_");
+    }
+
+    #[test]
+    fn rejects_names_that_leave_the_out_dir() {
+        let render_error = |template: &str, fields: &NameFields| match NameTemplate::parse(template)
+            .unwrap()
+            .render(fields)
+        {
+            Ok(name) => panic!("{template:?} should not render, got {name:?}"),
+            Err(err) => err.to_string(),
+        };
+
+        assert!(render_error("", &fields()).contains("not a plain relative path"));
+        assert!(render_error("../{stem}", &fields()).contains("not a plain relative path"));
+        assert!(render_error("/{stem}", &fields()).contains("not a plain relative path"));
+        assert!(render_error("a\tb", &fields()).contains("control characters"));
+
+        // Font-derived fields are sanitized rather than rejected
+        let fields = NameFields {
+            stem: "..".to_string(),
+            postscript: "..".to_string(),
+            family: "a/../b".to_string(),
+            ..fields()
+        };
+        let name = NameTemplate::parse("{stem}-{postscript}-{family}")
+            .unwrap()
+            .render(&fields)
+            .unwrap();
+        assert_eq!(name, "__-__-a_.._b");
+    }
+}
@@ -0,0 +1,272 @@
+use std::mem::discriminant;
+
+use kurbo::{BezPath, CubicBez, ParamCurve, PathEl, Point, QuadBez};
+
+/// Convert every cubic segment in `path` into quadratic segments.
+/// `tolerance` is the maximum allowed deviation in font units.
+pub fn cubic_to_quadratic(path: &BezPath, tolerance: f64) -> BezPath {
+    let mut out = BezPath::new();
+
+    let mut start = Point::ZERO;
+    let mut current = Point::ZERO;
+
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => {
+                out.move_to(p);
+                start = p;
+                current = p;
+            }
+            PathEl::LineTo(p) => {
+                out.line_to(p);
+                current = p;
+            }
+            PathEl::QuadTo(p1, p2) => {
+                out.quad_to(p1, p2);
+                current = p2;
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                let cubic = CubicBez::new(current, p1, p2, p3);
+
+                // Prefer a single quadratic spline, which shares implied on-curve points
+                // and produces fewer points than uniform subdivision.
+                match cubic.approx_spline(tolerance) {
+                    Some(spline) => {
+                        for quad in spline.to_quads() {
+                            out.quad_to(quad.p1, quad.p2);
+                        }
+                    }
+                    None => {
+                        for (_, _, quad) in cubic.to_quads(tolerance) {
+                            out.quad_to(quad.p1, quad.p2);
+                        }
+                    }
+                }
+
+                current = p3;
+            }
+            PathEl::ClosePath => {
+                out.close_path();
+                current = start;
+            }
+        }
+    }
+
+    out
+}
+
+/// Returns true if `path` contains any cubic segments.
+pub fn has_cubics(path: &BezPath) -> bool {
+    path.elements()
+        .iter()
+        .any(|el| matches!(el, PathEl::CurveTo(..)))
+}
+
+/// Convert cubic segments in a set of interpolation-compatible paths (one per variation
+/// master), splitting each cubic into the same number of quadratics in every path so the
+/// results stay point-compatible. Returns `None` if the paths differ in structure.
+pub fn cubic_to_quadratic_compatible(paths: &[BezPath], tolerance: f64) -> Option<Vec<BezPath>> {
+    let len = paths.first()?.elements().len();
+
+    if paths.iter().any(|path| path.elements().len() != len) {
+        return None;
+    }
+
+    let mut out = vec![BezPath::new(); paths.len()];
+    let mut starts = vec![Point::ZERO; paths.len()];
+    let mut currents = vec![Point::ZERO; paths.len()];
+
+    for idx in 0..len {
+        let els = paths
+            .iter()
+            .map(|path| path.elements()[idx])
+            .collect::<Vec<PathEl>>();
+
+        if els
+            .iter()
+            .any(|el| discriminant(el) != discriminant(&els[0]))
+        {
+            return None;
+        }
+
+        if let PathEl::CurveTo(..) = els[0] {
+            let cubics = els
+                .iter()
+                .zip(&currents)
+                .map(|(el, &current)| match *el {
+                    PathEl::CurveTo(p1, p2, p3) => CubicBez::new(current, p1, p2, p3),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<CubicBez>>();
+
+            // The master that needs the most segments decides for all of them
+            let count = cubics
+                .iter()
+                .map(|cubic| cubic.to_quads(tolerance).count())
+                .max()
+                .unwrap_or(1);
+
+            for (out, cubic) in out.iter_mut().zip(&cubics) {
+                for quad in split_cubic(cubic, count) {
+                    out.quad_to(quad.p1, quad.p2);
+                }
+            }
+        } else {
+            for (out, el) in out.iter_mut().zip(&els) {
+                out.push(*el);
+            }
+        }
+
+        for ((el, start), current) in els.iter().zip(&mut starts).zip(&mut currents) {
+            match *el {
+                PathEl::MoveTo(p) => {
+                    *start = p;
+                    *current = p;
+                }
+                PathEl::LineTo(p) | PathEl::QuadTo(_, p) | PathEl::CurveTo(_, _, p) => {
+                    *current = p;
+                }
+                PathEl::ClosePath => *current = *start,
+            }
+        }
+    }
+
+    Some(out)
+}
+
+/// Split `cubic` into `count` equal parts, each approximated by a single quadratic.
+fn split_cubic(cubic: &CubicBez, count: usize) -> impl Iterator<Item = QuadBez> + '_ {
+    (0..count).map(move |idx| {
+        let part = cubic.subsegment(idx as f64 / count as f64..(idx + 1) as f64 / count as f64);
+        let ctrl = (part.p1.to_vec2() * 3.0 - part.p0.to_vec2() + part.p2.to_vec2() * 3.0
+            - part.p3.to_vec2())
+            / 4.0;
+
+        QuadBez::new(part.p0, ctrl.to_point(), part.p3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_write_fonts::tables::glyf::SimpleGlyph;
+    use kurbo::ParamCurveNearest;
+
+    use super::*;
+
+    /// Curvatures `contour` is tested at, from flattened to exaggerated.
+    const CURVATURES: [f64; 3] = [0.6, 1.0, 1.4];
+
+    /// A closed contour with a line and two cubics, scaled and skewed by `k`.
+    fn contour(k: f64) -> BezPath {
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((600.0, 0.0));
+        path.curve_to((700.0 * k, 200.0), (500.0, 500.0 * k), (300.0, 600.0));
+        path.curve_to((100.0, 700.0), (-100.0 * k, 300.0), (0.0, 0.0));
+        path.close_path();
+        path
+    }
+
+    /// Cubics of `path`, with their start points filled in.
+    fn cubics(path: &BezPath) -> Vec<CubicBez> {
+        path.segments()
+            .filter_map(|seg| match seg {
+                kurbo::PathSeg::Cubic(cubic) => Some(cubic),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The largest distance from points sampled along the cubics of `original` to the
+    /// closest segment of `converted`.
+    fn max_error(original: &BezPath, converted: &BezPath) -> f64 {
+        cubics(original)
+            .iter()
+            .flat_map(|cubic| (0..=100).map(|idx| cubic.eval(idx as f64 / 100.0)))
+            .map(|point| {
+                converted
+                    .segments()
+                    .map(|seg| seg.nearest(point, 1e-9).distance_sq)
+                    .fold(f64::INFINITY, f64::min)
+                    .sqrt()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn conversion_stays_within_tolerance() {
+        for k in CURVATURES {
+            let path = contour(k);
+
+            for tolerance in [0.1, 1.0, 5.0] {
+                let converted = cubic_to_quadratic(&path, tolerance);
+
+                assert!(!has_cubics(&converted));
+                // Errors are measured at sample points, so allow a little slack
+                assert!(
+                    max_error(&path, &converted) <= tolerance * 1.1,
+                    "curvature {k}, tolerance {tolerance}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tighter_tolerance_adds_segments() {
+        for k in CURVATURES {
+            let path = contour(k);
+            let count = |tolerance| cubic_to_quadratic(&path, tolerance).elements().len();
+
+            assert!(count(0.1) > count(10.0), "curvature {k}");
+        }
+    }
+
+    #[test]
+    fn compatible_conversion_matches_across_masters() {
+        let masters = CURVATURES.map(contour);
+        let tolerance = 1.0;
+
+        let converted = cubic_to_quadratic_compatible(&masters, tolerance).unwrap();
+
+        for (master, converted) in masters.iter().zip(&converted) {
+            assert!(!has_cubics(converted));
+            assert!(max_error(master, converted) <= tolerance * 1.1);
+        }
+
+        let glyphs = SimpleGlyph::interpolatable_glyphs_from_bezpaths(&converted).unwrap();
+        let flags = |glyph: &SimpleGlyph| {
+            glyph
+                .contours
+                .iter()
+                .map(|contour| {
+                    contour
+                        .iter()
+                        .map(|point| point.on_curve)
+                        .collect::<Vec<bool>>()
+                })
+                .collect::<Vec<Vec<bool>>>()
+        };
+
+        for glyph in &glyphs[1..] {
+            assert_eq!(flags(glyph), flags(&glyphs[0]));
+        }
+    }
+
+    #[test]
+    fn compatible_conversion_rejects_mismatched_masters() {
+        let mut extra = contour(1.0);
+        extra.line_to((10.0, 10.0));
+
+        assert!(cubic_to_quadratic_compatible(&[contour(1.0), extra], 1.0).is_none());
+
+        let mut lines = BezPath::new();
+        lines.move_to((0.0, 0.0));
+        lines.line_to((600.0, 0.0));
+        lines.line_to((300.0, 600.0));
+        lines.line_to((0.0, 0.0));
+        lines.close_path();
+
+        assert!(cubic_to_quadratic_compatible(&[contour(1.0), lines], 1.0).is_none());
+        assert!(cubic_to_quadratic_compatible(&[], 1.0).is_none());
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use anyhow::{Context, Result};
+use fontcull_read_fonts::{
+    FontRef, TableProvider,
+    types::{F2Dot14, Tag},
+};
+use kurbo::{Affine, BezPath};
+use rustc_hash::FxHashMap;
+
+use crate::renderer::utils;
+
+/// A ruby string drawn in ruby font units, laid out on one line starting at x = 0.
+pub struct RubyText {
+    /// Each glyph's outline (at its own origin) with its advance
+    pub glyphs: Vec<(BezPath, f64)>,
+    /// All glyphs composed on one line
+    pub path: BezPath,
+    /// Total advance of the line
+    pub width: f64,
+    /// Lowest and highest y of the outlines, or `None` if they have no points
+    pub y_extent: Option<(f64, f64)>,
+}
+
+impl RubyText {
+    /// Draw `text` with the ruby `font`; returns `None` if any character cannot be drawn.
+    fn render(
+        font: &FontRef,
+        upem: f64,
+        text: &str,
+        location: &[(Tag, F2Dot14)],
+    ) -> Result<Option<Self>> {
+        let hmtx = font.hmtx().context("Missing ruby font hmtx")?;
+
+        let Some(glyph_paths) = utils::collect_glyph_paths(font, text.to_string(), location) else {
+            return Ok(None);
+        };
+
+        let mut glyphs = Vec::with_capacity(glyph_paths.len());
+        let mut path = BezPath::new();
+        let mut width = 0.0;
+
+        for (gid, glyph_path) in glyph_paths {
+            // Glyphs past the last long metric share its advance
+            let advance = hmtx.advance(gid).unwrap_or(upem as u16) as f64;
+
+            path.extend(Affine::translate((width, 0.0)) * &glyph_path);
+            width += advance;
+            glyphs.push((glyph_path, advance));
+        }
+
+        Ok(Some(Self {
+            glyphs,
+            y_extent: utils::y_extent(&path),
+            path,
+            width,
+        }))
+    }
+}
+
+type CacheKey = (String, Vec<(Tag, F2Dot14)>);
+
+/// Thread-safe cache of drawn ruby strings, keyed by reading and location.
+///
+/// Many base characters share a reading, so each reading is only drawn once.
+#[derive(Default)]
+pub struct RubyCache {
+    entries: RwLock<FxHashMap<CacheKey, Option<Arc<RubyText>>>>,
+}
+
+impl RubyCache {
+    /// Returns `text` drawn with the ruby `font` at `location`, drawing it on first use.
+    /// Returns `None` if any character cannot be drawn.
+    pub fn get_or_render(
+        &self,
+        font: &FontRef,
+        upem: f64,
+        text: &str,
+        location: &[(Tag, F2Dot14)],
+    ) -> Result<Option<Arc<RubyText>>> {
+        let key = (text.to_string(), location.to_vec());
+
+        if let Some(entry) = self
+            .entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(entry.clone());
+        }
+
+        // Drawing is deterministic, so a race only draws the same text twice
+        let entry = RubyText::render(font, upem, text, location)?.map(Arc::new);
+
+        let entry = self
+            .entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key)
+            .or_insert(entry)
+            .clone();
+
+        Ok(entry)
+    }
+}
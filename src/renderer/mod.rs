@@ -3,28 +3,82 @@ pub mod pinyin;
 #[cfg(feature = "romaji")]
 pub mod romaji;
 
+pub mod cache;
 pub mod utils;
 
 use std::ops::RangeInclusive;
 
 use anyhow::Result;
 use facet::Facet;
+use fontcull_read_fonts::types::{F2Dot14, Tag};
 use kurbo::BezPath;
 
 /// A pluggable renderer that can add "ruby" annotations.
 pub trait RubyRenderer: Send + Sync {
     /// Given a base character `ch`, add annotation paths (if any) into `final_path`.
     /// `orig_advance` is the glyph advance in font units; `main_upem` is the main font UPEM.
+    /// `location` holds the normalized coordinates (by axis tag) of the variable font master
+    /// or named instance being drawn, and is empty at the default location.
+    /// `baseline` is the shared target measured across the whole font at this location.
     fn annotate(
         &self,
         ch: char,
         final_path: &mut BezPath,
         orig_advance: f64,
         main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+        baseline: Option<BaselineTarget>,
     ) -> Result<()>;
 
+    /// Measure the baseline the annotation of `ch` needs to clear `base_path`.
+    /// Returns `None` if `ch` has no annotation or the layout does not use a shared baseline.
+    fn measure(
+        &self,
+        ch: char,
+        base_path: &BezPath,
+        main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+    ) -> Result<Option<BaselineTarget>>;
+
+    /// Whether annotations share a measured baseline. When false, [`measure`] always
+    /// returns `None`, so base glyphs are not drawn to measure them.
+    ///
+    /// [`measure`]: RubyRenderer::measure
+    fn needs_baseline(&self) -> bool {
+        true
+    }
+
     /// Returns the character ranges that this renderer can annotate.
     fn ranges(&self) -> &[RangeInclusive<u32>];
+
+    /// The name of the annotation, e.g. "Pinyin", used as the default family suffix.
+    fn name(&self) -> &str;
+}
+
+/// The baseline (in main font units) an annotation needs to clear its base glyph.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BaselineTarget {
+    /// Annotations above the base glyph need a baseline at or above this y
+    Above(f64),
+    /// Annotations below the base glyph need a baseline at or below this y
+    Below(f64),
+}
+
+impl BaselineTarget {
+    pub fn y(self) -> f64 {
+        match self {
+            BaselineTarget::Above(y) | BaselineTarget::Below(y) => y,
+        }
+    }
+
+    /// Combine two targets into one that satisfies both.
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (BaselineTarget::Above(a), BaselineTarget::Above(b)) => BaselineTarget::Above(a.max(b)),
+            (BaselineTarget::Below(a), BaselineTarget::Below(b)) => BaselineTarget::Below(a.min(b)),
+            (target, _) => target,
+        }
+    }
 }
 
 /// Positioning options for ruby annotations relative to the base glyph.
@@ -1,12 +1,12 @@
-use core::f64;
-
 use ::pinyin::ToPinyin;
-use anyhow::{Context, Result};
-use atomic_float::AtomicF64;
-use fontcull_read_fonts::{FontRef, TableProvider};
+use anyhow::Result;
+use fontcull_read_fonts::{
+    FontRef, TableProvider,
+    types::{F2Dot14, Tag},
+};
 use kurbo::{BezPath, Shape};
 
-use super::{CJK_RANGE, RubyPosition, RubyRenderer, utils};
+use super::{BaselineTarget, CJK_RANGE, RubyPosition, RubyRenderer, cache::RubyCache, utils};
 
 pub struct PinyinRenderer<'a> {
     /// reference for the ruby font
@@ -23,10 +23,8 @@ pub struct PinyinRenderer<'a> {
     baseline_offset_em: f64,
     /// When true, use tight placement; otherwise a consistent baseline is used
     tight: bool,
-    /// Cached consistent top target y (in main font units), computed lazily when placing Top annotations
-    cached_top_target: AtomicF64,
-    /// Cached consistent bottom target y (in main font units), computed lazily when placing Bottom annotations
-    cached_bottom_target: AtomicF64,
+    /// Drawn ruby, shared by every character with the same reading
+    cache: RubyCache,
 }
 
 impl<'a> PinyinRenderer<'a> {
@@ -48,10 +46,14 @@ impl<'a> PinyinRenderer<'a> {
             position,
             baseline_offset_em,
             tight,
-            cached_top_target: AtomicF64::new(f64::NEG_INFINITY),
-            cached_bottom_target: AtomicF64::new(f64::INFINITY),
+            cache: RubyCache::default(),
         })
     }
+
+    /// Returns the toned pinyin reading of `ch`, if it has one.
+    fn reading(ch: char) -> Option<String> {
+        ch.to_pinyin().map(|p| p.with_tone().to_string())
+    }
 }
 
 impl<'a> RubyRenderer for PinyinRenderer<'a> {
@@ -61,84 +63,107 @@ impl<'a> RubyRenderer for PinyinRenderer<'a> {
         final_path: &mut BezPath,
         orig_advance: f64,
         main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+        baseline: Option<BaselineTarget>,
     ) -> Result<()> {
-        if let Some(p) = ch.to_pinyin() {
-            let pinyin_text = p.with_tone().to_string();
-
-            let hmtx = self.font.hmtx().context("Missing pinyin font hmtx")?;
-
-            let parts_paths = match utils::collect_glyph_paths(&self.font, pinyin_text) {
-                Some(p) => p,
-                None => return Ok(()),
-            };
-            // scale factor relative to the pinyin font's UPEM
-            let p_scale_factor = (self.scale_ratio * main_upem) / self.upem;
-
-            let parts_widths = utils::compute_glyph_widths(
-                &parts_paths,
-                p_scale_factor,
-                |pgid: fontcull_skrifa::GlyphId| {
-                    hmtx.h_metrics()
-                        .get(pgid.to_u32() as usize)
-                        .map(|m| m.advance.get())
-                        .unwrap_or(self.upem as u16) as f64
-                },
-            );
-
-            match self.position {
-                RubyPosition::Top | RubyPosition::Bottom => {
-                    utils::render_top_bottom(
-                        final_path,
-                        parts_paths,
-                        &parts_widths,
-                        p_scale_factor,
-                        main_upem,
-                        orig_advance,
-                        self.position,
-                        self.gutter_em,
-                        self.baseline_offset_em,
-                        self.tight,
-                        &self.cached_top_target,
-                        &self.cached_bottom_target,
-                        |pgid: fontcull_skrifa::GlyphId| {
-                            hmtx.h_metrics()
-                                .get(pgid.to_u32() as usize)
-                                .map(|m| m.advance.get())
-                                .unwrap_or(self.upem as u16) as f64
-                        },
-                    );
-                }
-                RubyPosition::LeftDown
-                | RubyPosition::LeftUp
-                | RubyPosition::RightDown
-                | RubyPosition::RightUp => {
-                    let bbox = final_path.bounding_box();
-                    let center_y = (bbox.y0 + bbox.y1) / 2.0;
-
-                    utils::render_side(
-                        final_path,
-                        &parts_paths,
-                        p_scale_factor,
-                        main_upem,
-                        orig_advance,
-                        self.position,
-                        self.gutter_em,
-                        center_y,
-                        &mut |pgid: fontcull_skrifa::GlyphId| {
-                            hmtx.h_metrics()
-                                .get(pgid.to_u32() as usize)
-                                .map(|m| m.advance.get())
-                                .unwrap_or(self.upem as u16) as f64
-                        },
-                    );
-                }
+        let Some(text) = Self::reading(ch) else {
+            return Ok(());
+        };
+
+        let Some(ruby) = self
+            .cache
+            .get_or_render(&self.font, self.upem, &text, location)?
+        else {
+            return Ok(());
+        };
+
+        // scale factor relative to the pinyin font's UPEM
+        let p_scale_factor = (self.scale_ratio * main_upem) / self.upem;
+
+        match self.position {
+            RubyPosition::Top | RubyPosition::Bottom => {
+                utils::render_top_bottom(
+                    final_path,
+                    &ruby,
+                    p_scale_factor,
+                    main_upem,
+                    orig_advance,
+                    self.position,
+                    self.gutter_em,
+                    self.baseline_offset_em,
+                    self.tight,
+                    baseline,
+                );
+            }
+            RubyPosition::LeftDown
+            | RubyPosition::LeftUp
+            | RubyPosition::RightDown
+            | RubyPosition::RightUp => {
+                let bbox = final_path.bounding_box();
+                let center_y = (bbox.y0 + bbox.y1) / 2.0;
+
+                utils::render_side(
+                    final_path,
+                    &ruby,
+                    p_scale_factor,
+                    main_upem,
+                    orig_advance,
+                    self.position,
+                    self.gutter_em,
+                    center_y,
+                );
             }
         }
 
         Ok(())
     }
 
+    fn measure(
+        &self,
+        ch: char,
+        base_path: &BezPath,
+        main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+    ) -> Result<Option<BaselineTarget>> {
+        if !self.needs_baseline() {
+            return Ok(None);
+        }
+
+        let Some(text) = Self::reading(ch) else {
+            return Ok(None);
+        };
+
+        let Some(ruby) = self
+            .cache
+            .get_or_render(&self.font, self.upem, &text, location)?
+        else {
+            return Ok(None);
+        };
+
+        // scale factor relative to the pinyin font's UPEM
+        let p_scale_factor = (self.scale_ratio * main_upem) / self.upem;
+
+        Ok(Some(utils::measure_top_bottom(
+            base_path.bounding_box(),
+            &ruby,
+            p_scale_factor,
+            main_upem,
+            self.position,
+            self.gutter_em,
+            self.baseline_offset_em,
+        )))
+    }
+
+    fn needs_baseline(&self) -> bool {
+        // Tight and side annotations are placed per glyph
+        !self.tight && matches!(self.position, RubyPosition::Top | RubyPosition::Bottom)
+    }
+
     fn ranges(&self) -> &[std::ops::RangeInclusive<u32>] {
         &[CJK_RANGE]
     }
+
+    fn name(&self) -> &str {
+        "Pinyin"
+    }
 }
@@ -1,10 +1,15 @@
-use anyhow::{Context, Result};
-use atomic_float::AtomicF64;
-use fontcull_read_fonts::{FontRef, TableProvider};
+use anyhow::Result;
+use fontcull_read_fonts::{
+    FontRef, TableProvider,
+    types::{F2Dot14, Tag},
+};
 use kurbo::{BezPath, Shape};
 use wana_kana::ConvertJapanese;
 
-use super::{CJK_RANGE, HIRAGANA_RANGE, KATAKANA_RANGE, RubyPosition, RubyRenderer, utils};
+use super::{
+    BaselineTarget, CJK_RANGE, HIRAGANA_RANGE, KATAKANA_RANGE, RubyPosition, RubyRenderer,
+    cache::RubyCache, utils,
+};
 
 pub struct RomajiRenderer<'a> {
     /// reference for the ruby font
@@ -21,10 +26,8 @@ pub struct RomajiRenderer<'a> {
     baseline_offset_em: f64,
     /// When true, use tight placement; otherwise a consistent baseline is used
     tight: bool,
-    /// Cached consistent top target y (in main font units), computed lazily when placing Top annotations
-    cached_top_target: AtomicF64,
-    /// Cached consistent bottom target y (in main font units), computed lazily when placing Bottom annotations
-    cached_bottom_target: AtomicF64,
+    /// Drawn ruby, shared by every character with the same reading
+    cache: RubyCache,
 }
 
 impl<'a> RomajiRenderer<'a> {
@@ -46,10 +49,21 @@ impl<'a> RomajiRenderer<'a> {
             position,
             baseline_offset_em,
             tight,
-            cached_top_target: AtomicF64::new(f64::NEG_INFINITY),
-            cached_bottom_target: AtomicF64::new(f64::INFINITY),
+            cache: RubyCache::default(),
         })
     }
+
+    /// Returns the romaji reading of `ch`, if it has one.
+    fn reading(ch: char) -> Option<String> {
+        let kana = ch.to_string();
+        let romaji_text = kana.to_romaji();
+
+        if romaji_text.is_empty() || kana == romaji_text || romaji_text == "-" {
+            return None;
+        }
+
+        Some(romaji_text)
+    }
 }
 
 impl<'a> RubyRenderer for RomajiRenderer<'a> {
@@ -59,40 +73,27 @@ impl<'a> RubyRenderer for RomajiRenderer<'a> {
         final_path: &mut BezPath,
         orig_advance: f64,
         main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+        baseline: Option<BaselineTarget>,
     ) -> Result<()> {
-        let kana = ch.to_string();
-
-        let romaji_text = kana.to_romaji();
-        if romaji_text.is_empty() || kana == romaji_text || romaji_text == "-" {
+        let Some(text) = Self::reading(ch) else {
             return Ok(());
-        }
-
-        let hmtx = self.font.hmtx().context("Missing romaji font hmtx")?;
+        };
 
-        let glyph_paths = match utils::collect_glyph_paths(&self.font, romaji_text) {
-            Some(p) => p,
-            None => return Ok(()),
+        let Some(ruby) = self
+            .cache
+            .get_or_render(&self.font, self.upem, &text, location)?
+        else {
+            return Ok(());
         };
 
         let p_scale_factor = (self.scale_ratio * main_upem) / self.upem;
 
-        let parts_widths = utils::compute_glyph_widths(
-            &glyph_paths,
-            p_scale_factor,
-            |pgid: fontcull_skrifa::GlyphId| {
-                hmtx.h_metrics()
-                    .get(pgid.to_u32() as usize)
-                    .map(|m| m.advance.get())
-                    .unwrap_or(self.upem as u16) as f64
-            },
-        );
-
         match self.position {
             RubyPosition::Top | RubyPosition::Bottom => {
                 utils::render_top_bottom(
                     final_path,
-                    glyph_paths,
-                    &parts_widths,
+                    &ruby,
                     p_scale_factor,
                     main_upem,
                     orig_advance,
@@ -100,14 +101,7 @@ impl<'a> RubyRenderer for RomajiRenderer<'a> {
                     self.gutter_em,
                     self.baseline_offset_em,
                     self.tight,
-                    &self.cached_top_target,
-                    &self.cached_bottom_target,
-                    |pgid: fontcull_skrifa::GlyphId| {
-                        hmtx.h_metrics()
-                            .get(pgid.to_u32() as usize)
-                            .map(|m| m.advance.get())
-                            .unwrap_or(self.upem as u16) as f64
-                    },
+                    baseline,
                 );
             }
             RubyPosition::LeftDown
@@ -119,19 +113,13 @@ impl<'a> RubyRenderer for RomajiRenderer<'a> {
 
                 utils::render_side(
                     final_path,
-                    &glyph_paths,
+                    &ruby,
                     p_scale_factor,
                     main_upem,
                     orig_advance,
                     self.position,
                     self.gutter_em,
                     center_y,
-                    &mut |pgid: fontcull_skrifa::GlyphId| {
-                        hmtx.h_metrics()
-                            .get(pgid.to_u32() as usize)
-                            .map(|m| m.advance.get())
-                            .unwrap_or(self.upem as u16) as f64
-                    },
                 );
             }
         }
@@ -139,7 +127,51 @@ impl<'a> RubyRenderer for RomajiRenderer<'a> {
         Ok(())
     }
 
+    fn measure(
+        &self,
+        ch: char,
+        base_path: &BezPath,
+        main_upem: f64,
+        location: &[(Tag, F2Dot14)],
+    ) -> Result<Option<BaselineTarget>> {
+        if !self.needs_baseline() {
+            return Ok(None);
+        }
+
+        let Some(text) = Self::reading(ch) else {
+            return Ok(None);
+        };
+
+        let Some(ruby) = self
+            .cache
+            .get_or_render(&self.font, self.upem, &text, location)?
+        else {
+            return Ok(None);
+        };
+
+        let p_scale_factor = (self.scale_ratio * main_upem) / self.upem;
+
+        Ok(Some(utils::measure_top_bottom(
+            base_path.bounding_box(),
+            &ruby,
+            p_scale_factor,
+            main_upem,
+            self.position,
+            self.gutter_em,
+            self.baseline_offset_em,
+        )))
+    }
+
+    fn needs_baseline(&self) -> bool {
+        // Tight and side annotations are placed per glyph
+        !self.tight && matches!(self.position, RubyPosition::Top | RubyPosition::Bottom)
+    }
+
     fn ranges(&self) -> &[std::ops::RangeInclusive<u32>] {
         &[CJK_RANGE, HIRAGANA_RANGE, KATAKANA_RANGE]
     }
+
+    fn name(&self) -> &str {
+        "Romaji"
+    }
 }
@@ -0,0 +1,27 @@
+//! Hand-built fonts shared by the table-level tests.
+
+use fontcull_read_fonts::types::Tag;
+use fontcull_write_fonts::FontBuilder;
+
+/// A 54-byte version 1.0 `head` table with `revision` and a `checksumAdjustment` of
+/// `adjustment`.
+pub fn head(revision: u32, adjustment: u32) -> Vec<u8> {
+    let mut head = vec![0; 54];
+    head[..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    head[4..8].copy_from_slice(&revision.to_be_bytes());
+    head[8..12].copy_from_slice(&adjustment.to_be_bytes());
+    // magicNumber
+    head[12..16].copy_from_slice(&0x5F0F_3CF5u32.to_be_bytes());
+    head
+}
+
+/// An sfnt holding `tables` as-is.
+pub fn font(tables: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut builder = FontBuilder::new();
+
+    for &(tag, data) in tables {
+        builder.add_raw(Tag::new(tag), data.to_vec());
+    }
+
+    builder.build()
+}
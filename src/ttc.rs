@@ -1,8 +1,27 @@
+use std::hash::{Hash, Hasher};
+
 use anyhow::{Context, Result};
-use fontcull_read_fonts::{FontRef, TopLevelTable, tables::cff::Cff, types::Tag};
-use fontcull_write_fonts::tables::{glyf::Glyf, loca::Loca};
-use rustc_hash::FxHashMap;
+use fontcull_read_fonts::{FontRef, TopLevelTable, tables::head::Head};
+use rustc_hash::{FxHashMap, FxHasher};
+
+use crate::checksum;
+
+/// Append a table to the data block on a 4-byte boundary, returning its offset.
+fn store(table_data_block: &mut Vec<u8>, table_data: &[u8]) -> usize {
+    while table_data_block.len() % 4 != 0 {
+        table_data_block.push(0);
+    }
+
+    let off = table_data_block.len();
+    table_data_block.extend_from_slice(table_data);
+
+    off
+}
 
+/// Build a font collection from `fonts`.
+///
+/// Byte-identical tables are stored once and shared between faces, whatever their tag,
+/// except `head`: every face gets its own so its `checksumAdjustment` can be set.
 pub fn build_collection(fonts: &[FontRef]) -> Result<Vec<u8>> {
     let mut out = Vec::new();
 
@@ -12,24 +31,29 @@ pub fn build_collection(fonts: &[FontRef]) -> Result<Vec<u8>> {
     out.extend_from_slice(&0u16.to_be_bytes()); // Minor
     out.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
 
-    let offset_table_start = out.len();
+    // Table data follows the TTC header and every table directory
+    let mut directory_offset = out.len() + fonts.len() * 4;
+    let data_block_start = fonts.iter().fold(directory_offset, |end, font| {
+        end + 12 + font.table_directory().table_records().len() * 16
+    });
 
-    for _ in 0..fonts.len() {
-        out.extend_from_slice(&0u32.to_be_bytes());
+    for font in fonts {
+        out.extend_from_slice(&(directory_offset as u32).to_be_bytes());
+        directory_offset += 12 + font.table_directory().table_records().len() * 16;
     }
 
-    let mut font_offsets = Vec::new();
-    let mut table_cache: FxHashMap<(Tag, Vec<u8>), u32> = FxHashMap::default();
+    // Offsets of stored tables by content hash. Hashes may collide, so the bytes are
+    // compared before sharing.
+    let mut table_cache: FxHashMap<u64, Vec<usize>> = FxHashMap::default();
     let mut table_data_block = Vec::new();
 
     // Process and rewrite each font
     for font in fonts {
-        font_offsets.push(out.len() as u32);
         let records = font.table_directory().table_records();
         let num_tables = records.len() as u16;
 
         // Write OffsetTable header
-        out.extend_from_slice(&0x00010000u32.to_be_bytes()); // sfntVersion
+        out.extend_from_slice(&font.table_directory().sfnt_version().to_be_bytes());
         out.extend_from_slice(&num_tables.to_be_bytes());
         let entry_selector = (num_tables as f32).log2().floor() as u16;
         let search_range = (2u16.pow(entry_selector as u32)) * 16;
@@ -39,68 +63,119 @@ pub fn build_collection(fonts: &[FontRef]) -> Result<Vec<u8>> {
 
         for record in records {
             let tag = record.tag();
-            let table_data = font
-                .table_data(tag)
-                .context("Table missing")?
-                .as_ref()
-                .to_vec();
-
-            // Only share tables that are usually safe and heavy
-            let can_share = matches!(tag, Glyf::TAG | Cff::TAG | Loca::TAG);
-
-            let rel_offset = if can_share {
-                if let Some(&off) = table_cache.get(&(tag, table_data.clone())) {
-                    off
-                } else {
-                    while table_data_block.len() % 4 != 0 {
-                        table_data_block.push(0);
-                    }
+            let table_data = font.table_data(tag).context("Table missing")?.as_bytes();
 
-                    let off = table_data_block.len() as u32;
-                    table_cache.insert((tag, table_data.clone()), off);
-                    table_data_block.extend(&table_data);
-
-                    off
-                }
+            let rel_offset = if tag == Head::TAG {
+                store(&mut table_data_block, table_data)
             } else {
-                while table_data_block.len() % 4 != 0 {
-                    table_data_block.push(0);
-                }
+                let mut hasher = FxHasher::default();
+                table_data.hash(&mut hasher);
+
+                let candidates = table_cache.entry(hasher.finish()).or_default();
+
+                let shared = candidates.iter().copied().find(|&off| {
+                    table_data_block.get(off..off + table_data.len()) == Some(table_data)
+                });
 
-                let off = table_data_block.len() as u32;
-                table_data_block.extend(&table_data);
+                match shared {
+                    Some(off) => off,
+                    None => {
+                        let off = store(&mut table_data_block, table_data);
+                        candidates.push(off);
 
-                off
+                        off
+                    }
+                }
             };
 
+            // Checksums are verified once the collection is laid out
             out.extend_from_slice(&tag.to_be_bytes());
             out.extend_from_slice(&record.checksum().to_be_bytes());
-            out.extend_from_slice(&rel_offset.to_be_bytes());
+            out.extend_from_slice(&((data_block_start + rel_offset) as u32).to_be_bytes());
             out.extend_from_slice(&(table_data.len() as u32).to_be_bytes());
         }
     }
 
-    // Fix up absolute offsets
+    out.extend(table_data_block);
 
-    let data_block_start = out.len() as u32;
+    checksum::update_checksums(&mut out)?;
 
-    for (i, &off) in font_offsets.iter().enumerate() {
-        let pos = offset_table_start + (i * 4);
-        out[pos..pos + 4].copy_from_slice(&off.to_be_bytes());
-    }
+    Ok(out)
+}
 
-    for &f_off in &font_offsets {
-        let num_tables =
-            u16::from_be_bytes(out[f_off as usize + 4..f_off as usize + 6].try_into()?);
+#[cfg(test)]
+mod tests {
+    use fontcull_read_fonts::{
+        FileRef,
+        types::{CFF_SFNT_VERSION, TT_SFNT_VERSION, Tag},
+    };
+
+    use super::*;
+    use crate::test_fonts::{font, head};
+
+    #[test]
+    fn identical_tables_are_shared_except_head() {
+        let head = head(0x0001_0000, 0);
+
+        let first = font(&[(b"head", &head), (b"aaaa", &[1; 8]), (b"bbbb", &[2; 6])]);
+        let mut second = font(&[(b"head", &head), (b"cccc", &[1; 8]), (b"dddd", &[3; 4])]);
+        second[..4].copy_from_slice(&CFF_SFNT_VERSION.to_be_bytes());
+
+        let fonts = [
+            FontRef::new(&first).unwrap(),
+            FontRef::new(&second).unwrap(),
+        ];
+        let data = build_collection(&fonts).unwrap();
+
+        let FileRef::Collection(collection) = FileRef::new(&data).unwrap() else {
+            panic!("Expected a collection");
+        };
+        let faces = collection.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        let offset = |face: usize, tag: &[u8; 4]| {
+            faces[face]
+                .table_directory()
+                .table_records()
+                .iter()
+                .find(|record| record.tag() == Tag::new(tag))
+                .map(|record| record.offset())
+                .unwrap()
+        };
+
+        assert_eq!(
+            faces
+                .iter()
+                .map(|face| face.table_directory().sfnt_version())
+                .collect::<Vec<u32>>(),
+            [TT_SFNT_VERSION, CFF_SFNT_VERSION]
+        );
+        assert_eq!(offset(0, b"aaaa"), offset(1, b"cccc"));
+        assert_ne!(offset(0, b"head"), offset(1, b"head"));
+
+        for (face, source) in faces.iter().zip(&fonts) {
+            for record in face.table_directory().table_records() {
+                let tag = record.tag();
+                let mut table = face.table_data(tag).unwrap().as_bytes().to_vec();
+
+                assert_eq!(
+                    record.checksum(),
+                    checksum::checksum_for(tag, &table),
+                    "{tag}"
+                );
+
+                // Only checksumAdjustment differs, since it is set per face
+                if tag == Head::TAG {
+                    table[8..12].fill(0);
+                }
 
-        for i in 0..num_tables {
-            let off_pos = (f_off as usize + 12) + (i as usize * 16) + 8;
-            let rel = u32::from_be_bytes(out[off_pos..off_pos + 4].try_into()?);
-            out[off_pos..off_pos + 4].copy_from_slice(&(data_block_start + rel).to_be_bytes());
+                assert_eq!(table, source.table_data(tag).unwrap().as_bytes(), "{tag}");
+            }
         }
-    }
-
-    out.extend(table_data_block);
 
-    Ok(out)
+        for offset in checksum::directory_offsets(&data).unwrap() {
+            assert_eq!(
+                checksum::font_checksum(&data, offset).unwrap(),
+                checksum::CHECKSUM_MAGIC
+            );
+        }
+    }
 }
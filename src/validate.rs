@@ -0,0 +1,620 @@
+use std::{fmt, ops::RangeInclusive};
+
+use fontcull_read_fonts::{FileRef, FontRef, TableProvider, tables::glyf::Glyph, types::GlyphId};
+use fontcull_skrifa::{
+    MetadataProvider,
+    instance::{LocationRef, Size},
+    outline::OutlineGlyphCollection,
+};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    checksum::{self, CHECKSUM_MAGIC},
+    pen::PathPen,
+};
+
+/// Issues listed per kind before the rest are summarized.
+const MAX_LISTED: usize = 20;
+
+/// What a validation issue concerns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IssueKind {
+    /// The font or one of its tables could not be read
+    Parse,
+    /// A table record's checksum does not match its table
+    TableChecksum,
+    /// `head.checksumAdjustment` does not balance the font checksum
+    ChecksumAdjustment,
+    /// The output has a different number of glyphs than the input
+    GlyphCount,
+    /// Characters mapped in the input are no longer mapped
+    Coverage,
+    /// An annotated glyph lost its outline
+    EmptyGlyph,
+    /// `loca` offsets do not fit `maxp` or `glyf`
+    Loca,
+    /// `maxp` (or `hhea`) counts do not match the glyphs
+    Maxp,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IssueKind::Parse => "parse",
+            IssueKind::TableChecksum => "table-checksum",
+            IssueKind::ChecksumAdjustment => "checksum-adjustment",
+            IssueKind::GlyphCount => "glyph-count",
+            IssueKind::Coverage => "coverage",
+            IssueKind::EmptyGlyph => "empty-glyph",
+            IssueKind::Loca => "loca",
+            IssueKind::Maxp => "maxp",
+        })
+    }
+}
+
+/// A problem found in an output font.
+#[derive(Clone, Debug)]
+pub struct Issue {
+    /// Index of the face within the output file
+    pub face: usize,
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+/// Every issue found in one output file.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn extend(&mut self, other: ValidationReport) {
+        self.issues.extend(other.issues);
+    }
+
+    pub fn push(&mut self, face: usize, kind: IssueKind, message: String) {
+        self.issues.push(Issue {
+            face,
+            kind,
+            message,
+        });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Kinds in order of their first issue
+        let mut kinds = Vec::<IssueKind>::new();
+
+        for issue in &self.issues {
+            if !kinds.contains(&issue.kind) {
+                kinds.push(issue.kind);
+            }
+        }
+
+        for kind in kinds {
+            let issues = self
+                .issues
+                .iter()
+                .filter(|issue| issue.kind == kind)
+                .collect::<Vec<&Issue>>();
+
+            writeln!(f, "[{kind}] {} issues", issues.len())?;
+
+            for issue in issues.iter().take(MAX_LISTED) {
+                writeln!(f, "  face {}: {}", issue.face, issue.message)?;
+            }
+
+            if issues.len() > MAX_LISTED {
+                writeln!(f, "  ... and {} more", issues.len() - MAX_LISTED)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The input an output font is compared against.
+pub struct Reference<'a> {
+    /// Input faces, in the same order as the output faces
+    pub faces: Vec<FontRef<'a>>,
+    /// Character ranges the renderer annotates
+    pub ranges: &'a [RangeInclusive<u32>],
+    /// Whether the output was subset to the annotated characters
+    pub subset: bool,
+}
+
+/// Re-parse a built font or collection and check its structure. With a `reference`, the
+/// glyph counts, character coverage and annotated outlines are also compared against
+/// the input.
+pub fn validate(data: &[u8], reference: Option<&Reference>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let file = match FileRef::new(data) {
+        Ok(file) => file,
+        Err(err) => {
+            report.push(0, IssueKind::Parse, format!("Failed to parse font: {err}"));
+            return report;
+        }
+    };
+
+    let directory_offsets = checksum::directory_offsets(data).unwrap_or_default();
+
+    if let Some(reference) = reference
+        && file.fonts().count() != reference.faces.len()
+    {
+        report.push(
+            0,
+            IssueKind::GlyphCount,
+            format!(
+                "Output has {} faces, but the input has {}",
+                file.fonts().count(),
+                reference.faces.len()
+            ),
+        );
+    }
+
+    for (face, font) in file.fonts().enumerate() {
+        let font = match font {
+            Ok(font) => font,
+            Err(err) => {
+                report.push(
+                    face,
+                    IssueKind::Parse,
+                    format!("Failed to parse face: {err}"),
+                );
+                continue;
+            }
+        };
+
+        check_checksums(&font, face, data, directory_offsets.get(face), &mut report);
+        check_glyphs(&font, face, &mut report);
+
+        if let Some(reference) = reference
+            && let Some(input) = reference.faces.get(face)
+        {
+            compare(input, &font, face, reference, &mut report);
+        }
+    }
+
+    report
+}
+
+fn check_checksums(
+    font: &FontRef,
+    face: usize,
+    data: &[u8],
+    directory_offset: Option<&usize>,
+    report: &mut ValidationReport,
+) {
+    for record in font.table_directory.table_records() {
+        let tag = record.tag();
+
+        let Some(table) = font.table_data(tag) else {
+            report.push(
+                face,
+                IssueKind::Parse,
+                format!("'{tag}' table is out of bounds"),
+            );
+            continue;
+        };
+
+        let checksum = checksum::checksum_for(tag, table.as_bytes());
+
+        if checksum != record.checksum() {
+            report.push(
+                face,
+                IssueKind::TableChecksum,
+                format!(
+                    "'{tag}' checksum is {:#010x}, but the table sums to {checksum:#010x}",
+                    record.checksum()
+                ),
+            );
+        }
+    }
+
+    let Some(font_checksum) =
+        directory_offset.and_then(|&offset| checksum::font_checksum(data, offset).ok())
+    else {
+        return;
+    };
+
+    if font_checksum != CHECKSUM_MAGIC {
+        report.push(
+            face,
+            IssueKind::ChecksumAdjustment,
+            format!("Font checksum is {font_checksum:#010x} instead of {CHECKSUM_MAGIC:#010x}"),
+        );
+    }
+}
+
+fn check_glyphs(font: &FontRef, face: usize, report: &mut ValidationReport) {
+    let Ok(maxp) = font.maxp() else {
+        report.push(face, IssueKind::Parse, "Missing maxp table".to_string());
+        return;
+    };
+
+    let num_glyphs = maxp.num_glyphs() as usize;
+
+    if let Ok(hhea) = font.hhea()
+        && hhea.number_of_h_metrics() as usize > num_glyphs
+    {
+        report.push(
+            face,
+            IssueKind::Maxp,
+            format!(
+                "hhea.numberOfHMetrics ({}) exceeds maxp.numGlyphs ({num_glyphs})",
+                hhea.number_of_h_metrics()
+            ),
+        );
+    }
+
+    let Ok(glyf) = font.glyf() else {
+        return;
+    };
+
+    let loca = match font.loca(None) {
+        Ok(loca) => loca,
+        Err(err) => {
+            report.push(face, IssueKind::Loca, format!("Failed to read loca: {err}"));
+            return;
+        }
+    };
+
+    if loca.len() != num_glyphs {
+        report.push(
+            face,
+            IssueKind::Loca,
+            format!(
+                "loca has {} entries, but maxp.numGlyphs is {num_glyphs}",
+                loca.len()
+            ),
+        );
+    }
+
+    if !loca.all_offsets_are_ascending() {
+        report.push(
+            face,
+            IssueKind::Loca,
+            "loca offsets are not ascending".to_string(),
+        );
+    }
+
+    let glyf_len = glyf.offset_data().len() as u32;
+
+    if let Some(end) = loca.get_raw(loca.len())
+        && end > glyf_len
+    {
+        report.push(
+            face,
+            IssueKind::Loca,
+            format!("loca ends at {end}, past the end of glyf ({glyf_len})"),
+        );
+    }
+
+    let mut max_points = 0;
+    let mut max_contours = 0;
+
+    for gid in 0..loca.len().min(num_glyphs) as u32 {
+        match loca.get_glyf(GlyphId::new(gid), &glyf) {
+            Ok(Some(Glyph::Simple(glyph))) => {
+                max_points = max_points.max(glyph.num_points() as u16);
+                max_contours = max_contours.max(glyph.number_of_contours() as u16);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                report.push(
+                    face,
+                    IssueKind::Loca,
+                    format!("Glyph {gid} cannot be read: {err}"),
+                );
+            }
+        }
+    }
+
+    let declared = maxp.max_points().zip(maxp.max_contours());
+
+    if let Some((declared_points, declared_contours)) = declared
+        && (max_points > declared_points || max_contours > declared_contours)
+    {
+        report.push(
+            face,
+            IssueKind::Maxp,
+            format!(
+                "Glyphs have up to {max_points} points and {max_contours} contours, but maxp declares {declared_points} and {declared_contours}"
+            ),
+        );
+    }
+}
+
+/// Returns whether the glyph draws anything, or `None` if it fails to draw.
+fn has_outline(outlines: &OutlineGlyphCollection, gid: GlyphId) -> Option<bool> {
+    let glyph = outlines.get(gid)?;
+    let mut pen = PathPen::new();
+
+    glyph
+        .draw((Size::unscaled(), LocationRef::default()), &mut pen)
+        .ok()?;
+
+    Some(!pen.path.elements().is_empty())
+}
+
+fn compare(
+    input: &FontRef,
+    output: &FontRef,
+    face: usize,
+    reference: &Reference,
+    report: &mut ValidationReport,
+) {
+    let in_glyphs = input
+        .maxp()
+        .map(|maxp| maxp.num_glyphs())
+        .unwrap_or_default();
+    let out_glyphs = output
+        .maxp()
+        .map(|maxp| maxp.num_glyphs())
+        .unwrap_or_default();
+
+    if !reference.subset && in_glyphs != out_glyphs {
+        report.push(
+            face,
+            IssueKind::GlyphCount,
+            format!("Output has {out_glyphs} glyphs, but the input has {in_glyphs}"),
+        );
+    }
+
+    let annotated = |codepoint: u32| {
+        reference
+            .ranges
+            .iter()
+            .any(|range| range.contains(&codepoint))
+    };
+
+    let out_charmap = output.charmap();
+    let mut missing = Vec::new();
+    let mut pairs = Vec::new();
+
+    for (codepoint, in_gid) in input.charmap().mappings() {
+        // Subsetting only keeps annotated characters
+        if reference.subset && !annotated(codepoint) {
+            continue;
+        }
+
+        match out_charmap
+            .map(codepoint)
+            .filter(|gid| *gid != GlyphId::NOTDEF)
+        {
+            Some(out_gid) if annotated(codepoint) => pairs.push((codepoint, in_gid, out_gid)),
+            Some(_) => {}
+            None => missing.push(codepoint),
+        }
+    }
+
+    if !missing.is_empty() {
+        let examples = missing
+            .iter()
+            .take(8)
+            .map(|codepoint| format!("U+{codepoint:04X}"))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        report.push(
+            face,
+            IssueKind::Coverage,
+            format!(
+                "{} characters are no longer mapped ({examples})",
+                missing.len()
+            ),
+        );
+    }
+
+    let in_outlines = input.outline_glyphs();
+    let out_outlines = output.outline_glyphs();
+
+    // An annotated glyph must keep an outline if its input glyph had one
+    let mut empty = pairs
+        .into_par_iter()
+        .filter_map(|(codepoint, in_gid, out_gid)| {
+            let message = match has_outline(&out_outlines, out_gid) {
+                Some(true) => None,
+                Some(false) => (has_outline(&in_outlines, in_gid) == Some(true))
+                    .then(|| format!("U+{codepoint:04X} (glyph {out_gid}) has an empty outline")),
+                None => Some(format!("U+{codepoint:04X} (glyph {out_gid}) fails to draw")),
+            };
+
+            message.map(|message| (codepoint, message))
+        })
+        .collect::<Vec<(u32, String)>>();
+
+    empty.sort();
+
+    for (_, message) in empty {
+        report.push(face, IssueKind::EmptyGlyph, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_read_fonts::{TopLevelTable, types::Tag};
+    use fontcull_write_fonts::{
+        FontBuilder,
+        tables::{
+            cmap::Cmap,
+            glyf::{self, SimpleGlyph},
+            head::Head,
+            hhea::Hhea,
+            hmtx::{Hmtx, LongMetric},
+            maxp::Maxp,
+        },
+    };
+    use kurbo::BezPath;
+
+    use super::*;
+    use crate::glyf::GlyfBuilder;
+
+    /// A font mapping 'a' to a triangle, or to an empty glyph. `adjust` can break the
+    /// `maxp` and `hhea` counts.
+    fn test_font(empty: bool, adjust: impl FnOnce(&mut Maxp, &mut Hhea)) -> Vec<u8> {
+        let mut triangle = BezPath::new();
+        triangle.move_to((0.0, 0.0));
+        triangle.line_to((500.0, 0.0));
+        triangle.line_to((250.0, 500.0));
+        triangle.close_path();
+
+        let glyph = if empty {
+            glyf::Glyph::Empty
+        } else {
+            glyf::Glyph::Simple(SimpleGlyph::from_bezpath(&triangle).unwrap())
+        };
+
+        let mut glyf_builder = GlyfBuilder::new();
+        glyf_builder.add_glyph(&glyf::Glyph::Empty).unwrap();
+        glyf_builder.add_glyph(&glyph).unwrap();
+        let built = glyf_builder.build();
+
+        let head = Head {
+            units_per_em: 1000,
+            index_to_loc_format: built.loca_format as i16,
+            ..Default::default()
+        };
+        let mut maxp = Maxp {
+            max_points: Some(3),
+            max_contours: Some(1),
+            max_composite_points: Some(0),
+            max_composite_contours: Some(0),
+            max_zones: Some(2),
+            max_twilight_points: Some(0),
+            max_storage: Some(0),
+            max_function_defs: Some(0),
+            max_instruction_defs: Some(0),
+            max_stack_elements: Some(0),
+            max_size_of_instructions: Some(0),
+            max_component_elements: Some(0),
+            max_component_depth: Some(0),
+            ..Maxp::new(2)
+        };
+        let mut hhea = Hhea {
+            number_of_h_metrics: 2,
+            ..Default::default()
+        };
+
+        adjust(&mut maxp, &mut hhea);
+
+        let mut builder = FontBuilder::new();
+        builder.add_raw(glyf::Glyf::TAG, built.glyf);
+        builder.add_table(&built.loca).unwrap();
+        builder.add_table(&head).unwrap();
+        builder.add_table(&maxp).unwrap();
+        builder.add_table(&hhea).unwrap();
+        builder
+            .add_table(&Hmtx::new(vec![LongMetric::new(500, 0); 2], Vec::new()))
+            .unwrap();
+        builder
+            .add_table(&Cmap::from_mappings([('a', GlyphId::new(1))]).unwrap())
+            .unwrap();
+
+        let mut data = builder.build();
+        checksum::update_checksums(&mut data).unwrap();
+        data
+    }
+
+    fn kinds(report: &ValidationReport) -> Vec<IssueKind> {
+        report.issues.iter().map(|issue| issue.kind).collect()
+    }
+
+    /// Offset of the table `tag` in `data`.
+    fn table_offset(data: &[u8], tag: Tag) -> usize {
+        FontRef::new(data)
+            .unwrap()
+            .table_directory
+            .table_records()
+            .iter()
+            .find(|record| record.tag() == tag)
+            .unwrap()
+            .offset() as usize
+    }
+
+    #[test]
+    fn valid_font_passes() {
+        let data = test_font(false, |_, _| {});
+        let input = test_font(false, |_, _| {});
+        let reference = Reference {
+            faces: vec![FontRef::new(&input).unwrap()],
+            ranges: &[0x61..=0x61],
+            subset: false,
+        };
+
+        let report = validate(&data, Some(&reference));
+
+        assert!(report.is_ok(), "{report}");
+    }
+
+    #[test]
+    fn checksum_adjustment_is_checked() {
+        let mut data = test_font(false, |_, _| {});
+        let adjustment = table_offset(&data, Head::TAG) + 8;
+        data[adjustment] ^= 0xff;
+
+        assert_eq!(
+            kinds(&validate(&data, None)),
+            [IssueKind::ChecksumAdjustment]
+        );
+    }
+
+    #[test]
+    fn table_checksums_are_checked() {
+        let mut data = test_font(false, |_, _| {});
+        let cmap = table_offset(&data, Cmap::TAG);
+        data[cmap + 1] ^= 0xff;
+
+        let kinds = kinds(&validate(&data, None));
+
+        assert!(kinds.contains(&IssueKind::TableChecksum), "{kinds:?}");
+    }
+
+    #[test]
+    fn loca_must_match_maxp() {
+        let data = test_font(false, |maxp, _| maxp.num_glyphs = 3);
+
+        assert_eq!(kinds(&validate(&data, None)), [IssueKind::Loca]);
+    }
+
+    #[test]
+    fn maxp_maxima_must_cover_glyphs() {
+        let data = test_font(false, |maxp, _| maxp.max_points = Some(2));
+
+        assert_eq!(kinds(&validate(&data, None)), [IssueKind::Maxp]);
+
+        let data = test_font(false, |_, hhea| hhea.number_of_h_metrics = 3);
+
+        assert_eq!(kinds(&validate(&data, None)), [IssueKind::Maxp]);
+    }
+
+    #[test]
+    fn emptied_annotated_glyphs_are_reported() {
+        let input = test_font(false, |_, _| {});
+        let output = test_font(true, |_, _| {});
+        let reference = Reference {
+            faces: vec![FontRef::new(&input).unwrap()],
+            ranges: &[0x61..=0x61],
+            subset: false,
+        };
+
+        assert_eq!(
+            kinds(&validate(&output, Some(&reference))),
+            [IssueKind::EmptyGlyph]
+        );
+
+        // Glyphs that were already empty may stay empty
+        let reference = Reference {
+            faces: vec![FontRef::new(&output).unwrap()],
+            ..reference
+        };
+
+        assert!(validate(&output, Some(&reference)).is_ok());
+    }
+}
@@ -0,0 +1,359 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result, bail, ensure};
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+use fontcull_read_fonts::{FileRef, TableProvider};
+
+use crate::checksum;
+
+const WOFF_SIGNATURE: u32 = 0x774F_4646; // 'wOFF'
+const HEADER_LEN: usize = 44;
+const TABLE_ENTRY_LEN: usize = 20;
+
+/// Optional extended metadata and private data blocks of a WOFF file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WoffExtras<'a> {
+    /// Extended metadata XML, stored compressed
+    pub metadata: &'a [u8],
+    /// Private data, stored as-is
+    pub private: &'a [u8],
+}
+
+fn zlib(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+
+    Ok(encoder.finish()?)
+}
+
+fn pad4(data: &mut Vec<u8>) {
+    while data.len() % 4 != 0 {
+        data.push(0);
+    }
+}
+
+/// Wrap an sfnt font in a WOFF 1.0 container, zlib-compressing each table.
+///
+/// WOFF 1.0 has no collection format, so collections are rejected.
+pub fn compress(font_data: &[u8], extras: WoffExtras) -> Result<Vec<u8>> {
+    let font = match FileRef::new(font_data).context("Failed to parse font for WOFF compression")? {
+        FileRef::Font(font) => font,
+        FileRef::Collection(_) => bail!("WOFF 1.0 cannot hold font collections, use --split"),
+    };
+
+    // Table directory entries must be sorted by tag
+    let mut records = font.table_directory.table_records().to_vec();
+    records.sort_by_key(|record| record.tag());
+
+    let num_tables = records.len();
+    let tables_start = HEADER_LEN + num_tables * TABLE_ENTRY_LEN;
+    let mut total_sfnt_size = 12 + 16 * num_tables;
+
+    let mut directory = Vec::with_capacity(num_tables * TABLE_ENTRY_LEN);
+    let mut tables = Vec::new();
+
+    for record in &records {
+        let tag = record.tag();
+        let data = font.table_data(tag).context("Table missing")?.as_bytes();
+
+        // Tables that do not shrink are stored uncompressed
+        let compressed = zlib(data)?;
+        let stored = if compressed.len() < data.len() {
+            compressed.as_slice()
+        } else {
+            data
+        };
+
+        // Every table starts on a 4-byte boundary
+        pad4(&mut tables);
+
+        directory.extend_from_slice(&tag.to_be_bytes());
+        directory.extend_from_slice(&((tables_start + tables.len()) as u32).to_be_bytes());
+        directory.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+        directory.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        directory.extend_from_slice(&checksum::checksum_for(tag, data).to_be_bytes());
+
+        tables.extend_from_slice(stored);
+        total_sfnt_size += data.len().next_multiple_of(4);
+    }
+
+    // Metadata and private data also start on 4-byte boundaries, after the tables
+    let blocks_start = tables_start + tables.len();
+    let mut blocks = Vec::new();
+    let mut add_block = |data: &[u8]| {
+        let start = (blocks_start + blocks.len()).next_multiple_of(4);
+
+        blocks.resize(start - blocks_start, 0);
+        blocks.extend_from_slice(data);
+
+        (start as u32, data.len() as u32)
+    };
+
+    let (meta_offset, meta_length) = if extras.metadata.is_empty() {
+        (0, 0)
+    } else {
+        add_block(&zlib(extras.metadata)?)
+    };
+
+    let (priv_offset, priv_length) = if extras.private.is_empty() {
+        (0, 0)
+    } else {
+        add_block(extras.private)
+    };
+
+    // The font revision doubles as the WOFF version
+    let revision = font.head()?.font_revision().to_f64();
+    let major_version = revision.trunc() as u16;
+    let minor_version = (revision.fract() * 1000.0).round() as u16;
+
+    let length = blocks_start + blocks.len();
+
+    let mut out = Vec::with_capacity(length);
+    out.extend_from_slice(&WOFF_SIGNATURE.to_be_bytes());
+    out.extend_from_slice(&font.table_directory.sfnt_version().to_be_bytes());
+    out.extend_from_slice(&(length as u32).to_be_bytes());
+    out.extend_from_slice(&(num_tables as u16).to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes()); // reserved
+    out.extend_from_slice(&(total_sfnt_size as u32).to_be_bytes());
+    out.extend_from_slice(&major_version.to_be_bytes());
+    out.extend_from_slice(&minor_version.to_be_bytes());
+    out.extend_from_slice(&meta_offset.to_be_bytes());
+    out.extend_from_slice(&meta_length.to_be_bytes());
+    out.extend_from_slice(&(extras.metadata.len() as u32).to_be_bytes());
+    out.extend_from_slice(&priv_offset.to_be_bytes());
+    out.extend_from_slice(&priv_length.to_be_bytes());
+
+    out.extend(directory);
+    out.extend(tables);
+    out.extend(blocks);
+
+    Ok(out)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Truncated WOFF file")?;
+
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+/// Unwrap a WOFF 1.0 file into the sfnt font it contains.
+pub fn decompress(woff_data: &[u8]) -> Result<Vec<u8>> {
+    ensure!(read_u32(woff_data, 0)? == WOFF_SIGNATURE, "Not a WOFF file");
+
+    let flavor = read_u32(woff_data, 4)?;
+    let num_tables = (read_u32(woff_data, 12)? >> 16) as usize;
+
+    ensure!(num_tables > 0, "WOFF file has no tables");
+    // The sfnt searchRange only fits in 16 bits for fewer than 4096 tables
+    ensure!(
+        num_tables < 4096,
+        "WOFF file has too many tables: {num_tables}"
+    );
+
+    let mut entries = Vec::with_capacity(num_tables);
+
+    for idx in 0..num_tables {
+        let entry = HEADER_LEN + idx * TABLE_ENTRY_LEN;
+
+        let tag = read_u32(woff_data, entry)?;
+        let offset = read_u32(woff_data, entry + 4)? as usize;
+        let comp_length = read_u32(woff_data, entry + 8)? as usize;
+        let orig_length = read_u32(woff_data, entry + 12)? as usize;
+        let checksum = read_u32(woff_data, entry + 16)?;
+
+        ensure!(
+            comp_length <= orig_length,
+            "WOFF table is stored larger than its original length"
+        );
+
+        let stored = woff_data
+            .get(offset..offset + comp_length)
+            .context("WOFF table data out of bounds")?;
+
+        let data = if comp_length < orig_length {
+            let mut data = Vec::with_capacity(orig_length);
+            // Stop reading past the expected length, so the check below catches it
+            ZlibDecoder::new(stored)
+                .take(orig_length as u64 + 1)
+                .read_to_end(&mut data)
+                .context("Failed to decompress WOFF table")?;
+            data
+        } else {
+            stored.to_vec()
+        };
+
+        ensure!(
+            data.len() == orig_length,
+            "WOFF table has the wrong decompressed length"
+        );
+
+        entries.push((tag, checksum, data));
+    }
+
+    // Table records must be sorted by tag
+    entries.sort_by_key(|(tag, _, _)| *tag);
+
+    let entry_selector = num_tables.ilog2() as u16;
+    let search_range = (1u16 << entry_selector) * 16;
+    let range_shift = num_tables as u16 * 16 - search_range;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&flavor.to_be_bytes());
+    out.extend_from_slice(&(num_tables as u16).to_be_bytes());
+    out.extend_from_slice(&search_range.to_be_bytes());
+    out.extend_from_slice(&entry_selector.to_be_bytes());
+    out.extend_from_slice(&range_shift.to_be_bytes());
+
+    let mut offset = 12 + 16 * num_tables;
+
+    for (tag, checksum, data) in &entries {
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&checksum.to_be_bytes());
+        out.extend_from_slice(&(offset as u32).to_be_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+        offset += data.len().next_multiple_of(4);
+    }
+
+    for (_, _, data) in entries {
+        out.extend(data);
+        pad4(&mut out);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use fontcull_read_fonts::{FontRef, types::Tag};
+
+    use super::*;
+    use crate::test_fonts::{font, head};
+
+    const TAGS: [Tag; 3] = [Tag::new(b"head"), Tag::new(b"odd "), Tag::new(b"zero")];
+
+    /// A font with revision 1.5, a table too short to compress and one that compresses well.
+    fn test_font() -> Vec<u8> {
+        font(&[
+            (b"head", &head(0x0001_8000, 0)),
+            (b"odd ", &[1, 2, 3]),
+            (b"zero", &[0; 1000]),
+        ])
+    }
+
+    /// The stored and original lengths of the `idx`th directory entry.
+    fn entry_lengths(woff: &[u8], idx: usize) -> (u32, u32) {
+        let entry = HEADER_LEN + idx * TABLE_ENTRY_LEN;
+
+        (
+            read_u32(woff, entry + 8).unwrap(),
+            read_u32(woff, entry + 12).unwrap(),
+        )
+    }
+
+    #[test]
+    fn round_trip_keeps_tables() {
+        let sfnt = test_font();
+        let woff = compress(&sfnt, WoffExtras::default()).unwrap();
+
+        assert_eq!(read_u32(&woff, 8).unwrap() as usize, woff.len());
+        // Version 1.5
+        assert_eq!(read_u32(&woff, 20).unwrap(), 0x0001_01F4);
+        assert_eq!(entry_lengths(&woff, 1), (3, 3));
+        assert!(entry_lengths(&woff, 2).0 < 1000);
+
+        let decompressed = decompress(&woff).unwrap();
+        let original = FontRef::new(&sfnt).unwrap();
+        let round_trip = FontRef::new(&decompressed).unwrap();
+
+        for tag in TAGS {
+            assert_eq!(
+                original.table_data(tag).unwrap().as_bytes(),
+                round_trip.table_data(tag).unwrap().as_bytes(),
+                "{tag}"
+            );
+        }
+    }
+
+    #[test]
+    fn extras_are_stored_aligned() {
+        let extras = WoffExtras {
+            metadata: b"<metadata version=\"1.0\"/>",
+            private: b"abc",
+        };
+        let woff = compress(&test_font(), extras).unwrap();
+
+        let meta_offset = read_u32(&woff, 24).unwrap() as usize;
+        let meta_length = read_u32(&woff, 28).unwrap() as usize;
+        let priv_offset = read_u32(&woff, 36).unwrap() as usize;
+        let priv_length = read_u32(&woff, 40).unwrap() as usize;
+
+        assert_eq!(meta_offset % 4, 0);
+        assert_eq!(priv_offset % 4, 0);
+        assert_eq!(read_u32(&woff, 32).unwrap() as usize, extras.metadata.len());
+
+        let mut metadata = Vec::new();
+        ZlibDecoder::new(&woff[meta_offset..meta_offset + meta_length])
+            .read_to_end(&mut metadata)
+            .unwrap();
+
+        assert_eq!(metadata, extras.metadata);
+        assert_eq!(
+            &woff[priv_offset..priv_offset + priv_length],
+            extras.private
+        );
+        assert_eq!(priv_offset + priv_length, woff.len());
+
+        // The extra blocks do not get in the way of the tables
+        assert!(decompress(&woff).is_ok());
+    }
+
+    #[test]
+    fn rejects_non_woff_input() {
+        assert!(decompress(&test_font()).is_err());
+        assert!(decompress(b"wOF").is_err());
+    }
+
+    #[test]
+    fn directory_header_matches_table_count() {
+        let decompressed =
+            decompress(&compress(&test_font(), WoffExtras::default()).unwrap()).unwrap();
+
+        // 3 tables: searchRange 32, entrySelector 1, rangeShift 16
+        assert_eq!(decompressed[4..12], [0, 3, 0, 32, 0, 1, 0, 16]);
+    }
+
+    #[test]
+    fn oversized_stored_tables_are_rejected() {
+        let mut woff = compress(&test_font(), WoffExtras::default()).unwrap();
+        let entry = HEADER_LEN + TABLE_ENTRY_LEN;
+
+        // The 3 byte table claims 4 stored bytes
+        woff[entry + 8..entry + 12].copy_from_slice(&4u32.to_be_bytes());
+
+        assert!(decompress(&woff).is_err());
+    }
+
+    #[test]
+    fn too_many_tables_are_rejected() {
+        let mut woff = vec![0; HEADER_LEN];
+        woff[..4].copy_from_slice(&WOFF_SIGNATURE.to_be_bytes());
+        woff[12..14].copy_from_slice(&4096u16.to_be_bytes());
+
+        let err = decompress(&woff).unwrap_err();
+
+        assert!(err.to_string().contains("too many tables"), "{err}");
+    }
+
+    #[test]
+    fn empty_table_directory_is_rejected() {
+        let mut woff = vec![0; HEADER_LEN];
+        woff[..4].copy_from_slice(&WOFF_SIGNATURE.to_be_bytes());
+
+        let err = decompress(&woff).unwrap_err();
+
+        assert!(err.to_string().contains("no tables"), "{err}");
+    }
+}